@@ -382,6 +382,23 @@ impl BspRender
 		}
 	}
 
+	// Frees the buffers built for the current map, the shaders and skybox stay for the next one
+	pub fn unload_map(&mut self)
+	{
+		unsafe
+		{
+			if let Some(data) = self.data.take() {
+				self.gl.delete_vertex_array(data.vao);
+				self.gl.delete_buffer(data.vbo);
+				self.gl.delete_buffer(data.ibo);
+			}
+
+			if let Some(lightgrid_data) = self.lightgrid_data.take() {
+				self.gl.delete_buffer(lightgrid_data.sample_buffer);
+			}
+		}
+	}
+
 	pub fn is_ready(&self) -> bool
 	{
 		return match self.data { Some(_) => true, None => false };
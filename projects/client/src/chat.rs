@@ -0,0 +1,151 @@
+use raylib::prelude::*;
+use shared::chat::{self, MAX_CHAT_LEN};
+use std::collections::VecDeque;
+
+// Lines kept around for scrolling back through
+const MAX_HISTORY: usize = 100;
+
+// Lines shown at once, and how long a line stays up while the input box is closed
+const VISIBLE_LINES: usize = 8;
+const LINE_LIFETIME: f32 = 10f32;
+const LINE_FADE: f32 = 1f32;
+
+const FONT_SIZE: i32 = 20;
+const LINE_HEIGHT: i32 = 22;
+const MARGIN: i32 = 10;
+const WIDTH: i32 = 800;
+
+struct ChatLine
+{
+	text: String,
+	color: Color,
+	age: f32,
+}
+
+// Chat feed drawn over the 3D view, plus the input box for saying something
+pub struct Chat
+{
+	lines: VecDeque<ChatLine>,
+	input: Option<String>, // Some while typing
+	scroll: usize,         // Lines scrolled back from the newest
+}
+
+impl Chat
+{
+	pub fn new() -> Chat
+	{
+		return Chat { lines: VecDeque::with_capacity(MAX_HISTORY), input: None, scroll: 0 };
+	}
+
+	// Adds a line relayed by the server. No sender means the server said it itself.
+	pub fn push(&mut self, sender: Option<&str>, text: &str)
+	{
+		let Some(text) = chat::sanitize(text) else { return; };
+		let (text, color) = match sender {
+			Some(sender) => (format!("{}: {text}", chat::sanitize_name(sender)), Color::WHITE),
+			None => (text, Color::YELLOW),
+		};
+
+		if self.lines.len() >= MAX_HISTORY {
+			self.lines.pop_front();
+		}
+
+		self.lines.push_back(ChatLine { text, color, age: 0f32 });
+		if self.scroll > 0 {
+			// Keep what's being read in place while new lines come in
+			self.scroll = (self.scroll + 1).min(self.max_scroll());
+		}
+	}
+
+	pub fn is_typing(&self) -> bool
+	{
+		return self.input.is_some();
+	}
+
+	// Handles the keyboard for the input box, returning a line to send once one is entered
+	pub fn update(&mut self, rl: &mut RaylibHandle, dt: f32) -> Option<String>
+	{
+		for line in self.lines.iter_mut() {
+			line.age += dt;
+		}
+
+		let Some(input) = &mut self.input else {
+			if rl.is_key_pressed(KeyboardKey::KEY_T) || rl.is_key_pressed(KeyboardKey::KEY_ENTER) {
+				// Throw away the key that opened the box so it doesn't end up in the message
+				while rl.get_char_pressed().is_some() {}
+				self.input = Some(String::new());
+			}
+			return None;
+		};
+
+		while let Some(c) = rl.get_char_pressed() {
+			if !c.is_control() && input.chars().count() < MAX_CHAT_LEN {
+				input.push(c);
+			}
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_BACKSPACE) {
+			input.pop();
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_PAGE_UP) {
+			self.scroll = (self.scroll + VISIBLE_LINES / 2).min(self.max_scroll());
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_PAGE_DOWN) {
+			self.scroll = self.scroll.saturating_sub(VISIBLE_LINES / 2);
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_ESCAPE) {
+			self.close();
+		} else if rl.is_key_pressed(KeyboardKey::KEY_ENTER) {
+			let text = self.input.take().unwrap_or_default();
+			self.close();
+			return chat::sanitize(&text);
+		}
+
+		return None;
+	}
+
+	pub fn draw(&self, d: &mut RaylibDrawHandle)
+	{
+		let bottom = d.get_screen_height() - MARGIN * 4;
+
+		// While typing the whole history can be scrolled through, otherwise only recent lines show and fade out
+		let visible: Vec<(&ChatLine, f32)> = if self.is_typing() {
+			self.lines.iter().rev().skip(self.scroll).take(VISIBLE_LINES).map(|line| (line, 1f32)).collect()
+		} else {
+			self.lines.iter().rev().take(VISIBLE_LINES)
+				.take_while(|line| line.age < LINE_LIFETIME)
+				.map(|line| (line, ((LINE_LIFETIME - line.age) / LINE_FADE).min(1f32)))
+				.collect()
+		};
+
+		if let Some(input) = &self.input {
+			let top = bottom - VISIBLE_LINES as i32 * LINE_HEIGHT - MARGIN;
+			d.draw_rectangle(MARGIN, top, WIDTH, bottom - top + LINE_HEIGHT + MARGIN, Color::new(0, 0, 0, 128));
+			d.draw_text(&format!("say: {input}_"), MARGIN * 2, bottom + MARGIN / 2, FONT_SIZE, Color::WHITE);
+
+			if self.scroll > 0 {
+				d.draw_text(&format!("-- {} newer --", self.scroll), MARGIN * 2, top - LINE_HEIGHT, FONT_SIZE, Color::GRAY);
+			}
+		}
+
+		for (i, (line, alpha)) in visible.iter().enumerate() {
+			let y = bottom - (i as i32 + 1) * LINE_HEIGHT;
+			let color = Color::new(line.color.r, line.color.g, line.color.b, (*alpha * 255f32) as u8);
+			d.draw_text(&line.text, MARGIN * 2, y, FONT_SIZE, color);
+		}
+	}
+
+	fn close(&mut self)
+	{
+		self.input = None;
+		self.scroll = 0;
+	}
+
+	fn max_scroll(&self) -> usize
+	{
+		return self.lines.len().saturating_sub(VISIBLE_LINES);
+	}
+}
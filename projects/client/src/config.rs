@@ -0,0 +1,68 @@
+use shared::config::{parse_value, Config};
+use std::net::{IpAddr, Ipv4Addr};
+
+// Read from here when no other config file is given
+pub const DEFAULT_CONFIG_PATH: &str = "client.cfg";
+
+const MIN_RESOLUTION: (i32, i32) = (640, 360);
+const MAX_RESOLUTION: (i32, i32) = (7680, 4320);
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig
+{
+	pub address: IpAddr,
+	pub port: u16,
+	pub width: i32,
+	pub height: i32,
+	pub listen: bool, // Run a server in this process and connect to it over a loopback instead
+}
+
+impl Default for ClientConfig
+{
+	fn default() -> ClientConfig
+	{
+		return ClientConfig {
+			address: Ipv4Addr::LOCALHOST.into(),
+			port: server::config::DEFAULT_PORT,
+			width: 1920,
+			height: 1080,
+			listen: false,
+		};
+	}
+}
+
+impl Config for ClientConfig
+{
+	fn set(&mut self, key: &str, value: &str) -> Result<(), String>
+	{
+		match key {
+			"address" => self.address = parse_value(key, value)?,
+			"port" => self.port = parse_value(key, value)?,
+			"width" => self.width = parse_value(key, value)?,
+			"height" => self.height = parse_value(key, value)?,
+			"resolution" => {
+				let (width, height) = value.split_once('x').ok_or(format!("resolution should look like 1920x1080, got `{value}`"))?;
+				self.width = parse_value(key, width)?;
+				self.height = parse_value(key, height)?;
+			}
+			"listen" => self.listen = parse_value(key, value)?,
+			_ => return Err(format!("unknown setting `{key}`")),
+		}
+
+		return Ok(());
+	}
+
+	fn validate(&self) -> Result<(), String>
+	{
+		if self.port == 0 {
+			return Err("port can't be 0".to_string());
+		}
+
+		if !(MIN_RESOLUTION.0..=MAX_RESOLUTION.0).contains(&self.width) || !(MIN_RESOLUTION.1..=MAX_RESOLUTION.1).contains(&self.height) {
+			return Err(format!("resolution must be between {}x{} and {}x{}, got {}x{}",
+				MIN_RESOLUTION.0, MIN_RESOLUTION.1, MAX_RESOLUTION.0, MAX_RESOLUTION.1, self.width, self.height));
+		}
+
+		return Ok(());
+	}
+}
@@ -0,0 +1,219 @@
+use raylib::prelude::*;
+use shared::console::{Command, Console};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// Lines kept for scrolling back through, and commands kept for recalling with the arrow keys
+const MAX_LOG: usize = 1000;
+const MAX_HISTORY: usize = 64;
+
+const FONT_SIZE: i32 = 20;
+const LINE_HEIGHT: i32 = 22;
+const MARGIN: i32 = 10;
+
+// Everything logged since the overlay last looked, from wherever in the client it was logged
+static LOG: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Prints a line and keeps it for the console overlay
+pub fn log(line: impl Into<String>)
+{
+	let line = line.into();
+	println!("{line}");
+	if let Ok(mut log) = LOG.lock() {
+		log.push(line);
+	}
+}
+
+// Drop-down console, toggled with the key under escape. Runs text through the shared console and hands
+// back any commands that need the rest of the client to carry out.
+pub struct ConsoleOverlay
+{
+	open: bool,
+	input: String,
+	log: VecDeque<String>,
+	scroll: usize, // Lines scrolled back from the newest
+	history: Vec<String>,
+	history_pos: Option<usize>, // Which history entry is being shown, None while typing something new
+}
+
+impl ConsoleOverlay
+{
+	pub fn new() -> ConsoleOverlay
+	{
+		return ConsoleOverlay { open: false, input: String::new(), log: VecDeque::with_capacity(MAX_LOG), scroll: 0, history: vec!(), history_pos: None };
+	}
+
+	pub fn is_open(&self) -> bool
+	{
+		return self.open;
+	}
+
+	pub fn update(&mut self, rl: &mut RaylibHandle, console: &mut Console) -> Vec<Command>
+	{
+		let mut commands = vec!();
+
+		if rl.is_key_pressed(KeyboardKey::KEY_GRAVE) {
+			self.open = !self.open;
+			// The tilde itself shouldn't end up in the input
+			while rl.get_char_pressed().is_some() {}
+		} else if self.open {
+			self.handle_keys(rl, console, &mut commands);
+		}
+
+		self.collect_output(console);
+		return commands;
+	}
+
+	fn handle_keys(&mut self, rl: &mut RaylibHandle, console: &mut Console, commands: &mut Vec<Command>)
+	{
+		while let Some(c) = rl.get_char_pressed() {
+			if !c.is_control() {
+				self.input.push(c);
+				self.history_pos = None;
+			}
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_BACKSPACE) {
+			self.input.pop();
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_ESCAPE) {
+			self.open = false;
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_TAB) {
+			self.complete(console);
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_UP) {
+			self.recall(|pos, len| Some(pos.map_or(len.saturating_sub(1), |p| p.saturating_sub(1))));
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_DOWN) {
+			self.recall(|pos, len| pos.map(|p| p + 1).filter(|p| *p < len));
+		}
+
+		let page = (rl.get_screen_height() / 2 / LINE_HEIGHT) as usize / 2;
+		if rl.is_key_pressed(KeyboardKey::KEY_PAGE_UP) {
+			self.scroll = (self.scroll + page).min(self.log.len().saturating_sub(1));
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_PAGE_DOWN) {
+			self.scroll = self.scroll.saturating_sub(page);
+		}
+
+		if rl.is_key_pressed(KeyboardKey::KEY_ENTER) && !self.input.trim().is_empty() {
+			let line = std::mem::take(&mut self.input);
+			self.push_line(format!("] {line}"));
+
+			if self.history.last() != Some(&line) {
+				if self.history.len() >= MAX_HISTORY {
+					self.history.remove(0);
+				}
+				self.history.push(line.clone());
+			}
+			self.history_pos = None;
+			self.scroll = 0;
+
+			console.execute(&line, &mut |_, cmd| commands.push(cmd.clone()));
+		}
+	}
+
+	// Steps through the history, `step` picks the next position from the current one and the history length
+	fn recall(&mut self, step: impl Fn(Option<usize>, usize) -> Option<usize>)
+	{
+		if self.history.is_empty() {
+			return;
+		}
+
+		self.history_pos = step(self.history_pos, self.history.len());
+		self.input = self.history_pos.map(|pos| self.history[pos].clone()).unwrap_or_default();
+	}
+
+	// Completes the command name being typed. With several matches it goes as far as they all agree
+	// and lists them.
+	fn complete(&mut self, console: &mut Console)
+	{
+		let prefix = self.input.trim_start().to_lowercase();
+		if prefix.is_empty() || prefix.contains(char::is_whitespace) {
+			return;
+		}
+
+		let mut matches: Vec<&str> = console.names().filter(|name| name.starts_with(&prefix)).collect();
+		matches.sort();
+		matches.dedup();
+
+		match matches.as_slice() {
+			[] => {}
+			[only] => self.input = format!("{only} "),
+			[first, rest @ ..] => {
+				let common = rest.iter().fold(first.len(), |len, name| {
+					first.bytes().zip(name.bytes()).take(len).take_while(|(a, b)| a == b).count()
+				});
+				self.input = first[..common].to_string();
+
+				let lines: Vec<String> = matches.iter().map(|name| format!("  {name}")).collect();
+				for line in lines {
+					self.push_line(line);
+				}
+			}
+		}
+	}
+
+	fn collect_output(&mut self, console: &mut Console)
+	{
+		let logged = LOG.lock().map(|mut log| std::mem::take(&mut *log)).unwrap_or_default();
+		for line in logged {
+			self.push_line(line);
+		}
+
+		// Console output only goes to the overlay, `log` would print it a second time
+		for line in console.take_output() {
+			println!("{line}");
+			self.push_line(line);
+		}
+	}
+
+	fn push_line(&mut self, line: String)
+	{
+		if self.log.len() >= MAX_LOG {
+			self.log.pop_front();
+		}
+
+		self.log.push_back(line);
+		if self.scroll > 0 {
+			// Keep what's being read in place while new lines come in
+			self.scroll = (self.scroll + 1).min(self.log.len().saturating_sub(1));
+		}
+	}
+
+	pub fn draw(&self, d: &mut RaylibDrawHandle)
+	{
+		if !self.open {
+			return;
+		}
+
+		let width = d.get_screen_width();
+		let height = d.get_screen_height() / 2;
+		d.draw_rectangle(0, 0, width, height, Color::new(0, 0, 0, 200));
+		d.draw_line(0, height, width, height, Color::ORANGE);
+
+		let input_y = height - LINE_HEIGHT - MARGIN / 2;
+		d.draw_text(&format!("] {}_", self.input), MARGIN, input_y, FONT_SIZE, Color::WHITE);
+
+		let mut y = input_y - LINE_HEIGHT;
+		if self.scroll > 0 {
+			d.draw_text("^   ^   ^   ^", MARGIN, y, FONT_SIZE, Color::ORANGE);
+			y -= LINE_HEIGHT;
+		}
+
+		for line in self.log.iter().rev().skip(self.scroll) {
+			if y < 0 {
+				break;
+			}
+
+			d.draw_text(line, MARGIN, y, FONT_SIZE, Color::LIGHTGRAY);
+			y -= LINE_HEIGHT;
+		}
+	}
+}
@@ -0,0 +1,207 @@
+use raylib::prelude::*;
+use shared::message::*;
+use shared::snapshot::*;
+
+// Two server ticks at the default rate, enough to ride out a single dropped snapshot
+pub const DEFAULT_INTERP_DELAY: f32 = 0.1f32;
+
+// How long entities keep moving along their last velocity once we run out of snapshots
+const MAX_EXTRAPOLATE: f32 = 0.25f32;
+
+// If our clock drifts further than this from the server's, jump to it instead of easing towards it
+const CLOCK_SNAP: f32 = 0.5f32;
+
+// Fraction of the clock error corrected per snapshot
+const CLOCK_CORRECT_RATE: f32 = 0.1f32;
+
+// Movement between two snapshots longer than this is treated as a teleport even without the flag,
+// since the flag only lives for one tick and the snapshot carrying it may have been lost
+const TELEPORT_DISTANCE: f32 = 256f32;
+
+// An entity as it should be drawn this frame
+pub struct RenderEntity
+{
+	pub id: u16,
+	pub origin: Vector3,
+	pub yaw: f32,
+	pub pitch: f32,
+	pub model: u16,
+	pub frame: u8,
+}
+
+pub struct Interpolation
+{
+	delay: f32,
+	tick_interval: f32,
+	server_time: f32,
+	synced: bool,
+	extrapolating: bool,
+}
+
+impl Interpolation
+{
+	pub fn new(delay: f32) -> Interpolation
+	{
+		return Interpolation { delay, tick_interval: 0f32, server_time: 0f32, synced: false, extrapolating: false };
+	}
+
+	pub fn set_tick_rate(&mut self, tick_rate: u32)
+	{
+		self.tick_interval = 1f32 / tick_rate.max(1) as f32;
+	}
+
+	pub fn set_delay(&mut self, delay: f32)
+	{
+		self.delay = delay.max(0f32);
+	}
+
+	pub fn delay(&self) -> f32
+	{
+		return self.delay;
+	}
+
+	pub fn is_extrapolating(&self) -> bool
+	{
+		return self.extrapolating;
+	}
+
+	// Our estimate of the current server time, advanced every frame and nudged by each snapshot
+	pub fn server_time(&self) -> f32
+	{
+		return self.server_time;
+	}
+
+	pub fn render_time(&self) -> f32
+	{
+		return self.server_time - self.delay;
+	}
+
+	// Render time as a server tick and a fraction towards the next one, sent along with commands for lag compensation
+	pub fn view_tick(&self) -> (u32, u8)
+	{
+		if self.tick_interval <= 0f32 {
+			return (0, 0);
+		}
+
+		let ticks = (self.render_time() / self.tick_interval).max(0f32);
+		return (ticks as u32, (ticks.fract() * 255f32) as u8);
+	}
+
+	pub fn update(&mut self, dt: f32)
+	{
+		self.server_time += dt;
+	}
+
+	// Called with every snapshot that arrives, keeps the local clock in step with the server
+	pub fn on_snapshot(&mut self, tick: u32)
+	{
+		let snapshot_time = self.tick_time(tick);
+		let error = snapshot_time - self.server_time;
+
+		if !self.synced || error.abs() > CLOCK_SNAP {
+			self.server_time = snapshot_time;
+			self.synced = true;
+		} else {
+			self.server_time += error * CLOCK_CORRECT_RATE;
+		}
+	}
+
+	pub fn reset(&mut self)
+	{
+		self.synced = false;
+		self.extrapolating = false;
+	}
+
+	fn tick_time(&self, tick: u32) -> f32
+	{
+		return tick as f32 * self.tick_interval;
+	}
+
+	// Works out where every entity should be drawn at the current render time
+	pub fn sample(&mut self, snapshots: &SnapshotHistory) -> Vec<RenderEntity>
+	{
+		let render_time = self.render_time();
+		self.extrapolating = false;
+
+		// Find the pair of snapshots surrounding the render time
+		let mut from = None;
+		let mut to = None;
+		for snapshot in snapshots.iter() {
+			if self.tick_time(snapshot.tick) <= render_time {
+				from = Some(snapshot);
+			} else {
+				to = Some(snapshot);
+				break;
+			}
+		}
+
+		return match (from, to) {
+			(Some(from), Some(to)) => {
+				let from_time = self.tick_time(from.tick);
+				let frac = (render_time - from_time) / (self.tick_time(to.tick) - from_time);
+				to.entities.iter()
+					.filter_map(|entity| blend(from.get(entity.id), entity, frac))
+					.collect()
+			}
+			(Some(latest), None) => {
+				// Ran past the newest snapshot, carry on along the last known velocity for a little while
+				let previous = snapshots.iter().rev().nth(1);
+				let Some(previous) = previous else {
+					return latest.entities.iter().filter_map(|entity| blend(None, entity, 1f32)).collect();
+				};
+
+				let overshoot = (render_time - self.tick_time(latest.tick)).min(MAX_EXTRAPOLATE);
+				let frac = 1f32 + overshoot / (self.tick_time(latest.tick) - self.tick_time(previous.tick));
+				self.extrapolating = overshoot > 0f32;
+				latest.entities.iter()
+					.filter_map(|entity| blend(previous.get(entity.id), entity, frac))
+					.collect()
+			}
+			// Render time is older than anything we have, show the oldest state until we catch up
+			(None, Some(oldest)) => oldest.entities.iter().filter_map(|entity| blend(None, entity, 1f32)).collect(),
+			(None, None) => Vec::new(),
+		};
+	}
+}
+
+// Blends between two states of the same entity, with frac past 1 extrapolating beyond `to`
+fn blend(from: Option<&EntityState>, to: &EntityState, frac: f32) -> Option<RenderEntity>
+{
+	if to.effects.contains(EntityEffect::Hidden) {
+		return None;
+	}
+
+	let to_origin = dequantize_pos(to.origin);
+	let (to_yaw, to_pitch) = (dequantize_angle(to.angles[0]), dequantize_angle(to.angles[1]));
+
+	let Some(from) = from.filter(|from| {
+		!to.effects.contains(EntityEffect::Teleport) && (dequantize_pos(from.origin) - to_origin).length() < TELEPORT_DISTANCE
+	}) else {
+		return Some(RenderEntity { id: to.id, origin: to_origin, yaw: to_yaw, pitch: to_pitch, model: to.model, frame: to.frame });
+	};
+
+	let from_origin = dequantize_pos(from.origin);
+	let (from_yaw, from_pitch) = (dequantize_angle(from.angles[0]), dequantize_angle(from.angles[1]));
+
+	return Some(RenderEntity {
+		id: to.id,
+		origin: from_origin + (to_origin - from_origin) * frac,
+		yaw: lerp_angle(from_yaw, to_yaw, frac),
+		pitch: lerp_angle(from_pitch, to_pitch, frac),
+		model: to.model,
+		frame: if frac < 0.5f32 { from.frame } else { to.frame },
+	});
+}
+
+// Interpolates along the shorter way around the circle
+fn lerp_angle(from: f32, to: f32, frac: f32) -> f32
+{
+	let mut diff = (to - from) % std::f32::consts::TAU;
+	if diff > std::f32::consts::PI {
+		diff -= std::f32::consts::TAU;
+	} else if diff < -std::f32::consts::PI {
+		diff += std::f32::consts::TAU;
+	}
+
+	return from + diff * frac;
+}
@@ -1,41 +1,117 @@
 mod transport;
-use transport::Transport;
+use transport::GnsTransport;
 
 mod bsp_render;
 use bsp_render::*;
 
 mod bsp_lit;
-use bsp_lit::*;
 
 mod palette;
-use palette::PALETTE;
 
-use std::{f32::consts::PI, ffi::c_void, sync::atomic::{AtomicBool, Ordering} };
+mod prediction;
+use prediction::Prediction;
+
+mod interp;
+use interp::{Interpolation, DEFAULT_INTERP_DELAY};
+
+mod chat;
+use chat::Chat;
+
+mod config;
+use config::{ClientConfig, DEFAULT_CONFIG_PATH};
+
+mod console;
+use console::{log, ConsoleOverlay};
+
+mod world;
+use world::World;
+
+use std::{f32::consts::PI, sync::atomic::{AtomicBool, Ordering} };
 use raylib::prelude::*;
+use enumset::EnumSet;
 use gns::GnsGlobal;
-use std::net::Ipv4Addr;
 use std::error::Error;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
 
-use shared::message::Message;
-use shared::bsp_entity;
+use shared::console::{Console, CvarFlag, CvarValue};
+use shared::message::*;
+use shared::rcon::rcon_response;
 use shared::bsp::*;
 use shared::bsp_query::*;
-use shared::player::Player;
+use shared::player::{Player, EYE_HEIGHT, HITBOX_MAXS, HITBOX_MINS};
+use shared::user_cmd::*;
+use shared::transport::*;
+use shared::snapshot::{player_entity_id, SnapshotHistory, MODEL_IMPACT, MODEL_PLAYER};
+
+// Caps how many commands a single slow frame can generate, so a hitch doesn't snowball
+const MAX_CMDS_PER_FRAME: u32 = 8;
+
+const DEFAULT_SENSITIVITY: f32 = 0.5f32;
+const DEFAULT_FOV: f32 = 60f32;
+
+// Archived cvars are loaded from here on startup and saved back on exit
+const ARCHIVE_PATH: &str = "config.cfg";
+
+enum ConnectionStatus {
+	Connecting,
+	Handshaking,
+	Joined { map: String, tick_rate: u32, slot: u8 },
+	Rejected(RejectReason),
+	Closed(String),
+}
+
+// Everything the client knows about its connection to the server
+struct NetState {
+	server: Option<ConnectionId>,
+	status: ConnectionStatus,
+	prediction: Prediction,
+	snapshots: SnapshotHistory,
+	interp: Interpolation,
+	rcon_pending: VecDeque<(String, String)>, // Commands and the password to send them with, each waiting on a challenge
+	pending_map: Option<String>, // The server's map, loaded before the next frame unless it already is
+	rejoin: bool, // Say hello again once the map from a `ChangeLevel` is loaded
+}
+
+impl NetState {
+	fn new() -> NetState {
+		return NetState { server: None, status: ConnectionStatus::Connecting, prediction: Prediction::new(), snapshots: SnapshotHistory::new(), interp: Interpolation::new(DEFAULT_INTERP_DELAY), rcon_pending: VecDeque::new(), pending_map: None, rejoin: false };
+	}
+}
+
+struct InputState {
+	yaw: f32,
+	pitch: f32,
+	free_move: bool,
+	sequence: u32,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>>
 {
+	let config: ClientConfig = shared::config::load(std::env::args(), DEFAULT_CONFIG_PATH).unwrap_or_else(|err| {
+		eprintln!("Bad client config: {err}");
+		std::process::exit(1);
+	});
+
     // Initial the global networking state. Note that this instance must be unique per-process.
     let gns_global = GnsGlobal::get().expect("no global networking state");
 
     let (mut rl, thread) = raylib::init()
-        .size(1920, 1080)
+        .size(config.width, config.height)
         .title("Hello, World")
         .build();
 
-    let transport = Transport::new(gns_global.clone(), Ipv4Addr::LOCALHOST.into(), 27821).expect("connection failed");
-	//let bsp = load_bsp("assets/box.bsp");
-	//let bsp = load_bsp("assets/qbj3_chaosed0.bsp");
+    // A listen server runs the game server on its own thread in this process, talking to us over a loopback
+    let mut transport: Box<dyn Transport> = if config.listen {
+        let (mut server_end, client_end) = loopback_pair();
+        // Nothing types into the listen server's console yet
+        let (_, commands) = std::sync::mpsc::channel();
+        std::thread::spawn(move || server::run(&mut server_end, &server::config::ServerConfig::default(), commands));
+        Box::new(client_end)
+    } else {
+        Box::new(GnsTransport::new(gns_global.clone(), config.address, config.port).expect("connection failed"))
+    };
 	let mut bsp_render = BspRender::new();
 
 	bsp_render.load_skybox("assets/skybox/mak_cloudysky5");
@@ -57,62 +133,8 @@ async fn main() -> Result<(), Box<dyn Error>>
 
 	bsp_render.load_shaders(&default_shader, &cutout_shader, &skybox_shader);
 
-	let textures = {
-		let mut image_gen_set = tokio::task::JoinSet::new();
-		for (i, texture) in bsp.textures.iter().enumerate()
-		{
-			let pixels = texture.pixels.clone();
-			let width = texture.width;
-			let height = texture.height;
-
-			image_gen_set.spawn(async move { (i, gen_pixels(pixels, width, height)) });
-		}
-
-		let mut textures = std::iter::repeat_with(|| Option::<Texture2D>::None).take(bsp.textures.len()).collect::<Vec<_>>();
-		while let Some(tup) = image_gen_set.join_next().await
-		{
-			let (i, pixels) = tup.unwrap();
-			let texture = &bsp.textures[i];
-			let image = image_from_pixels(pixels, texture.width, texture.height, PixelFormat::PIXELFORMAT_UNCOMPRESSED_R8G8B8A8);
-			let tex = rl.load_texture_from_image(&thread, &image)
-				.unwrap_or_else(|err| panic!("Could not generate texture from image: {err}"));
-
-			tex.set_texture_filter(&thread, TextureFilter::TEXTURE_FILTER_POINT);
-			tex.set_texture_wrap(&thread, TextureWrap::TEXTURE_WRAP_REPEAT);
-			textures[i] = Some(tex);
-		}
-
-		textures.into_iter().map(|t| t.unwrap()).collect::<Vec<Texture2D>>()
-	};
-
-	let light_data = pack_lightmaps(&bsp);
-	bsp_render.build_buffers(&bsp, &light_data);
-
-	let lightmaps = light_data.lightmaps.into_iter().map(|lm|
-		{
-			let image = image_from_pixels(lm.bytes, lm.width, lm.height, PixelFormat::PIXELFORMAT_UNCOMPRESSED_R8G8B8);
-			let tex = rl.load_texture_from_image(&thread, &image)
-				.unwrap_or_else(|err| panic!("Could not generate texture from image: {err}"));
-
-			tex.set_texture_filter(&thread, TextureFilter::TEXTURE_FILTER_BILINEAR);
-			tex.set_texture_wrap(&thread, TextureWrap::TEXTURE_WRAP_CLAMP);
-			return tex;
-		}).collect::<Vec<Texture2D>>();
-
-	if let Some(lightgrid) = &bsp.lightgrid {
-		bsp_render.build_lightgrid_data(lightgrid);
-
-		let dist_loc = mesh_shader.get_shader_location("lgData.dist");
-		let size_x_loc = mesh_shader.get_shader_location("lgData.size_x");
-		let size_y_loc = mesh_shader.get_shader_location("lgData.size_y");
-		let size_z_loc = mesh_shader.get_shader_location("lgData.size_z");
-		let mins_loc = mesh_shader.get_shader_location("lgData.mins");
-		mesh_shader.set_shader_value(dist_loc, to_wld(lightgrid.header.grid_dist));
-		mesh_shader.set_shader_value(size_x_loc, lightgrid.header.grid_size[1]);
-		mesh_shader.set_shader_value(size_y_loc, lightgrid.header.grid_size[2]);
-		mesh_shader.set_shader_value(size_z_loc, lightgrid.header.grid_size[0]);
-		mesh_shader.set_shader_value(mins_loc, to_wld(lightgrid.header.grid_mins));
-	}
+	// Nothing to show until the server tells us which map it's running
+	let mut world: Option<World> = None;
 
 	//let mut time = 0f32;
 
@@ -120,11 +142,9 @@ async fn main() -> Result<(), Box<dyn Error>>
 
 	rl.set_target_fps(60);
 
-    let origin_str = "origin".to_string();
-	let mut cam = Camera3D::perspective(Vector3::ZERO, Vector3::Z, Vector3::Y, 60f32);
+	let mut cam = Camera3D::perspective(Vector3::ZERO, Vector3::Z, Vector3::Y, DEFAULT_FOV);
 
-    let pos = bsp_entity::of_type(&bsp, "info_player_start").next().unwrap().get_vec3(&origin_str);
-    let mut player = Player::new(pos + Vector3::Y);
+    let mut player = Player::new(Vector3::ZERO);
 
 	rl.disable_cursor();
 	rl.set_exit_key(None);
@@ -132,15 +152,58 @@ async fn main() -> Result<(), Box<dyn Error>>
     let mut raycast_position = None;
     let mut raycast_end = None;
 
-    let bsp_visq = BspVisQuery::new(&bsp);
-    let bsp_clipq = BspClipQuery::new(&bsp);
-
 	let mut cube_pos = Vector3::Y * 64f32;
 	let mut cube_dir = 1f32;
 
-    while !rl.window_should_close() {
+	let mut input = InputState { yaw: 0f32, pitch: 0f32, free_move: false, sequence: 0 };
+	let cmd_dt = CMD_MSEC as f32 / 1000f32;
+	let mut cmd_time = 0f32;
+	let mut net = NetState::new();
+	let mut show_net_debug = false;
+	let mut chat = Chat::new();
+	let client_id = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+	let client_name = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or("player".to_string());
+
+	let mut console = Console::new();
+	register_commands(&mut console, &client_name);
+	let mut console_overlay = ConsoleOverlay::new();
+	let mut quit = false;
+
+	if std::path::Path::new(ARCHIVE_PATH).is_file() {
+		console.exec(ARCHIVE_PATH, &mut |console, cmd| console.print(format!("{} can't be run from {ARCHIVE_PATH}", cmd.name)));
+	}
+
+    while !rl.window_should_close() && !quit {
 		let dt = rl.get_frame_time();
 
+		// Maps are loaded between frames, and the old one goes first so the two are never loaded at once
+		if let Some(map) = net.pending_map.take() && world.as_ref().is_none_or(|world| world.map != map) {
+			world = None;
+			match World::load(&mut rl, &thread, &mut bsp_render, &mut mesh_shader, &map).await {
+				Ok(loaded) => {
+					log(format!("Loaded {map}"));
+					player = Player::new(loaded.spawn + Vector3::Y);
+					world = Some(loaded);
+				}
+				Err(err) => {
+					log(format!("Couldn't load {map}: {err}"));
+					if let Some(conn) = net.server {
+						transport.close(conn, "missing map");
+					}
+					net.server = None;
+					net.rejoin = false;
+					net.status = ConnectionStatus::Closed(format!("missing map {map}"));
+				}
+			}
+		}
+
+		if net.rejoin && world.is_some() && let Some(conn) = net.server {
+			net.rejoin = false;
+			transport.send_default(conn, &hello(&console, client_id, &client_name));
+		}
+
+		let clipq = world.as_ref().map(|world| BspClipQuery::new(&world.bsp));
+
 		cube_pos += cube_dir * Vector3::X * 64f32 * dt;
 		if cube_dir > 0f32 && cube_pos.x > 256f32 {
 			cube_dir = -1f32;
@@ -148,20 +211,30 @@ async fn main() -> Result<(), Box<dyn Error>>
 			cube_dir = 1f32;
 		}
 
-		if rl.is_key_pressed(KeyboardKey::KEY_ESCAPE) {
+		// Only one of the console and chat box takes the keyboard at a time, and escape closes
+		// whichever is open before it releases the cursor
+		let console_was_open = console_overlay.is_open();
+		let chat_was_typing = chat.is_typing();
+		let console_cmds = if chat_was_typing { vec!() } else { console_overlay.update(&mut rl, &mut console) };
+		let chat_line = if console_was_open || console_overlay.is_open() { None } else { chat.update(&mut rl, dt) };
+
+		if !console_was_open && !chat_was_typing && rl.is_key_pressed(KeyboardKey::KEY_ESCAPE) {
 			rl.enable_cursor();
 		}
 
+		let typing = chat.is_typing() || console_overlay.is_open();
+		let sensitivity = console.get_float("sensitivity").unwrap_or(DEFAULT_SENSITIVITY);
+
 		if rl.is_cursor_hidden() {
-			poll_input(&mut rl, &mut player);
+			poll_look(&mut rl, &mut input, sensitivity, typing);
 		} else if rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_LEFT) {
 			rl.disable_cursor();
 		}
 
-		if rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_LEFT) {
+		if let Some(clipq) = &clipq && rl.is_mouse_button_pressed(MouseButton::MOUSE_BUTTON_LEFT) {
             raycast_position = Some(cam.position);
             let raycast_dir = cam.target - cam.position;
-            let rend = ray_intersect(&bsp_clipq, raycast_position.unwrap(), raycast_dir, f32::INFINITY, *DPASS);
+            let rend = ray_intersect(clipq, raycast_position.unwrap(), raycast_dir, f32::INFINITY, *DPASS);
             raycast_end = Some(match rend {
 				Some(rend) => rend.position,
 				None => raycast_position.unwrap() + raycast_dir * 9999f32
@@ -173,11 +246,99 @@ async fn main() -> Result<(), Box<dyn Error>>
         //let leaf = bsp_query::get_leaf_containing_point(&bsp, cam.position);
         //println!("LEAF {:?}: {:?} {:?} {:?} {:?}", cam.position, leaf.contents, leaf.firstmarksurface, leaf.nummarksurfaces, leaf.visofs);
 
-        transport.poll_messages(message_handler);
-        gns_global.poll_callbacks();
+		for event in transport.poll_events() {
+			match event {
+				ConnectionEvent::Connected(conn) => {
+					net.server = Some(conn);
+					transport.send_default(conn, &hello(&console, client_id, &client_name));
+					net.status = ConnectionStatus::Handshaking;
+				}
+				ConnectionEvent::Disconnected(_, reason) => {
+					log(format!("Disconnected: {reason}"));
+					net.server = None;
+					if !matches!(net.status, ConnectionStatus::Rejected(_) | ConnectionStatus::Closed(_)) {
+						net.status = ConnectionStatus::Closed(reason);
+					}
+				}
+				ConnectionEvent::Connecting(_) => {}
+			}
+		}
+
+		for (conn, msg) in transport.poll_messages() {
+			if let Some(reply) = handle_message(msg, &mut net, &mut chat, &mut player, clipq.as_ref()) {
+				transport.send_default(conn, &reply);
+			}
+		}
+
+		if let Some(text) = chat_line && let Some(conn) = net.server {
+			transport.send_default(conn, &Message::Chat(text));
+		}
+
+		for cmd in console_cmds {
+			match cmd.name.as_str() {
+				"connect" => match parse_address(&cmd.args[0]) {
+					Ok(addr) => {
+						if let Some(conn) = net.server {
+							transport.close(conn, "connecting elsewhere");
+						}
+
+						log(format!("Connecting to {addr}..."));
+						match GnsTransport::new(gns_global.clone(), addr.ip(), addr.port()) {
+							Ok(gns) => {
+								transport = Box::new(gns);
+								net = NetState::new();
+							}
+							Err(_) => log(format!("Couldn't connect to {addr}")),
+						}
+					}
+					Err(err) => log(err),
+				},
+				"disconnect" => if let Some(conn) = net.server {
+					transport.close(conn, "disconnected");
+					net.server = None;
+					net.status = ConnectionStatus::Closed("disconnected".to_string());
+				},
+				"say" => if let Some(conn) = net.server {
+					transport.send_default(conn, &Message::Chat(cmd.rest(0)));
+				},
+				"rcon" => match (net.server, console.get_str("rcon_password").unwrap_or_default()) {
+					(None, _) => log("Not connected to a server"),
+					(_, "") => log("Set rcon_password first"),
+					(Some(conn), password) => {
+						net.rcon_pending.push_back((cmd.rest(0), password.to_string()));
+						transport.send_default(conn, &Message::RconChallengeRequest);
+					}
+				},
+				"quit" => quit = true,
+				_ => {}
+			}
+		}
+
+		// Movement only advances in fixed steps, one command per step
+		cmd_time = (cmd_time + dt).min(cmd_dt * MAX_CMDS_PER_FRAME as f32);
+		let mut sent_cmd = false;
+		while let Some(clipq) = &clipq && cmd_time >= cmd_dt {
+			cmd_time -= cmd_dt;
+			let cmd = build_cmd(&rl, &mut input, &net.interp, typing);
+			player.simulate(clipq, &cmd);
+			net.prediction.add_cmd(cmd);
+			sent_cmd = true;
+		}
+
+		// Every packet carries the newest few unacknowledged commands so a dropped packet doesn't lose input
+		if sent_cmd && let Some(conn) = net.server {
+			let skip = net.prediction.pending_count().saturating_sub(MAX_CMDS_PER_MESSAGE);
+			transport.send_default(conn, &Message::UserCmds(net.prediction.pending().skip(skip).copied().collect()));
+		}
+
+		net.prediction.update(dt);
+		net.interp.update(dt);
+		let remote_entities = net.interp.sample(&net.snapshots);
+
+		if !typing && rl.is_key_pressed(KeyboardKey::KEY_F3) { show_net_debug = !show_net_debug; }
 
-        player.update(&bsp_clipq, dt);
-        cam.position = player.pos + Vector3::Y * 16f32;
+        cam.fovy = console.get_float("fov").unwrap_or(DEFAULT_FOV).clamp(10f32, 170f32);
+        cam.position = player.pos + net.prediction.render_offset() + Vector3::Y * EYE_HEIGHT;
         cam.target = cam.position + player.forward();
 
         let mut d = rl.begin_drawing(&thread);
@@ -191,12 +352,28 @@ async fn main() -> Result<(), Box<dyn Error>>
 		{
 			let modelview: Matrix = unsafe { raylib::ffi::rlGetMatrixModelview().try_into().unwrap() };
 			let projection: Matrix = unsafe { raylib::ffi::rlGetMatrixProjection().try_into().unwrap() };
-			bsp_render.render(&textures, &lightmaps, &bsp, modelview * projection, cam);
+			if let Some(world) = &world {
+				bsp_render.render(&world.textures, &world.lightmaps, &world.bsp, modelview * projection, cam);
+			}
 
 			d3d.draw_shader_mode(&mut mesh_shader, |mut dsm| {
 				bsp_render.bind_lightgrid_data();
 				//dsm.draw_cube(cube_pos, 64f32, 64f32, 64f32, Color::WHITE);
 				dsm.draw_cube(cam.position + cam.forward() * 16f32, 4f32, 4f32, 4f32, Color::WHITE);
+
+				if let ConnectionStatus::Joined { slot, .. } = net.status {
+					let own_id = player_entity_id(slot);
+					for entity in remote_entities.iter().filter(|e| e.id != own_id) {
+						match entity.model {
+							MODEL_PLAYER => {
+								let size = HITBOX_MAXS - HITBOX_MINS;
+								dsm.draw_cube(entity.origin + (HITBOX_MINS + HITBOX_MAXS) * 0.5f32, size.x, size.y, size.z, Color::WHITE);
+							}
+							MODEL_IMPACT => dsm.draw_cube(entity.origin, 4f32, 4f32, 4f32, Color::ORANGE),
+							_ => {}
+						}
+					}
+				}
 			});
 
             if let Some(end) = raycast_end &&
@@ -208,14 +385,60 @@ async fn main() -> Result<(), Box<dyn Error>>
 			//render_lightgrid_leafs(&mut d3d, &bsp);
 		});
 
-		d.draw_texture_ex(&lightmaps[0], Vector2::new(10f32, 10f32), 0f32, 0.2f32, Color::WHITE);
+		if let Some(world) = &world {
+			d.draw_texture_ex(&world.lightmaps[0], Vector2::new(10f32, 10f32), 0f32, 0.2f32, Color::WHITE);
+		}
 
 		d.draw_fps(10, 10);
+		draw_connection_status(&mut d, &net.status);
+		chat.draw(&mut d);
+		console_overlay.draw(&mut d);
+
+		if show_net_debug {
+			draw_net_debug(&mut d, &net);
+		}
     }
 
+	if let Err(err) = std::fs::write(ARCHIVE_PATH, console.archive()) {
+		eprintln!("Couldn't save {ARCHIVE_PATH}: {err}");
+	}
+
 	return Ok(());
 }
 
+fn register_commands(console: &mut Console, default_name: &str)
+{
+	console.register_cvar("name", CvarValue::Str(default_name.to_string()), CvarFlag::Archive.into(), "Name other players see, used from the next connect");
+	console.register_cvar("sensitivity", CvarValue::Float(DEFAULT_SENSITIVITY), CvarFlag::Archive.into(), "Mouse look speed");
+	console.register_cvar("fov", CvarValue::Float(DEFAULT_FOV), CvarFlag::Archive.into(), "Vertical field of view in degrees");
+
+	console.register_command("connect", "connect <address[:port]>", "Connect to a server", 1);
+	console.register_command("disconnect", "disconnect", "Leave the current server", 0);
+	console.register_command("say", "say <text>", "Send a chat message", 1);
+	console.register_cvar("rcon_password", CvarValue::Str(String::new()), EnumSet::empty(), "Password for the server's remote console");
+
+	console.register_command("rcon", "rcon <command>", "Run a command on the server's console", 1);
+	console.register_command("quit", "quit", "Exit the game", 0);
+}
+
+fn hello(console: &Console, id: u64, default_name: &str) -> Message
+{
+	let name = console.get_str("name").unwrap_or(default_name).to_string();
+	return Message::HelloFromClient { protocol_version: PROTOCOL_VERSION, build_hash: BUILD_HASH, id, name };
+}
+
+// Takes an address with or without a port, using the default port when there isn't one
+fn parse_address(text: &str) -> Result<SocketAddr, String>
+{
+	if let Ok(addr) = text.parse::<SocketAddr>() {
+		return Ok(addr);
+	}
+
+	return text.parse::<IpAddr>()
+		.map(|ip| SocketAddr::new(ip, server::config::DEFAULT_PORT))
+		.map_err(|_| format!("{text} isn't an address"));
+}
+
 fn render_lightgrid_leafs(d3d: &mut RaylibMode3D<'_, RaylibDrawHandle>, bsp: &Bsp) {
 	let Some(lightgrid) = bsp.lightgrid.as_ref() else { return; };
 	render_lightgrid_leafs_recursive(d3d, &lightgrid, &lightgrid.nodes[lightgrid.header.root_node as usize], Vector3::ZERO, &lightgrid.header);
@@ -268,67 +491,156 @@ fn render_lightgrid_leaf(d3d: &mut RaylibMode3D<'_, RaylibDrawHandle>, lightgrid
 	}
 }
 
-fn gen_pixels(pixels: Vec<u8>, width: u32, height: u32) -> Vec<u8>
+fn poll_look(rl: &mut RaylibHandle, input: &mut InputState, sensitivity: f32, typing: bool)
 {
-	if width == 0 || height == 0 {
-		return [255, 0, 255, 255, 255, 0, 255, 255, 255, 0, 255, 255, 255, 0, 255, 255].into();
-	}
+    let mouse_delta = rl.get_mouse_delta();
+	let dt = rl.get_frame_time();
+    let rot_speed = sensitivity * dt;
 
-	let pixels_u8 = pixels.iter().flat_map(|b| {
-		let col = PALETTE[*b as usize].to_le_bytes();
-		return [col[2], col[1], col[0], 255];
-	}).collect::<Vec<u8>>();
+	input.yaw -= mouse_delta.x * rot_speed;
+	input.pitch += mouse_delta.y * rot_speed;
+	input.pitch = input.pitch.clamp(-PI * 0.49f32, PI * 0.49f32);
 
-	return pixels_u8;
+    if !typing && rl.is_key_pressed(KeyboardKey::KEY_ZERO) { input.free_move = !input.free_move; }
 }
 
-fn image_from_pixels(pixels: Vec<u8>, width: u32, height: u32, format: PixelFormat) -> Image
+fn build_cmd(rl: &RaylibHandle, input: &mut InputState, interp: &Interpolation, typing: bool) -> UserCmd
 {
-	unsafe {
-		let mut pixels = std::mem::ManuallyDrop::new(pixels);
-
-		return Image::from_raw(raylib::ffi::Image {
-			data: pixels.as_mut_ptr() as *mut c_void,
-			width: if width == 0 { 2 } else { width as i32 },
-			height: if height == 0 { 2 } else { height as i32 },
-			format: format as i32,
-			mipmaps: 1
-		});
+	let mut cmd = UserCmd::new(input.sequence);
+	input.sequence += 1;
+	(cmd.view_tick, cmd.view_lerp) = interp.view_tick();
+
+	cmd.yaw = input.yaw;
+	cmd.pitch = input.pitch;
+
+	if input.free_move { cmd.buttons |= Button::FreeMove; }
+
+	// Keys don't drive movement while the cursor is released or they're going into the chat box
+	if !rl.is_cursor_hidden() || typing {
+		return cmd;
 	}
-}
 
-fn poll_input(rl: &mut RaylibHandle, player : &mut Player)
-{
-	const ROT_SPEED: f32 = 0.5f32;
+    let mut movement = [0i32; 3];
 
-    let mouse_delta = rl.get_mouse_delta();
-	let dt = rl.get_frame_time();
-    let rot_speed = ROT_SPEED * dt;
+	if rl.is_key_down(KeyboardKey::KEY_W) { movement[2] += 1; }
+	if rl.is_key_down(KeyboardKey::KEY_A) { movement[0] += 1; }
+	if rl.is_key_down(KeyboardKey::KEY_S) { movement[2] -= 1; }
+	if rl.is_key_down(KeyboardKey::KEY_D) { movement[0] -= 1; }
+	if rl.is_key_down(KeyboardKey::KEY_SPACE) { movement[1] += 1; }
+	if rl.is_key_down(KeyboardKey::KEY_Q) { movement[1] += 1; }
+	if rl.is_key_down(KeyboardKey::KEY_LEFT_CONTROL) { movement[1] -= 1; }
+	if rl.is_key_down(KeyboardKey::KEY_E) { movement[1] -= 1; }
 
-	player.yaw -= mouse_delta.x * rot_speed;
-	player.pitch += mouse_delta.y * rot_speed;
-	player.pitch = player.pitch.clamp(-PI * 0.49f32, PI * 0.49f32);
+	let to_axis = |v: i32| (v.clamp(-1, 1) * MOVE_AXIS_MAX as i32) as i8;
+	cmd.side_move = to_axis(movement[0]);
+	cmd.up_move = to_axis(movement[1]);
+	cmd.forward_move = to_axis(movement[2]);
 
-    let mut movement = Vector3::ZERO;
+    if rl.is_key_down(KeyboardKey::KEY_SPACE) { cmd.buttons |= Button::Jump; }
+    if rl.is_key_down(KeyboardKey::KEY_LEFT_SHIFT) { cmd.buttons |= Button::Sprint; }
+	if rl.is_mouse_button_down(MouseButton::MOUSE_BUTTON_LEFT) { cmd.buttons |= Button::Attack; }
 
-	if rl.is_key_down(KeyboardKey::KEY_W) { movement.z += 1f32; }
-	if rl.is_key_down(KeyboardKey::KEY_A) { movement.x += 1f32; }
-	if rl.is_key_down(KeyboardKey::KEY_S) { movement.z -= 1f32; }
-	if rl.is_key_down(KeyboardKey::KEY_D) { movement.x -= 1f32; }
-	if rl.is_key_down(KeyboardKey::KEY_SPACE) { movement.y += 1f32; }
-	if rl.is_key_down(KeyboardKey::KEY_Q) { movement.y += 1f32; }
-	if rl.is_key_down(KeyboardKey::KEY_LEFT_CONTROL) { movement.y -= 1f32; }
-	if rl.is_key_down(KeyboardKey::KEY_E) { movement.y -= 1f32; }
+	return cmd;
+}
 
-    player.movement = movement;
+fn draw_net_debug(d: &mut RaylibDrawHandle, net: &NetState)
+{
+	let prediction = &net.prediction;
+	let lines = [
+		format!("prediction error: {:.3}", prediction.last_error()),
+		format!("smoothing offset: {:.3}", prediction.render_offset().length()),
+		format!("pending cmds: {}", prediction.pending_count()),
+		format!("dropped cmds: {}", prediction.dropped_cmds()),
+		format!("snapshot tick: {}", net.snapshots.latest().map_or(0, |s| s.tick)),
+		format!("interp delay: {:.0}ms{}", net.interp.delay() * 1000f32, if net.interp.is_extrapolating() { " (extrapolating)" } else { "" }),
+	];
+
+	for (i, line) in lines.iter().enumerate() {
+		d.draw_text(line, 10, 40 + i as i32 * 20, 20, Color::WHITE);
+	}
+}
 
-    if rl.is_key_pressed(KeyboardKey::KEY_SPACE) { player.jump = true; }
-    player.sprint = rl.is_key_down(KeyboardKey::KEY_LEFT_SHIFT);
+fn draw_connection_status(d: &mut RaylibDrawHandle, status: &ConnectionStatus)
+{
+	let text = match status {
+		ConnectionStatus::Connecting => "Connecting...".to_string(),
+		ConnectionStatus::Handshaking => "Waiting for server...".to_string(),
+		ConnectionStatus::Joined { .. } => return,
+		ConnectionStatus::Rejected(reason) => format!("Disconnected: {reason}"),
+		ConnectionStatus::Closed(reason) => format!("Connection closed: {reason}"),
+	};
 
-    if rl.is_key_pressed(KeyboardKey::KEY_ZERO) { player.free_move = !player.free_move; }
+	let width = d.measure_text(&text, 30);
+	let x = (d.get_screen_width() - width) / 2;
+	d.draw_text(&text, x, d.get_screen_height() / 3, 30, Color::WHITE);
 }
 
-fn message_handler(_msg: Message) {
+// Returns a reply to send back to the server, if any
+fn handle_message<'a>(msg: Message, net: &mut NetState, chat: &mut Chat, player: &mut Player, bsp: Option<&'a BspClipQuery<'a>>) -> Option<Message> {
+	match msg {
+		Message::HelloFromServer { map, tick_rate, slot } => {
+			log(format!("Joined server running {map} at {tick_rate} ticks/s in slot {slot}"));
+			net.interp.set_tick_rate(tick_rate);
+			net.interp.reset();
+			net.pending_map = Some(map.clone());
+			net.status = ConnectionStatus::Joined { map, tick_rate, slot };
+		}
+		Message::ChangeLevel { map } => {
+			log(format!("Server is changing map to {map}"));
+			// Nothing from the old map carries over, we start again from the server's hello
+			net.prediction = Prediction::new();
+			net.snapshots = SnapshotHistory::new();
+			net.interp.reset();
+			net.status = ConnectionStatus::Handshaking;
+			net.pending_map = Some(map);
+			net.rejoin = true;
+		}
+		Message::Rejected(reason) => {
+			log(format!("Server rejected connection: {reason}"));
+			net.status = ConnectionStatus::Rejected(reason);
+		}
+		Message::ChatFrom { sender, text } => {
+			log(format!("{}: {text}", sender.as_deref().unwrap_or("Server")));
+			chat.push(sender.as_deref(), &text);
+		}
+		Message::RconChallenge { nonce } => {
+			if let Some((command, password)) = net.rcon_pending.pop_front() {
+				return Some(Message::RconCommand { response: rcon_response(nonce, &password, &command), command });
+			}
+		}
+		Message::RconOutput { lines } => {
+			for line in lines {
+				log(line);
+			}
+		}
+		// Anything still in flight from before a map change is stale
+		Message::PlayerState { .. } | Message::Snapshot(_) if !matches!(net.status, ConnectionStatus::Joined { .. }) => {}
+		Message::PlayerState { ack_sequence, state } => {
+			if let Some(bsp) = bsp {
+				net.prediction.reconcile(player, bsp, &state.to_state(), ack_sequence);
+			}
+		}
+		Message::Snapshot(delta) => {
+			// Snapshots based on one we no longer have are dropped, the server rebases on our last ack
+			let base = delta.base_tick.and_then(|tick| net.snapshots.get(tick));
+			if net.snapshots.latest().is_some_and(|latest| latest.tick >= delta.tick) {
+				return None;
+			}
+
+			match delta.apply(base) {
+				Ok(snapshot) => {
+					let tick = snapshot.tick;
+					net.snapshots.push(snapshot);
+					net.interp.on_snapshot(tick);
+					return Some(Message::SnapshotAck { tick });
+				}
+				Err(err) => log(format!("Dropping snapshot: {err:?}")),
+			}
+		}
+		_ => {}
+	}
+
+	return None;
 }
 
 fn print_bsp_tree(bsp: &Bsp, idx: i32, ind: usize) {
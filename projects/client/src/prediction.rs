@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+use raylib::prelude::*;
+use shared::bsp_query::BspQuery;
+use shared::player::*;
+use shared::user_cmd::UserCmd;
+
+// Roughly a second of commands at the fixed command rate
+const CMD_BACKUP: usize = 64;
+
+// Corrections bigger than this are teleports or a badly desynced state, so snap instead of smoothing
+const SNAP_DISTANCE: f32 = 64f32;
+
+// How quickly the smoothed error decays, as a fraction per second
+const SMOOTH_RATE: f32 = 10f32;
+
+pub struct Prediction
+{
+	pending: VecDeque<UserCmd>,
+	smooth_error: Vector3,
+	last_error: f32,
+	dropped_cmds: u32,
+	last_ack: Option<u32>, // PlayerState is unreliable, so anything acking this or earlier arrived out of order
+}
+
+impl Prediction
+{
+	pub fn new() -> Prediction
+	{
+		return Prediction { pending: VecDeque::with_capacity(CMD_BACKUP), smooth_error: Vector3::ZERO, last_error: 0f32, dropped_cmds: 0, last_ack: None };
+	}
+
+	// Records a command that has been simulated locally but not yet acknowledged by the server
+	pub fn add_cmd(&mut self, cmd: UserCmd)
+	{
+		if self.pending.len() >= CMD_BACKUP {
+			self.pending.pop_front();
+			self.dropped_cmds += 1;
+		}
+
+		self.pending.push_back(cmd);
+	}
+
+	pub fn pending(&self) -> impl Iterator<Item = &UserCmd>
+	{
+		return self.pending.iter();
+	}
+
+	// Applies the authoritative state for the last command the server processed, then re-simulates
+	// every command it hasn't seen yet on top of it
+	pub fn reconcile<'a>(&mut self, player: &mut Player, bsp: &'a impl BspQuery<'a>, state: &PlayerState, ack_sequence: u32)
+	{
+		// The commands an older state needs replayed on top of it were dropped by the newer one
+		if self.last_ack.is_some_and(|last_ack| ack_sequence <= last_ack) {
+			return;
+		}
+		self.last_ack = Some(ack_sequence);
+
+		while let Some(cmd) = self.pending.front() && cmd.sequence <= ack_sequence {
+			self.pending.pop_front();
+		}
+
+		let predicted_pos = player.pos;
+
+		player.set_state(state);
+		for cmd in &self.pending {
+			player.simulate(bsp, cmd);
+		}
+
+		let error = predicted_pos - player.pos;
+		self.last_error = error.length();
+
+		if self.last_error > SNAP_DISTANCE {
+			self.smooth_error = Vector3::ZERO;
+		} else {
+			self.smooth_error += error;
+		}
+	}
+
+	pub fn update(&mut self, dt: f32)
+	{
+		self.smooth_error *= (1f32 - SMOOTH_RATE * dt).max(0f32);
+	}
+
+	// Offset to add to the simulated position when rendering, so corrections blend in over a few frames
+	pub fn render_offset(&self) -> Vector3
+	{
+		return self.smooth_error;
+	}
+
+	// Distance between the predicted and corrected position at the last reconcile
+	pub fn last_error(&self) -> f32
+	{
+		return self.last_error;
+	}
+
+	pub fn pending_count(&self) -> usize
+	{
+		return self.pending.len();
+	}
+
+	pub fn dropped_cmds(&self) -> u32
+	{
+		return self.dropped_cmds;
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use shared::bsp_query::FloorQuery;
+	use shared::user_cmd::MOVE_AXIS_MAX;
+
+	#[test]
+	fn older_acks_are_ignored()
+	{
+		let bsp = FloorQuery::new(vec!());
+		let mut player = Player::new(Vector3::new(0f32, 24f32, 0f32));
+		let mut prediction = Prediction::new();
+		let mut states = vec!();
+
+		for i in 0..10 {
+			let mut cmd = UserCmd::new(i);
+			cmd.forward_move = MOVE_AXIS_MAX;
+			player.simulate(&bsp, &cmd);
+			prediction.add_cmd(cmd);
+			states.push(player.state());
+		}
+
+		// The server's states match what we predicted, so the newer one changes nothing
+		prediction.reconcile(&mut player, &bsp, &states[7], 7);
+		let predicted = player.pos;
+		assert_eq!(prediction.pending_count(), 2);
+
+		// Going back to the older one would lose commands 6 and 7 and snap the player back
+		prediction.reconcile(&mut player, &bsp, &states[5], 5);
+		assert_eq!(player.pos, predicted);
+		assert_eq!(prediction.pending_count(), 2);
+	}
+}
@@ -1,16 +1,25 @@
+use crate::console::log;
 use shared::message::Message;
+use shared::transport::*;
 use gns::*;
+use gns::sys::{k_nSteamNetworkingSend_Reliable, k_nSteamNetworkingSend_Unreliable, k_nSteamNetworkingSend_UnreliableNoDelay};
+use gns::sys::ESteamNetworkingConnectionState;
 use std::net::IpAddr;
 use std::sync::Arc;
 
-pub struct Transport
+// A client only ever has the one connection, to the server
+pub const SERVER_CONNECTION: ConnectionId = ConnectionId(0);
+
+pub struct GnsTransport
 {
-    client: GnsSocket<IsClient>
+    gns_global: Arc<GnsGlobal>,
+    client: GnsSocket<IsClient>,
+    connected: bool,
 }
 
-impl Transport
+impl GnsTransport
 {
-    pub fn new(gns_global: Arc<GnsGlobal>, addr: IpAddr, port: u16) -> Result<Transport, ()> {
+    pub fn new(gns_global: Arc<GnsGlobal>, addr: IpAddr, port: u16) -> Result<GnsTransport, ()> {
         // Create a new [`GnsSocket`], the index type [`IsCreated`] is used to determine the state of the socket.
         // The [`GnsSocket::new`] function is only available for the [`IsCreated`] state. This is the initial state of the socket.
         let gns_socket = GnsSocket::<IsCreated>::new(gns_global.clone());
@@ -20,13 +29,42 @@ impl Transport
         // Since we are now using a client socket, we have access to a different set of operations.
         let client = gns_socket.connect(addr, port)?;
 
-        return Ok(Transport { client });
+        return Ok(GnsTransport { gns_global, client, connected: false });
     }
+}
+
+impl Transport for GnsTransport
+{
+    fn send(&mut self, _conn: ConnectionId, msg: &Message, reliability: Reliability) {
+        let flags = match reliability {
+            Reliability::Reliable => k_nSteamNetworkingSend_Reliable,
+            Reliability::Unreliable => k_nSteamNetworkingSend_Unreliable,
+            Reliability::UnreliableNoDelay => k_nSteamNetworkingSend_UnreliableNoDelay,
+        };
+
+        let bytes = msg.to_bytes();
+        let message = self.client.utils().allocate_message(self.client.connection(), flags, &bytes);
+        self.client.send_messages(vec![message]);
+    }
+
+    fn connections(&self) -> Vec<ConnectionId> {
+        return if self.connected { vec![SERVER_CONNECTION] } else { vec!() };
+    }
+
+    fn close(&mut self, _conn: ConnectionId, reason: &str) {
+        self.connected = false;
+        self.client.close_connection(self.client.connection(), 0, reason, true);
+    }
+
+    fn poll_messages(&mut self) -> Vec<(ConnectionId, Message)> {
+        let mut messages = vec!();
 
-    pub fn poll_messages(&self, mut msg_callback: impl FnMut(Message)) {
         loop {
             let num_msg = self.client.poll_messages::<100>(|message| {
-                msg_callback(Message::from_bytes(message.payload()))
+                match Message::from_bytes(message.payload()) {
+                    Ok(msg) => messages.push((SERVER_CONNECTION, msg)),
+                    Err(err) => log(format!("Dropping malformed message of {} bytes: {err}", message.payload().len())),
+                }
             });
 
             if let Some(n) = num_msg &&
@@ -35,22 +73,43 @@ impl Transport
             }
         }
 
+        return messages;
+    }
+
+    fn poll_events(&mut self) -> Vec<ConnectionEvent> {
+        self.gns_global.poll_callbacks();
+
+        let mut events = vec!();
+
         loop
         {
-            // Don't do anything with events.
-            // One would check the event for connection status, i.e. doing something when we are connected/disconnected from the server.
             let num_msg = self.client.poll_event::<100>(|ev| {
                 let info = ev.info();
                 let old_state = ev.old_state();
                 let new_state = info.state();
                 let end_reason = info.end_reason();
                 let end_debug = info.end_debug();
-                println!("connection event: {old_state:?} -> {new_state:?}. Reason: {end_reason:?} Debug: {end_debug}");
+                log(format!("connection event: {old_state:?} -> {new_state:?}. Reason: {end_reason:?} Debug: {end_debug}"));
+
+                match new_state {
+                    ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_Connected => {
+                        self.connected = true;
+                        events.push(ConnectionEvent::Connected(SERVER_CONNECTION));
+                    }
+                    ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ClosedByPeer |
+                    ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ProblemDetectedLocally => {
+                        self.connected = false;
+                        events.push(ConnectionEvent::Disconnected(SERVER_CONNECTION, end_debug.to_string()));
+                    }
+                    _ => {}
+                }
             });
 
             if num_msg < 100usize {
                 break;
             }
         }
+
+        return events;
     }
 }
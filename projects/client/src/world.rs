@@ -0,0 +1,120 @@
+use raylib::prelude::*;
+use shared::bsp::*;
+use shared::bsp_entity;
+use std::ffi::c_void;
+use crate::bsp_lit::pack_lightmaps;
+use crate::bsp_render::BspRender;
+use crate::palette::PALETTE;
+
+// Everything loaded for the map the server is running. Replaced whole when the map changes, dropping the
+// textures frees them and `load` frees the old render buffers before building new ones.
+pub struct World
+{
+	pub map: String,
+	pub bsp: Bsp,
+	pub textures: Vec<Texture2D>,
+	pub lightmaps: Vec<Texture2D>,
+	pub spawn: Vector3,
+}
+
+impl World
+{
+	// Loads a map by the name the server knows it by, which is a path next to the executable
+	pub async fn load(rl: &mut RaylibHandle, thread: &RaylibThread, bsp_render: &mut BspRender, mesh_shader: &mut Shader, map: &str) -> Result<World, String>
+	{
+		bsp_render.unload_map();
+		server::config::check_map(map)?;
+
+		let bsp = load_bsp(map)?;
+
+		let textures = {
+			let mut image_gen_set = tokio::task::JoinSet::new();
+			for (i, texture) in bsp.textures.iter().enumerate()
+			{
+				let pixels = texture.pixels.clone();
+				let width = texture.width;
+				let height = texture.height;
+
+				image_gen_set.spawn(async move { (i, gen_pixels(pixels, width, height)) });
+			}
+
+			let mut textures = std::iter::repeat_with(|| Option::<Texture2D>::None).take(bsp.textures.len()).collect::<Vec<_>>();
+			while let Some(tup) = image_gen_set.join_next().await
+			{
+				let (i, pixels) = tup.unwrap();
+				let texture = &bsp.textures[i];
+				let image = image_from_pixels(pixels, texture.width, texture.height, PixelFormat::PIXELFORMAT_UNCOMPRESSED_R8G8B8A8);
+				let tex = rl.load_texture_from_image(thread, &image)
+					.unwrap_or_else(|err| panic!("Could not generate texture from image: {err}"));
+
+				tex.set_texture_filter(thread, TextureFilter::TEXTURE_FILTER_POINT);
+				tex.set_texture_wrap(thread, TextureWrap::TEXTURE_WRAP_REPEAT);
+				textures[i] = Some(tex);
+			}
+
+			textures.into_iter().map(|t| t.unwrap()).collect::<Vec<Texture2D>>()
+		};
+
+		let light_data = pack_lightmaps(&bsp);
+		bsp_render.build_buffers(&bsp, &light_data);
+
+		let lightmaps = light_data.lightmaps.into_iter().map(|lm|
+			{
+				let image = image_from_pixels(lm.bytes, lm.width, lm.height, PixelFormat::PIXELFORMAT_UNCOMPRESSED_R8G8B8);
+				let tex = rl.load_texture_from_image(thread, &image)
+					.unwrap_or_else(|err| panic!("Could not generate texture from image: {err}"));
+
+				tex.set_texture_filter(thread, TextureFilter::TEXTURE_FILTER_BILINEAR);
+				tex.set_texture_wrap(thread, TextureWrap::TEXTURE_WRAP_CLAMP);
+				return tex;
+			}).collect::<Vec<Texture2D>>();
+
+		if let Some(lightgrid) = &bsp.lightgrid {
+			bsp_render.build_lightgrid_data(lightgrid);
+
+			let dist_loc = mesh_shader.get_shader_location("lgData.dist");
+			let size_x_loc = mesh_shader.get_shader_location("lgData.size_x");
+			let size_y_loc = mesh_shader.get_shader_location("lgData.size_y");
+			let size_z_loc = mesh_shader.get_shader_location("lgData.size_z");
+			let mins_loc = mesh_shader.get_shader_location("lgData.mins");
+			mesh_shader.set_shader_value(dist_loc, to_wld(lightgrid.header.grid_dist));
+			mesh_shader.set_shader_value(size_x_loc, lightgrid.header.grid_size[1]);
+			mesh_shader.set_shader_value(size_y_loc, lightgrid.header.grid_size[2]);
+			mesh_shader.set_shader_value(size_z_loc, lightgrid.header.grid_size[0]);
+			mesh_shader.set_shader_value(mins_loc, to_wld(lightgrid.header.grid_mins));
+		}
+
+		let spawn = bsp_entity::of_type(&bsp, "info_player_start").next().and_then(|e| e.get_vec3("origin").ok()).unwrap_or(Vector3::ZERO);
+
+		return Ok(World { map: map.to_string(), bsp, textures, lightmaps, spawn });
+	}
+}
+
+fn gen_pixels(pixels: Vec<u8>, width: u32, height: u32) -> Vec<u8>
+{
+	if width == 0 || height == 0 {
+		return [255, 0, 255, 255, 255, 0, 255, 255, 255, 0, 255, 255, 255, 0, 255, 255].into();
+	}
+
+	let pixels_u8 = pixels.iter().flat_map(|b| {
+		let col = PALETTE[*b as usize].to_le_bytes();
+		return [col[2], col[1], col[0], 255];
+	}).collect::<Vec<u8>>();
+
+	return pixels_u8;
+}
+
+fn image_from_pixels(pixels: Vec<u8>, width: u32, height: u32, format: PixelFormat) -> Image
+{
+	unsafe {
+		let mut pixels = std::mem::ManuallyDrop::new(pixels);
+
+		return Image::from_raw(raylib::ffi::Image {
+			data: pixels.as_mut_ptr() as *mut c_void,
+			width: if width == 0 { 2 } else { width as i32 },
+			height: if height == 0 { 2 } else { height as i32 },
+			format: format as i32,
+			mipmaps: 1
+		});
+	}
+}
@@ -0,0 +1,47 @@
+// Writes a map's entity lump out to a .ent file next to it, which the server and client then load in
+// place of the lump. Entities can be edited in there without recompiling the map.
+//
+//     export_ent assets/box.bsp
+//
+// An existing .ent file is kept unless --force is given, it may have changes in it.
+// Clients need a copy of the same file, they predict movement against the ladders in it.
+
+use shared::bsp::read_embedded_entities;
+use shared::ent::{parse_ent, write_ent};
+use std::path::Path;
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let force = args.iter().any(|arg| arg == "--force");
+    let maps: Vec<&String> = args.iter().filter(|arg| *arg != "--force").collect();
+    let [map] = maps.as_slice() else {
+        eprintln!("Usage: export_ent <map.bsp> [--force]");
+        exit(2);
+    };
+
+    let map_path = Path::new(map.as_str());
+    let ent_path = map_path.with_extension("ent");
+    if ent_path.exists() && !force {
+        eprintln!("{} already exists, use --force to replace it", ent_path.display());
+        exit(1);
+    }
+
+    let lump = read_embedded_entities(map_path).unwrap_or_else(|err| {
+        eprintln!("Couldn't read the entities in {}: {err}", map_path.display());
+        exit(1);
+    });
+
+    // Parsed and written back rather than copied, so the file starts out in the layout we write
+    let entities = parse_ent(&lump).unwrap_or_else(|err| {
+        eprintln!("Invalid entity lump in {} at {err}", map_path.display());
+        exit(1);
+    });
+
+    if let Err(err) = std::fs::write(&ent_path, write_ent(&entities)) {
+        eprintln!("Couldn't write {}: {err}", ent_path.display());
+        exit(1);
+    }
+
+    println!("Wrote {} entities to {}", entities.len(), ent_path.display());
+}
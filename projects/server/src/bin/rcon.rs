@@ -0,0 +1,85 @@
+// Runs one command on a server's remote console and prints what it said back.
+//
+//     RCON_PASSWORD=secret rcon 192.168.1.10:27821 kick 3
+//
+// The password comes from the environment so it doesn't end up in shell history or process listings.
+
+use gns::*;
+use gns::sys::{k_nSteamNetworkingSend_Reliable, ESteamNetworkingConnectionState};
+use shared::message::Message;
+use shared::rcon::rcon_response;
+use std::net::{IpAddr, SocketAddr};
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() < 2 {
+        eprintln!("Usage: RCON_PASSWORD=<password> rcon <address[:port]> <command...>");
+        exit(2);
+    }
+
+    let addr = args[0].parse::<SocketAddr>()
+        .or_else(|_| args[0].parse::<IpAddr>().map(|ip| SocketAddr::new(ip, server::config::DEFAULT_PORT)))
+        .unwrap_or_else(|_| {
+            eprintln!("{} isn't an address", args[0]);
+            exit(2);
+        });
+    let command = args[1..].join(" ");
+    let password = std::env::var("RCON_PASSWORD").unwrap_or_else(|_| {
+        eprintln!("RCON_PASSWORD isn't set");
+        exit(2);
+    });
+
+    // Initial the global networking state. Note that this instance must be unique per-process.
+    let gns_global = GnsGlobal::get().expect("no global networking state");
+    let client = GnsSocket::<IsCreated>::new(gns_global.clone()).connect(addr.ip(), addr.port()).unwrap_or_else(|_| {
+        eprintln!("Couldn't connect to {addr}");
+        exit(1);
+    });
+
+    let send = |msg: &Message| {
+        let message = client.utils().allocate_message(client.connection(), k_nSteamNetworkingSend_Reliable, &msg.to_bytes());
+        client.send_messages(vec![message]);
+    };
+
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        gns_global.poll_callbacks();
+
+        let mut closed = None;
+        client.poll_event::<100>(|ev| match ev.info().state() {
+            ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_Connected => send(&Message::RconChallengeRequest),
+            ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ClosedByPeer |
+            ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ProblemDetectedLocally => closed = Some(ev.info().end_debug().to_string()),
+            _ => {}
+        });
+
+        if let Some(reason) = closed {
+            eprintln!("Connection closed: {reason}");
+            exit(1);
+        }
+
+        let mut output = None;
+        client.poll_messages::<100>(|message| match Message::from_bytes(message.payload()) {
+            Ok(Message::RconChallenge { nonce }) => send(&Message::RconCommand { response: rcon_response(nonce, &password, &command), command: command.clone() }),
+            Ok(Message::RconOutput { lines }) => output = Some(lines),
+            _ => {}
+        });
+
+        if let Some(lines) = output {
+            for line in lines {
+                println!("{line}");
+            }
+            client.close_connection(client.connection(), 0, "done", true);
+            return;
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    eprintln!("No answer from {addr}");
+    exit(1);
+}
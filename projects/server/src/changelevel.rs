@@ -0,0 +1,54 @@
+use raylib::prelude::*;
+use shared::player::{Player, PlayerState};
+use shared::rcon::BadAttempts;
+use shared::transport::ConnectionId;
+use std::collections::HashMap;
+use std::path::Path;
+use crate::conn_handler::ConnHandler;
+use crate::entity::GameEntities;
+
+// Set by a trigger along with the next map, so the players come along
+pub struct Transition {
+    pub landmark: Option<String>,
+}
+
+// Everything the server for the next map takes over from the previous one
+pub struct Carryover {
+    pub connections: ConnHandler,
+    pub landmark: Option<String>,
+    pub players: HashMap<ConnectionId, CarriedPlayer>,
+    pub rcon_attempts: BadAttempts, // Server wide bad rcon passwords, counted from tick 0 of the next map
+}
+
+pub struct CarriedPlayer {
+    pub offset: Option<Vector3>, // From the landmark, if the previous map had it
+    pub velocity: Vector3,
+}
+
+impl CarriedPlayer {
+    // Where and how the player starts on the next map. Without the landmark in both maps they're back at the spawn point.
+    pub fn respawn(&self, landmark: Option<Vector3>, spawn_origin: Vector3) -> PlayerState {
+        let origin = match (landmark, self.offset) {
+            (Some(landmark), Some(offset)) => landmark + offset,
+            _ => spawn_origin,
+        };
+
+        let mut state = Player::new(origin).state();
+        state.velocity = self.velocity;
+        return state;
+    }
+}
+
+pub fn landmark_origin(entities: &GameEntities, name: &str) -> Option<Vector3> {
+    return entities.of_class("info_landmark").find(|e| e.targetname.as_deref() == Some(name)).map(|e| e.origin);
+}
+
+// Triggers name maps the way Quake does, without a path or extension, and those are looked up next to
+// the current map. Anything ending in .bsp is taken as a path like the `map` command takes.
+pub fn resolve_map(current_map: &str, name: &str) -> String {
+    if name.ends_with(".bsp") {
+        return name.to_string();
+    }
+
+    return Path::new(current_map).with_file_name(format!("{name}.bsp")).to_string_lossy().into_owned();
+}
@@ -0,0 +1,94 @@
+use enumset::EnumSet;
+use shared::console::*;
+use shared::transport::ConnectionId;
+use crate::config::check_map;
+use crate::Server;
+use std::path::Path;
+
+// Archived cvars are loaded from here on startup and written back whenever one of them changes
+pub const ARCHIVE_PATH: &str = "server_archive.cfg";
+
+// Registers the server's cvars and every command `Server::run_command` handles
+pub fn register(console: &mut Console) {
+    console.register_cvar("hostname", CvarValue::Str("Unnamed server".to_string()), CvarFlag::Archive | CvarFlag::ServerInfo, "Name shown to players");
+    console.register_cvar("rcon_password", CvarValue::Str(String::new()), EnumSet::empty(), "Password for the remote console, empty turns it off");
+
+    console.register_command("status", "status", "List the map and everyone connected", 0);
+    console.register_command("say", "say <text>", "Send a chat message to everyone as the server", 1);
+    console.register_command("kick", "kick <slot or name>", "Disconnect a player", 1);
+    console.register_command("map", "map <bsp>", "Switch to another map", 1);
+    console.register_command("changelevel", "changelevel <bsp>", "Same as map, everyone stays connected either way", 1);
+}
+
+// Runs the startup script before the first map loads, which is the only way a server without a terminal
+// gets cvars set. Server commands need a map running, so they're refused.
+pub fn exec_startup(console: &mut Console, path: &str) {
+    if !Path::new(path).is_file() {
+        return;
+    }
+
+    console.exec(path, &mut |console, cmd| console.print(format!("{} can't be run before a map is loaded", cmd.name)));
+}
+
+// Writes the archived cvars out if they've changed from `saved`, what was last written. A server is
+// usually stopped by killing it, so there's no waiting until it exits.
+pub fn save_archive(console: &Console, saved: &mut String) {
+    let archive = console.archive();
+    if archive == *saved {
+        return;
+    }
+
+    if let Err(err) = std::fs::write(ARCHIVE_PATH, &archive) {
+        println!("WARNING: couldn't save {ARCHIVE_PATH}: {err}");
+    }
+    *saved = archive;
+}
+
+impl Server<'_> {
+    pub fn run_command(&mut self, console: &mut Console, cmd: &Command) {
+        match cmd.name.as_str() {
+            "status" => self.status(console),
+            "say" => self.broadcast_chat(None, &cmd.rest(0)),
+            "kick" => {
+                let Some(conn) = self.find_player(&cmd.args[0]) else {
+                    console.print(format!("No player {}", cmd.args[0]));
+                    return;
+                };
+
+                self.remove_client(conn, "was kicked");
+                self.closing.push((conn, "kicked by server operator".to_string()));
+            }
+            "map" | "changelevel" => match check_map(&cmd.args[0]) {
+                Ok(()) => self.next_map = Some(cmd.args[0].clone()),
+                Err(err) => console.print(err),
+            },
+            _ => console.print(format!("{} isn't a server command", cmd.name)),
+        }
+    }
+
+    fn status(&self, console: &mut Console) {
+        let info = self.conn_handler.info();
+        console.print(format!("hostname: {}", console.get_str("hostname").unwrap_or_default()));
+        console.print(format!("map:      {} at {} ticks/s, tick {}", info.map, info.tick_rate, self.tick));
+
+        let mut players: Vec<(u8, ConnectionId, &str, u64)> = self.conn_handler.spawned()
+            .filter_map(|(conn, c)| c.slot.map(|slot| (slot, conn, c.name.as_str(), c.id)))
+            .collect();
+        players.sort();
+
+        console.print(format!("players:  {} of {}, {} connecting", players.len(), info.max_players, self.conn_handler.len() - players.len()));
+        console.print("slot name                             id");
+        for (slot, _, name, id) in players {
+            console.print(format!("{slot:<4} {name:<32} {id:x}"));
+        }
+    }
+
+    // Players are found by slot number first, then by name
+    fn find_player(&self, which: &str) -> Option<ConnectionId> {
+        if let Ok(slot) = which.parse::<u8>() && let Some((conn, _)) = self.conn_handler.spawned().find(|(_, c)| c.slot == Some(slot)) {
+            return Some(conn);
+        }
+
+        return self.conn_handler.spawned().find(|(_, c)| c.name.eq_ignore_ascii_case(which)).map(|(conn, _)| conn);
+    }
+}
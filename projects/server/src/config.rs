@@ -0,0 +1,115 @@
+use shared::config::{parse_value, Config};
+use std::net::{IpAddr, Ipv4Addr};
+
+pub const DEFAULT_PORT: u16 = 27821;
+pub const DEFAULT_TICK_RATE: u32 = 20;
+pub const DEFAULT_MAX_PLAYERS: u8 = 16;
+pub const DEFAULT_LAG_HISTORY_MS: u32 = 1000;
+pub const DEFAULT_MAX_REWIND_MS: u32 = 500;
+
+// Read from here when no other config file is given
+pub const DEFAULT_CONFIG_PATH: &str = "server.cfg";
+
+// Console script run at startup, if there is one
+pub const DEFAULT_EXEC_PATH: &str = "autoexec.cfg";
+
+// Player slots beyond this would be more than snapshots and lag compensation are sized for
+const MAX_PLAYERS_LIMIT: u8 = 64;
+
+// Below this movement gets choppy for everyone, above it a tick is shorter than a single user command
+const TICK_RATE_RANGE: std::ops::RangeInclusive<u32> = 10..=60;
+
+// Rewinding further than this favors players with bad connections too much over everyone else
+const MAX_REWIND_LIMIT_MS: u32 = 1000;
+const LAG_HISTORY_LIMIT_MS: u32 = 5000;
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub bind: IpAddr, // 0.0.0.0 to accept connections on every interface
+    pub port: u16,
+    pub map: String,
+    pub max_players: u8,
+    pub tick_rate: u32,
+    pub lag_history_ms: u32, // How far back hitscan lag compensation keeps player positions
+    pub max_rewind_ms: u32, // The most latency a shot is compensated for, older ones are clamped to this
+    pub fgd: String, // Entity definitions to check each map's entities against, none when empty
+    pub exec: String, // Console script for cvars like rcon_password, which have no setting here
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        return ServerConfig {
+            bind: Ipv4Addr::LOCALHOST.into(),
+            port: DEFAULT_PORT,
+            map: "assets/box.bsp".to_string(),
+            max_players: DEFAULT_MAX_PLAYERS,
+            tick_rate: DEFAULT_TICK_RATE,
+            lag_history_ms: DEFAULT_LAG_HISTORY_MS,
+            max_rewind_ms: DEFAULT_MAX_REWIND_MS,
+            fgd: String::new(),
+            exec: DEFAULT_EXEC_PATH.to_string(),
+        };
+    }
+}
+
+impl Config for ServerConfig {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "bind" => self.bind = parse_value(key, value)?,
+            "port" => self.port = parse_value(key, value)?,
+            "map" => self.map = value.to_string(),
+            "max_players" => self.max_players = parse_value(key, value)?,
+            "tick_rate" => self.tick_rate = parse_value(key, value)?,
+            "lag_history_ms" => self.lag_history_ms = parse_value(key, value)?,
+            "max_rewind_ms" => self.max_rewind_ms = parse_value(key, value)?,
+            "fgd" => self.fgd = value.to_string(),
+            "exec" => self.exec = value.to_string(),
+            _ => return Err(format!("unknown setting `{key}`")),
+        }
+
+        return Ok(());
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.port == 0 {
+            return Err("port can't be 0".to_string());
+        }
+
+        if !(1..=MAX_PLAYERS_LIMIT).contains(&self.max_players) {
+            return Err(format!("max_players must be between 1 and {MAX_PLAYERS_LIMIT}, got {}", self.max_players));
+        }
+
+        if !TICK_RATE_RANGE.contains(&self.tick_rate) {
+            return Err(format!("tick_rate must be between {} and {}, got {}", TICK_RATE_RANGE.start(), TICK_RATE_RANGE.end(), self.tick_rate));
+        }
+
+        if self.max_rewind_ms > MAX_REWIND_LIMIT_MS {
+            return Err(format!("max_rewind_ms can be at most {MAX_REWIND_LIMIT_MS}, got {}", self.max_rewind_ms));
+        }
+
+        if !(self.max_rewind_ms..=LAG_HISTORY_LIMIT_MS).contains(&self.lag_history_ms) {
+            return Err(format!("lag_history_ms must be between max_rewind_ms ({}) and {LAG_HISTORY_LIMIT_MS}, got {}", self.max_rewind_ms, self.lag_history_ms));
+        }
+
+        check_map(&self.map)?;
+        return Ok(());
+    }
+}
+
+impl ServerConfig {
+    // Milliseconds to whole ticks, rounding up so a setting never covers less time than it says
+    pub fn ms_to_ticks(&self, ms: u32) -> u32 {
+        return (ms * self.tick_rate).div_ceil(1000);
+    }
+}
+
+// Maps are looked up next to the executable, same as `load_bsp` does
+pub fn check_map(map: &str) -> Result<(), String> {
+    let exe_path = std::env::current_exe().map_err(|err| err.to_string())?;
+    let map_path = exe_path.parent().map(|dir| dir.join(map)).unwrap_or(map.into());
+    if !map_path.is_file() {
+        return Err(format!("map {} not found", map_path.display()));
+    }
+
+    return Ok(());
+}
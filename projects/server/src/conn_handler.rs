@@ -1,32 +1,139 @@
-use shared::message::Message;
+use shared::transport::ConnectionId;
+use shared::chat;
+use shared::message::*;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use crate::err::Error;
 
-pub struct ConnHandler {
-    connections: Vec<Connection>,
+#[derive(Clone)]
+pub struct ServerInfo {
+    pub map: String,
+    pub tick_rate: u32,
+    pub max_players: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConnState {
+    Connecting,  // Accepted by the transport, not yet fully connected
+    Handshaking, // Connected, waiting on the client's hello
+    Spawned,     // Hello accepted and given a player slot
+    Disconnected,
+}
+
+pub struct Connection {
+    pub state: ConnState,
+    pub slot: Option<u8>,
+    pub id: u64,
+    pub name: String,
 }
 
-struct Connection {
-    id: u64,
-    name: String
+// The one registry of every connection the server knows about, keyed by transport connection
+pub struct ConnHandler {
+    info: ServerInfo,
+    connections: HashMap<ConnectionId, Connection>,
+    banned_ids: HashSet<u64>,
 }
 
 impl ConnHandler {
-    pub fn new() -> ConnHandler {
-        return ConnHandler { connections: vec!() }
+    pub fn new(info: ServerInfo) -> ConnHandler {
+        return ConnHandler { info, connections: HashMap::new(), banned_ids: HashSet::new() }
     }
 
-    pub fn on_connected(&mut self, msg: Message) -> Result<u64, Error> {
-        let Message::HelloFromClient(id, s) = msg else {
-            return Err(Error::InternalError);
-        };
+    pub fn info(&self) -> &ServerInfo {
+        return &self.info;
+    }
 
-        self.connections.push(Connection { id, name: s });
-        return Ok(id);
+    pub fn on_connecting(&mut self, conn: ConnectionId) {
+        self.connections.insert(conn, Connection { state: ConnState::Connecting, slot: None, id: 0, name: String::new() });
     }
 
-    pub fn on_disconnected(&mut self, id: u64) -> Result<(), Error> {
-        let pos = self.connections.iter().position(|c| c.id == id).ok_or(Error::InternalError)?;
-        self.connections.remove(pos);
+    pub fn on_connected(&mut self, conn: ConnectionId) -> Result<(), Error> {
+        let connection = self.connections.get_mut(&conn).ok_or(Error::InternalError)?;
+        connection.state = ConnState::Handshaking;
         return Ok(());
     }
+
+    // Validates a client's hello, and if it's allowed in, assigns it a slot and returns the reply to send
+    pub fn on_hello(&mut self, conn: ConnectionId, msg: Message) -> Result<Message, Error> {
+        let Message::HelloFromClient { protocol_version, build_hash, id, name } = msg else {
+            return Err(Error::BadRequest);
+        };
+
+        let state = self.state(conn).ok_or(Error::InternalError)?;
+        if state != ConnState::Handshaking {
+            return Err(Error::BadRequest);
+        }
+
+        if protocol_version != PROTOCOL_VERSION {
+            return Err(Error::Rejected(RejectReason::VersionMismatch { server_protocol: PROTOCOL_VERSION, client_protocol: protocol_version }));
+        }
+
+        if build_hash != BUILD_HASH {
+            println!("Client {name} ({id}) is running build {build_hash:x}, server is {BUILD_HASH:x}");
+        }
+
+        if self.banned_ids.contains(&id) {
+            return Err(Error::Rejected(RejectReason::Banned { reason: "banned by server operator".to_string() }));
+        }
+
+        let name = chat::sanitize_name(&name);
+        let slot = (0..self.info.max_players).find(|slot| !self.connections.values().any(|c| c.slot == Some(*slot)))
+            .ok_or(Error::Rejected(RejectReason::ServerFull { max_players: self.info.max_players }))?;
+
+        println!("Client {name} ({id}) joined in slot {slot}");
+
+        let connection = self.connections.get_mut(&conn).ok_or(Error::InternalError)?;
+        connection.state = ConnState::Spawned;
+        connection.slot = Some(slot);
+        connection.id = id;
+        connection.name = name;
+
+        return Ok(Message::HelloFromServer { map: self.info.map.clone(), tick_rate: self.info.tick_rate, slot });
+    }
+
+    // Moves to another map without dropping anyone. Everyone in the game gives up their slot and goes back to
+    // handshaking until they've loaded it, these are returned so they can be told.
+    pub fn change_map(&mut self, info: ServerInfo) -> Vec<ConnectionId> {
+        self.info = info;
+
+        let mut spawned = vec!();
+        for (conn, connection) in self.connections.iter_mut().filter(|(_, c)| c.state == ConnState::Spawned) {
+            connection.state = ConnState::Handshaking;
+            connection.slot = None;
+            spawned.push(*conn);
+        }
+
+        return spawned;
+    }
+
+    // Removes the connection, handing back its last known details in the disconnected state
+    pub fn on_disconnected(&mut self, conn: ConnectionId) -> Result<Connection, Error> {
+        let mut connection = self.connections.remove(&conn).ok_or(Error::InternalError)?;
+        connection.state = ConnState::Disconnected;
+        return Ok(connection);
+    }
+
+    pub fn ban(&mut self, id: u64) {
+        self.banned_ids.insert(id);
+    }
+
+    pub fn get(&self, conn: ConnectionId) -> Option<&Connection> {
+        return self.connections.get(&conn);
+    }
+
+    pub fn state(&self, conn: ConnectionId) -> Option<ConnState> {
+        return self.connections.get(&conn).map(|c| c.state);
+    }
+
+    pub fn slot(&self, conn: ConnectionId) -> Option<u8> {
+        return self.connections.get(&conn).and_then(|c| c.slot);
+    }
+
+    pub fn spawned(&self) -> impl Iterator<Item = (ConnectionId, &Connection)> {
+        return self.connections.iter().filter(|(_, c)| c.state == ConnState::Spawned).map(|(conn, c)| (*conn, c));
+    }
+
+    pub fn len(&self) -> usize {
+        return self.connections.len();
+    }
 }
@@ -0,0 +1,412 @@
+use raylib::prelude::*;
+use shared::bsp::*;
+use shared::bsp_entity::{KeyError, optional};
+use shared::player::{HITBOX_MAXS, HITBOX_MINS};
+use shared::snapshot::EntityState;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use crate::snapshot::EntityTable;
+
+// Targets firing targets that fire the first one again would otherwise never stop
+const MAX_USE_DEPTH: u32 = 32;
+
+// Keys that go into GameEntity's own fields rather than `keys`
+const FIELD_KEYS: [&str; 12] = ["classname", "targetname", "target", "origin", "angle", "spawnflags", "message", "map", "landmark", "wait", "delay", "model"];
+
+// Who set something off, passed along through touches and uses so whatever fires last knows who started it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Activator {
+    World,
+    Player(u8), // By slot
+    Entity(u16),
+}
+
+// What a think function wants done next
+pub enum Think {
+    Again(u32), // Think again at this tick
+    Idle,
+    Remove,
+}
+
+// What entities want from the rest of the server, handled once the tick that raised them is over
+#[derive(Clone, PartialEq, Debug)]
+pub enum GameEvent {
+    ChangeLevel { map: String, landmark: Option<String> },
+    Print(String),
+}
+
+pub type SpawnFn = fn(entity: &mut GameEntity, ctx: &SpawnContext) -> Result<(), String>;
+pub type ThinkFn = fn(entities: &mut GameEntities, id: u16) -> Think;
+pub type TouchFn = fn(entities: &mut GameEntities, id: u16, other: Activator);
+pub type UseFn = fn(entities: &mut GameEntities, id: u16, activator: Activator);
+
+// What spawn functions get to look at besides the entity's own fields
+pub struct SpawnContext<'a> {
+    pub bsp: &'a Bsp,
+    pub map: &'a str,
+    pub tick_rate: u32,
+}
+
+// A gameplay entity. Like Quake's edicts every class shares the one set of fields, filled from whichever
+// keys the map gave it, and anything that isn't a field is left in `keys` for the spawn function.
+pub struct GameEntity {
+    pub id: u16,
+    pub classname: String,
+    pub targetname: Option<String>,
+    pub target: Option<String>,
+    pub origin: Vector3,
+    pub angle: f32, // Yaw in degrees, the way editors set it
+    pub spawnflags: u32,
+    pub bounds: Option<(Vector3, Vector3)>, // World space box of the entity's brush model
+    pub message: Option<String>,
+    pub map: Option<String>,
+    pub landmark: Option<String>,
+    pub wait: f32, // Seconds before a trigger fires again, negative for never
+    pub delay: f32, // Seconds between being used and firing targets
+    pub keys: HashMap<String, String>,
+
+    pub next_think: Option<u32>,
+    pub think_fn: Option<ThinkFn>,
+    pub touch_fn: Option<TouchFn>,
+    pub use_fn: Option<UseFn>,
+    pub activator: Activator, // Who last used it, for firing targets after a delay
+    pub rearm_tick: u32, // Touches before this tick are ignored
+}
+
+impl GameEntity {
+    pub fn new(id: u16, classname: &str) -> GameEntity {
+        return GameEntity {
+            id,
+            classname: classname.to_string(),
+            targetname: None,
+            target: None,
+            origin: Vector3::ZERO,
+            angle: 0f32,
+            spawnflags: 0,
+            bounds: None,
+            message: None,
+            map: None,
+            landmark: None,
+            wait: 0f32,
+            delay: 0f32,
+            keys: HashMap::new(),
+            next_think: None,
+            think_fn: None,
+            touch_fn: None,
+            use_fn: None,
+            activator: Activator::World,
+            rearm_tick: 0,
+        };
+    }
+
+    // Fills the shared fields from the map's keys, leaving the rest in `keys`
+    fn read_keys(&mut self, keys: &Entity, bsp: &Bsp) -> Result<(), String> {
+        let text = |key: &str| keys.get_str(key).ok().map(str::to_string);
+        self.targetname = text("targetname");
+        self.target = text("target");
+        self.message = text("message");
+        self.map = text("map");
+        self.landmark = text("landmark");
+
+        self.read_numbers(keys).map_err(|err| err.to_string())?;
+
+        if let Ok(value) = keys.get_str("model") && value.starts_with('*') {
+            let model = keys.get_model(bsp).ok_or_else(|| format!("no brush model {value}"))?;
+            let (mins, maxs) = model.bounds();
+            let (mins, maxs) = (to_wld(mins), to_wld(maxs));
+            self.bounds = Some((mins.min(maxs), mins.max(maxs)));
+        }
+
+        for (key, value) in &keys.pairs {
+            if !FIELD_KEYS.contains(&key.as_str()) {
+                self.keys.insert(key.clone(), value.clone());
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn read_numbers(&mut self, keys: &Entity) -> Result<(), KeyError> {
+        self.origin = optional(keys.get_vec3("origin"))?.unwrap_or(Vector3::ZERO);
+        self.angle = optional(keys.get_f32("angle"))?.unwrap_or_default();
+        self.spawnflags = keys.get_spawnflags()?;
+        self.wait = optional(keys.get_f32("wait"))?.unwrap_or_default();
+        self.delay = optional(keys.get_f32("delay"))?.unwrap_or_default();
+        return Ok(());
+    }
+
+    pub fn touches(&self, player_pos: Vector3) -> bool {
+        let Some((bounds_mins, bounds_maxs)) = self.bounds else { return false; };
+        let mins = player_pos + HITBOX_MINS;
+        let maxs = player_pos + HITBOX_MAXS;
+        return mins.x < bounds_maxs.x && maxs.x > bounds_mins.x
+            && mins.y < bounds_maxs.y && maxs.y > bounds_mins.y
+            && mins.z < bounds_maxs.z && maxs.z > bounds_mins.z;
+    }
+}
+
+// Which spawn function makes each classname. Entities with a classname that isn't in here aren't spawned.
+pub struct Registry {
+    classes: HashMap<&'static str, SpawnFn>,
+}
+
+impl Registry {
+    // Comes with every class the game knows, more can be registered on top
+    pub fn new() -> Registry {
+        let mut registry = Registry { classes: HashMap::new() };
+        crate::triggers::register(&mut registry);
+        return registry;
+    }
+
+    pub fn register(&mut self, classname: &'static str, spawn: SpawnFn) {
+        self.classes.insert(classname, spawn);
+    }
+
+    pub fn get(&self, classname: &str) -> Option<SpawnFn> {
+        return self.classes.get(classname).copied();
+    }
+}
+
+// Hands out the lowest free id, so the same map with the same spawns and removals always ends up with the same ids
+struct IdAllocator {
+    free: BTreeSet<u16>,
+    next: u16,
+}
+
+impl IdAllocator {
+    fn alloc(&mut self) -> Option<u16> {
+        if let Some(id) = self.free.pop_first() {
+            return Some(id);
+        }
+
+        let id = self.next;
+        self.next = self.next.checked_add(1)?;
+        return Some(id);
+    }
+
+    fn free(&mut self, id: u16) {
+        self.free.insert(id);
+    }
+}
+
+// Every entity on the map, in id order so callbacks run the same way every time. The ones clients see
+// also have their networked state in `table` under the same id. Ids below the first one handed out
+// belong to players, which are only in the table.
+pub struct GameEntities {
+    entities: BTreeMap<u16, GameEntity>,
+    table: EntityTable,
+    ids: IdAllocator,
+    events: Vec<GameEvent>,
+    tick: u32,
+    tick_rate: u32,
+    use_depth: u32,
+}
+
+impl GameEntities {
+    pub fn new(tick_rate: u32, first_id: u16) -> GameEntities {
+        let ids = IdAllocator { free: BTreeSet::new(), next: first_id };
+        return GameEntities { entities: BTreeMap::new(), table: EntityTable::new(), ids, events: vec!(), tick: 0, tick_rate, use_depth: 0 };
+    }
+
+    // Spawns everything in the entity lump that has a spawn function, in lump order. Entities that fail to
+    // spawn are left out with a warning, so one bad key doesn't stop the map from loading.
+    pub fn spawn_all(bsp: &Bsp, map: &str, tick_rate: u32, first_id: u16, registry: &Registry) -> GameEntities {
+        let mut entities = GameEntities::new(tick_rate, first_id);
+        let ctx = SpawnContext { bsp, map, tick_rate };
+        let mut unknown: BTreeMap<&str, u32> = BTreeMap::new();
+
+        for keys in &bsp.entities {
+            let classname = keys.classname();
+            let Some(spawn) = registry.get(classname) else {
+                *unknown.entry(classname).or_default() += 1;
+                continue;
+            };
+
+            let Some(id) = entities.ids.alloc() else {
+                println!("WARNING: out of entity ids, {classname} and everything after it left out");
+                break;
+            };
+
+            let mut entity = GameEntity::new(id, classname);
+            let spawned = entity.read_keys(keys, bsp).and_then(|_| spawn(&mut entity, &ctx));
+
+            match spawned {
+                Ok(()) => { entities.entities.insert(id, entity); }
+                Err(err) => {
+                    println!("WARNING: {classname} not spawned, {err}: {:?}", keys);
+                    entities.ids.free(id);
+                }
+            }
+        }
+
+        if !unknown.is_empty() {
+            let list: Vec<String> = unknown.iter().map(|(classname, count)| format!("{classname} x{count}")).collect();
+            println!("Entities without a spawn function: {}", list.join(", "));
+        }
+
+        return entities;
+    }
+
+    // Adds an entity while the game is running, the caller fills in its fields and callbacks
+    pub fn spawn(&mut self, classname: &str) -> Option<u16> {
+        let id = self.ids.alloc()?;
+        self.entities.insert(id, GameEntity::new(id, classname));
+        return Some(id);
+    }
+
+    pub fn remove(&mut self, id: u16) -> Option<GameEntity> {
+        let entity = self.entities.remove(&id)?;
+        self.table.remove(id);
+        self.ids.free(id);
+        return Some(entity);
+    }
+
+    // Makes the entity show up in snapshots, or updates what they show of it
+    pub fn set_networked(&mut self, id: u16, mut state: EntityState) {
+        if self.entities.contains_key(&id) {
+            state.id = id;
+            self.table.insert(state);
+        }
+    }
+
+    pub fn table(&self) -> &EntityTable {
+        return &self.table;
+    }
+
+    pub fn table_mut(&mut self) -> &mut EntityTable {
+        return &mut self.table;
+    }
+
+    pub fn get(&self, id: u16) -> Option<&GameEntity> {
+        return self.entities.get(&id);
+    }
+
+    pub fn get_mut(&mut self, id: u16) -> Option<&mut GameEntity> {
+        return self.entities.get_mut(&id);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &GameEntity> {
+        return self.entities.values();
+    }
+
+    pub fn of_class<'a>(&'a self, classname: &'a str) -> impl Iterator<Item = &'a GameEntity> {
+        return self.entities.values().filter(move |e| e.classname == classname);
+    }
+
+    pub fn find_target(&self, targetname: &str) -> Vec<u16> {
+        return self.entities.values().filter(|e| e.targetname.as_deref() == Some(targetname)).map(|e| e.id).collect();
+    }
+
+    pub fn tick(&self) -> u32 {
+        return self.tick;
+    }
+
+    pub fn seconds_to_ticks(&self, seconds: f32) -> u32 {
+        return (seconds.max(0f32) * self.tick_rate as f32).round() as u32;
+    }
+
+    pub fn push_event(&mut self, event: GameEvent) {
+        self.events.push(event);
+    }
+
+    pub fn take_events(&mut self) -> Vec<GameEvent> {
+        return std::mem::take(&mut self.events);
+    }
+
+    pub fn set_think(&mut self, id: u16, tick: u32, think: ThinkFn) {
+        if let Some(entity) = self.entities.get_mut(&id) {
+            entity.next_think = Some(tick);
+            entity.think_fn = Some(think);
+        }
+    }
+
+    // Runs every think that's due by this tick, in id order
+    pub fn run_thinks(&mut self, tick: u32) {
+        self.tick = tick;
+
+        let due: Vec<(u16, ThinkFn)> = self.entities.values()
+            .filter(|e| e.next_think.is_some_and(|at| at <= tick))
+            .filter_map(|e| e.think_fn.map(|think| (e.id, think)))
+            .collect();
+
+        for (id, think) in due {
+            if !self.entities.contains_key(&id) {
+                continue;
+            }
+
+            match think(self, id) {
+                Think::Again(at) => self.set_think(id, at, think),
+                Think::Idle => if let Some(entity) = self.entities.get_mut(&id) { entity.next_think = None; },
+                Think::Remove => { self.remove(id); }
+            }
+        }
+    }
+
+    // Touches every entity the player's box is in
+    pub fn touch_player(&mut self, slot: u8, pos: Vector3) {
+        let touched: Vec<(u16, TouchFn)> = self.entities.values()
+            .filter(|e| e.touches(pos))
+            .filter_map(|e| e.touch_fn.map(|touch| (e.id, touch)))
+            .collect();
+
+        for (id, touch) in touched {
+            if self.entities.contains_key(&id) {
+                touch(self, id, Activator::Player(slot));
+            }
+        }
+    }
+
+    // Uses every entity with this targetname
+    pub fn fire(&mut self, target: &str, activator: Activator) {
+        if self.use_depth >= MAX_USE_DEPTH {
+            println!("WARNING: {target} fired more than {MAX_USE_DEPTH} deep, stopping there");
+            return;
+        }
+
+        self.use_depth += 1;
+        for id in self.find_target(target) {
+            if let Some(use_fn) = self.entities.get(&id).and_then(|e| e.use_fn) {
+                use_fn(self, id, activator);
+            }
+        }
+        self.use_depth -= 1;
+    }
+
+    // What an entity does once it's set off: after its delay, shows its message and fires its target
+    pub fn use_targets(&mut self, id: u16, activator: Activator) {
+        let Some(entity) = self.entities.get(&id) else { return; };
+        if entity.delay <= 0f32 {
+            self.fire_targets(id, activator);
+            return;
+        }
+
+        // Delays go through a stand-in like Quake's, so the entity's own think is left alone
+        let (message, target) = (entity.message.clone(), entity.target.clone());
+        let at = self.tick + self.seconds_to_ticks(entity.delay);
+        let Some(delayed) = self.spawn("delayed_use") else { return; };
+        if let Some(entity) = self.entities.get_mut(&delayed) {
+            entity.message = message;
+            entity.target = target;
+            entity.activator = activator;
+        }
+
+        self.set_think(delayed, at, |entities, id| {
+            let activator = entities.get(id).map(|e| e.activator).unwrap_or(Activator::World);
+            entities.fire_targets(id, activator);
+            return Think::Remove;
+        });
+    }
+
+    fn fire_targets(&mut self, id: u16, activator: Activator) {
+        let Some(entity) = self.entities.get(&id) else { return; };
+        let message = entity.message.clone();
+        let target = entity.target.clone();
+
+        if let Some(message) = message {
+            self.events.push(GameEvent::Print(message));
+        }
+
+        if let Some(target) = target {
+            self.fire(&target, activator);
+        }
+    }
+}
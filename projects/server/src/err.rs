@@ -1,7 +1,9 @@
+use shared::message::RejectReason;
 
 #[repr(u8)]
 pub enum Error {
     None = 0,
     BadRequest,
     InternalError,
+    Rejected(RejectReason),
 }
@@ -0,0 +1,200 @@
+use raylib::prelude::*;
+use shared::bsp_query::*;
+use shared::message::*;
+use shared::player::{view_dir, EYE_HEIGHT, HITBOX_MAXS, HITBOX_MINS};
+use shared::user_cmd::UserCmd;
+use std::collections::VecDeque;
+use crate::snapshot::EntityTable;
+
+struct TickRecord {
+    tick: u32,
+    positions: Vec<(u16, [i32; 3])>,
+}
+
+#[derive(PartialEq, Debug)]
+pub struct HitscanResult {
+    pub position: Vector3,
+    pub entity: Option<u16>, // None when the world was hit first
+}
+
+// Where every player was over the last few ticks, so shots can be checked against what the shooter saw
+pub struct LagCompensation {
+    history: VecDeque<TickRecord>,
+    history_ticks: usize,
+    max_rewind_ticks: u32, // Clients further behind than this get no more help, so high pings can't shoot people long after they got to cover
+}
+
+// Positions an entity had before being rewound, to put them back afterwards
+pub struct Rewind {
+    saved: Vec<(u16, [i32; 3])>,
+}
+
+impl LagCompensation {
+    pub fn new(history_ticks: usize, max_rewind_ticks: u32) -> LagCompensation {
+        return LagCompensation { history: VecDeque::with_capacity(history_ticks), history_ticks: history_ticks.max(1), max_rewind_ticks };
+    }
+
+    pub fn record(&mut self, tick: u32, players: impl Iterator<Item = u16>, table: &EntityTable) {
+        if self.history.len() >= self.history_ticks {
+            self.history.pop_front();
+        }
+
+        let positions = players.filter_map(|id| table.get(id).map(|e| (id, e.origin))).collect();
+        self.history.push_back(TickRecord { tick, positions });
+    }
+
+    fn get(&self, tick: u32) -> Option<&TickRecord> {
+        return self.history.iter().find(|r| r.tick == tick);
+    }
+
+    // Moves every recorded player except the shooter back to where they were at the given view time.
+    // Players that didn't exist yet at that time are left where they are.
+    pub fn rewind(&self, table: &mut EntityTable, current_tick: u32, view_tick: u32, view_lerp: u8, shooter: u16) -> Rewind {
+        let mut saved = Vec::new();
+
+        // Ticks are recorded once they've run, so while one runs the newest record is the tick before. Anything
+        // later than that is clamped to it rather than using positions from halfway through this tick.
+        let Some(latest) = self.history.back().map(|r| r.tick) else { return Rewind { saved }; };
+        let oldest = current_tick.saturating_sub(self.max_rewind_ticks).min(latest);
+        let (view_tick, frac) = if view_tick < oldest {
+            (oldest, 0f32)
+        } else if view_tick >= latest {
+            (latest, 0f32)
+        } else {
+            (view_tick, view_lerp as f32 / 255f32)
+        };
+
+        let Some(from) = self.get(view_tick) else { return Rewind { saved }; };
+        let to = self.get(view_tick + 1);
+
+        for &(id, from_origin) in &from.positions {
+            if id == shooter {
+                continue;
+            }
+
+            let Some(entity) = table.get_mut(id) else { continue; };
+            let from_pos = dequantize_pos(from_origin);
+            let to_pos = to.and_then(|to| to.positions.iter().find(|(to_id, _)| *to_id == id)).map(|(_, o)| dequantize_pos(*o)).unwrap_or(from_pos);
+
+            saved.push((id, entity.origin));
+            entity.origin = quantize_pos(from_pos + (to_pos - from_pos) * frac);
+        }
+
+        return Rewind { saved };
+    }
+
+    pub fn restore(&self, table: &mut EntityTable, rewind: Rewind) {
+        for (id, origin) in rewind.saved {
+            if let Some(entity) = table.get_mut(id) {
+                entity.origin = origin;
+            }
+        }
+    }
+
+    // Traces a shot from the shooter's eyes against the world and against every player's hitbox as the shooter saw them
+    pub fn hitscan<'a>(&self, world: &'a impl BspQuery<'a>, table: &mut EntityTable, current_tick: u32, cmd: &UserCmd, shooter: u16, range: f32) -> Option<HitscanResult> {
+        let shooter_state = table.get(shooter)?;
+        let origin = dequantize_pos(shooter_state.origin) + Vector3::Y * EYE_HEIGHT;
+        let dir = view_dir(cmd.yaw, cmd.pitch);
+        let world_dist = ray_intersect(world, origin, dir, range, *DPASS).map(|hit| (hit.position - origin).length()).unwrap_or(range);
+
+        let rewind = self.rewind(table, current_tick, cmd.view_tick, cmd.view_lerp, shooter);
+
+        // Only players are lag compensated and have hitboxes, which are exactly the ones in the newest record
+        let players = self.history.back().map(|r| r.positions.as_slice()).unwrap_or(&[]);
+
+        let mut closest = world_dist;
+        let mut entity = None;
+        for target in players.iter().filter(|(id, _)| *id != shooter).filter_map(|(id, _)| table.get(*id)) {
+            let pos = dequantize_pos(target.origin);
+            if let Some(dist) = ray_box_intersect(origin, dir, closest, pos + HITBOX_MINS, pos + HITBOX_MAXS) {
+                closest = dist;
+                entity = Some(target.id);
+            }
+        }
+
+        self.restore(table, rewind);
+        return Some(HitscanResult { position: origin + dir * closest, entity });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::snapshot::EntityState;
+
+    const SHOOTER: u16 = 1;
+    const TARGET: u16 = 2;
+
+    fn at(id: u16, pos: Vector3) -> EntityState {
+        return EntityState { id, origin: quantize_pos(pos), ..Default::default() };
+    }
+
+    // The shooter stands still at the origin while the target crosses in front of it, 10 units along x a
+    // tick, starting right in its line of fire at tick 2. Ticks 1 to 5 have been recorded.
+    fn crossing_target() -> (LagCompensation, EntityTable) {
+        let mut lag_comp = LagCompensation::new(8, 4);
+        let mut table = EntityTable::new();
+        table.insert(at(SHOOTER, Vector3::ZERO));
+
+        for tick in 1..=5 {
+            table.insert(at(TARGET, Vector3::new(tick as f32 * 10f32 - 20f32, 0f32, 100f32)));
+            lag_comp.record(tick, [SHOOTER, TARGET].into_iter(), &table);
+        }
+
+        return (lag_comp, table);
+    }
+
+    fn target_x(table: &EntityTable) -> f32 {
+        return dequantize_pos(table.get(TARGET).unwrap().origin).x;
+    }
+
+    #[test]
+    fn rewinds_between_ticks_and_restores() {
+        let (lag_comp, mut table) = crossing_target();
+
+        // Halfway from tick 3 to tick 4
+        let rewind = lag_comp.rewind(&mut table, 6, 3, 128, SHOOTER);
+        assert!((target_x(&table) - 15f32).abs() < 0.1f32, "{}", target_x(&table));
+        assert_eq!(table.get(SHOOTER).unwrap().origin, quantize_pos(Vector3::ZERO));
+
+        lag_comp.restore(&mut table, rewind);
+        assert_eq!(target_x(&table), 30f32);
+    }
+
+    #[test]
+    fn rewinds_are_clamped_to_the_records() {
+        let (lag_comp, mut table) = crossing_target();
+
+        // Further back than the 4 ticks anyone gets
+        let rewind = lag_comp.rewind(&mut table, 6, 0, 200, SHOOTER);
+        assert_eq!(target_x(&table), 0f32);
+        lag_comp.restore(&mut table, rewind);
+
+        // Tick 6 is still running, so where the target has got to in it isn't recorded yet
+        table.insert(at(TARGET, Vector3::new(40f32, 0f32, 100f32)));
+        let rewind = lag_comp.rewind(&mut table, 6, 6, 200, SHOOTER);
+        assert_eq!(target_x(&table), 30f32);
+        lag_comp.restore(&mut table, rewind);
+        assert_eq!(target_x(&table), 40f32);
+    }
+
+    #[test]
+    fn hitscan_uses_what_the_shooter_saw() {
+        let (lag_comp, mut table) = crossing_target();
+        let world = FloorQuery::new(vec!());
+        let mut cmd = UserCmd::new(0);
+
+        // Straight ahead, where the target was at tick 2
+        cmd.view_tick = 2;
+        let hit = lag_comp.hitscan(&world, &mut table, 6, &cmd, SHOOTER, 1000f32).unwrap();
+        assert_eq!(hit.entity, Some(TARGET));
+        assert!((hit.position.z - (100f32 + HITBOX_MINS.z)).abs() < 0.1f32, "{:?}", hit.position);
+        assert_eq!(target_x(&table), 30f32);
+
+        // It has moved out of the way since
+        cmd.view_tick = 5;
+        let miss = lag_comp.hitscan(&world, &mut table, 6, &cmd, SHOOTER, 1000f32).unwrap();
+        assert_eq!(miss.entity, None);
+    }
+}
@@ -0,0 +1,549 @@
+pub mod config;
+use config::ServerConfig;
+
+pub mod commands;
+
+pub mod changelevel;
+use changelevel::{landmark_origin, Carryover, CarriedPlayer, Transition};
+
+pub mod entity;
+use entity::{GameEntities, GameEvent, Registry, Think};
+
+pub mod triggers;
+
+pub mod conn_handler;
+use conn_handler::{ConnHandler, ConnState, ServerInfo};
+
+pub mod err;
+use err::Error;
+
+pub mod snapshot;
+use snapshot::{ClientSnapshots, EntityTable};
+
+pub mod rcon;
+use rcon::Rcon;
+
+pub mod lag_comp;
+use lag_comp::LagCompensation;
+
+pub mod tick;
+use tick::{TickBudget, TickClock};
+
+use enumset::EnumSet;
+use raylib::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use shared::message::*;
+use shared::bsp::*;
+use shared::chat::{self, ChatFlood, FloodLimit};
+use shared::console::Console;
+use shared::fgd::parse_fgd;
+use shared::bsp_query::{BspClipQuery, BspVisQuery};
+use shared::player::{Player, PlayerState};
+use shared::snapshot::*;
+use shared::transport::*;
+use shared::user_cmd::*;
+
+const HITSCAN_RANGE: f32 = 8192f32;
+
+// About half a second of input. Anything queued beyond this is the client running fast, so the oldest is dropped.
+const MAX_QUEUED_CMDS: usize = 32;
+
+// A few lines in quick succession are fine, a steady stream of more than one a second is not
+const CHAT_BURST: u32 = 4;
+const CHAT_MUTE_SECONDS: u32 = 10;
+
+// Everything the server knows about the world and its clients
+pub struct Server<'a> {
+    bsp: &'a Bsp,
+    clip: BspClipQuery<'a>,
+    world: BspVisQuery<'a>,
+    conn_handler: ConnHandler,
+    entities: GameEntities, // Spawned from the map and during the game, with the players' networked state
+    clients: HashMap<ConnectionId, ClientState>,
+    lag_comp: LagCompensation,
+    closing: Vec<(ConnectionId, String)>, // Closed on the next poll, once anything queued for them has gone out
+    chat: Vec<Message>, // Waiting to go out to everyone in the game
+    rcon: Rcon,
+    spawn_origin: Vector3,
+    next_map: Option<String>, // Set by the `map` and `changelevel` commands, switched to after the current poll
+    transition: Option<Transition>, // Set along with `next_map` when a trigger changed the map
+    carried: HashMap<ConnectionId, PlayerState>, // Players from the previous map, waiting to spawn here
+    tick_rate: u32,
+    tick: u32,
+}
+
+// Per connection game state, created once the client has spawned
+struct ClientState {
+    snapshots: ClientSnapshots,
+    player: Player,
+    cmd_queue: VecDeque<UserCmd>,
+    last_queued: Option<u32>,
+    last_processed: Option<u32>,
+    last_buttons: EnumSet<Button>,
+    cmd_budget: Duration, // How much command time the client may still run, topped up every tick
+    max_cmd_msec: u8,
+    chat_flood: ChatFlood,
+}
+
+impl ClientState {
+    fn new(spawn_origin: Vector3, tick_rate: u32) -> ClientState {
+        return ClientState {
+            snapshots: ClientSnapshots::new(),
+            player: Player::new(spawn_origin),
+            cmd_queue: VecDeque::with_capacity(MAX_QUEUED_CMDS),
+            last_queued: None,
+            last_processed: None,
+            last_buttons: EnumSet::empty(),
+            cmd_budget: Duration::ZERO,
+            // Anything longer could never fit in the budget and would hold up the queue for good
+            max_cmd_msec: (2000 / tick_rate.max(1)).clamp(1, u8::MAX as u32) as u8,
+            chat_flood: ChatFlood::new(FloodLimit { burst: CHAT_BURST, refill_ticks: tick_rate, mute_ticks: tick_rate * CHAT_MUTE_SECONDS }),
+        };
+    }
+
+    fn queue_cmds(&mut self, cmds: Vec<UserCmd>) {
+        // Clients resend their last few commands in every packet, only keep the new ones
+        let skip = cmds.len().saturating_sub(MAX_CMDS_PER_MESSAGE);
+        for mut cmd in cmds.into_iter().skip(skip) {
+            if self.last_queued.is_some_and(|last| cmd.sequence <= last) {
+                continue;
+            }
+
+            // A command that takes no time would cost nothing, letting a client run as many as it likes a tick
+            cmd.msec = cmd.msec.clamp(1, self.max_cmd_msec);
+
+            if self.cmd_queue.len() >= MAX_QUEUED_CMDS {
+                self.cmd_queue.pop_front();
+            }
+
+            self.last_queued = Some(cmd.sequence);
+            self.cmd_queue.push_back(cmd);
+        }
+    }
+}
+
+fn cmd_time(cmd: &UserCmd) -> Duration {
+    return Duration::from_millis(cmd.msec as u64);
+}
+
+// Runs the server on the given transport forever, taking console commands from `commands`
+pub fn run(transport: &mut dyn Transport, config: &ServerConfig, commands: Receiver<String>) {
+    let mut console = Console::new();
+    commands::register(&mut console);
+    // Saved cvars go first so the startup script can still override them
+    commands::exec_startup(&mut console, commands::ARCHIVE_PATH);
+    commands::exec_startup(&mut console, &config.exec);
+    for output in console.take_output() {
+        println!("{output}");
+    }
+
+    let mut config = config.clone();
+    let mut bsp = load_bsp(&config.map).unwrap_or_else(|err| {
+        eprintln!("Couldn't load {}: {err}", config.map);
+        std::process::exit(1);
+    });
+
+    let mut carryover = None;
+    loop {
+        // Only a map change gets us back here, and everyone connected comes along to the next one
+        let (next_bsp, next_carryover) = run_map(transport, &mut config, &bsp, &mut console, &commands, carryover);
+        bsp = next_bsp;
+        carryover = Some(next_carryover);
+    }
+}
+
+// Lists every entity in the map that doesn't match the definitions the mapper's editor used. Nothing is
+// stopped by it, entities still spawn or not the same way.
+fn check_entities(bsp: &Bsp, fgd: &str) {
+    let exe_path = std::env::current_exe().expect("Failed to get exe path");
+    let path = exe_path.parent().map(|dir| dir.join(fgd)).unwrap_or(fgd.into());
+    // Included files are found next to the one including them
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut include = |name: &str| std::fs::read_to_string(dir.join(name)).map_err(|err| err.to_string());
+    let schema = match std::fs::read_to_string(&path).map_err(|err| err.to_string()).and_then(|text| parse_fgd(&text, &mut include).map_err(|err| err.to_string())) {
+        Ok(schema) => schema,
+        Err(err) => {
+            println!("WARNING: can't check entities against {}: {err}", path.display());
+            return;
+        }
+    };
+
+    let issues = schema.check(&bsp.entities);
+    for issue in &issues {
+        println!("WARNING: {issue}");
+    }
+    println!("{} entity issues against {fgd}", issues.len());
+}
+
+// Runs the configured map until the console or a trigger asks for another one, handing back the next map
+// and what it takes over
+fn run_map(transport: &mut dyn Transport, config: &mut ServerConfig, bsp: &Bsp, console: &mut Console, commands: &Receiver<String>, carryover: Option<Carryover>) -> (Bsp, Carryover) {
+    if !config.fgd.is_empty() {
+        check_entities(bsp, &config.fgd);
+    }
+
+    let mut server = Server::new(bsp, config);
+    if let Some(carryover) = carryover {
+        server.take_carryover(carryover, transport);
+    }
+
+    let mut clock = TickClock::new(config.tick_rate);
+    let mut budget = TickBudget::new(clock.interval());
+
+    println!("Running {} at {} ticks/s, listening for connections...", config.map, config.tick_rate);
+
+    let mut archived = console.archive();
+    loop {
+        server.poll(transport);
+        server.run_rcon(console, transport);
+
+        for line in commands.try_iter() {
+            console.execute(&line, &mut |console, cmd| server.run_command(console, cmd));
+            for output in console.take_output() {
+                println!("{output}");
+            }
+        }
+        commands::save_archive(console, &mut archived);
+
+        // The next map is loaded before leaving this one, so a broken one doesn't take the server down
+        if let Some(map) = server.next_map.take() {
+            match load_bsp(&map) {
+                Ok(next_bsp) => {
+                    println!("Changing map to {map}");
+                    config.map = map;
+                    return (next_bsp, server.into_carryover());
+                }
+                Err(err) => println!("WARNING: staying on {}, couldn't load {map}: {err}", config.map),
+            }
+        }
+
+        // Run however many ticks are due, which is more than one if the last one ran late
+        for _ in 0..clock.ticks_due(Instant::now()) {
+            let start = Instant::now();
+            server.run_tick(transport, clock.interval());
+            budget.record(start.elapsed());
+        }
+
+        let now = Instant::now();
+        budget.report(now, clock.dropped_ticks());
+        std::thread::sleep(clock.time_until_next(now));
+    }
+}
+
+impl<'a> Server<'a> {
+    pub fn new(bsp: &'a Bsp, config: &ServerConfig) -> Server<'a> {
+        let entities = GameEntities::spawn_all(bsp, &config.map, config.tick_rate, player_entity_id(config.max_players), &Registry::new());
+        let spawn_origin = entities.of_class("info_player_start").next().map(|e| e.origin).unwrap_or(Vector3::ZERO);
+
+        return Server {
+            bsp,
+            clip: BspClipQuery::new(bsp),
+            world: BspVisQuery::new(bsp),
+            conn_handler: ConnHandler::new(ServerInfo {
+                map: config.map.clone(),
+                tick_rate: config.tick_rate,
+                max_players: config.max_players,
+            }),
+            entities,
+            clients: HashMap::new(),
+            // The config makes sure the history reaches back at least as far as a shot can be rewound
+            lag_comp: LagCompensation::new(config.ms_to_ticks(config.lag_history_ms) as usize, config.ms_to_ticks(config.max_rewind_ms)),
+            closing: vec!(),
+            chat: vec!(),
+            rcon: Rcon::new(config.tick_rate),
+            spawn_origin,
+            next_map: None,
+            transition: None,
+            carried: HashMap::new(),
+            tick_rate: config.tick_rate,
+            tick: 0,
+        };
+    }
+
+    // Carries over everyone connected to the server for the previous map. Anyone who was in the game is
+    // told to load this one, and spawns again once they say hello.
+    pub fn take_carryover(&mut self, previous: Carryover, transport: &mut dyn Transport) {
+        let info = self.conn_handler.info().clone();
+        let map = info.map.clone();
+        self.conn_handler = previous.connections;
+        self.rcon.carry_over(previous.rcon_attempts);
+
+        for conn in self.conn_handler.change_map(info) {
+            transport.send_default(conn, &Message::ChangeLevel { map: map.clone() });
+        }
+
+        let landmark = previous.landmark.and_then(|name| landmark_origin(&self.entities, &name));
+        for (conn, player) in previous.players {
+            self.carried.insert(conn, player.respawn(landmark, self.spawn_origin));
+        }
+    }
+
+    // Players only come along with their state when a trigger changed the map, the console starts everyone afresh
+    pub fn into_carryover(self) -> Carryover {
+        let rcon_attempts = self.rcon.server_attempts(self.tick);
+        let Some(transition) = self.transition else {
+            return Carryover { connections: self.conn_handler, landmark: None, players: HashMap::new(), rcon_attempts };
+        };
+
+        let origin = transition.landmark.as_ref().and_then(|name| landmark_origin(&self.entities, name));
+        let players = self.clients.iter().map(|(conn, client)| {
+            let state = client.player.state();
+            (*conn, CarriedPlayer { offset: origin.map(|origin| state.pos - origin), velocity: state.velocity })
+        }).collect();
+
+        return Carryover { connections: self.conn_handler, landmark: transition.landmark, players, rcon_attempts };
+    }
+
+    pub fn tick(&self) -> u32 {
+        return self.tick;
+    }
+
+    pub fn conn_handler(&self) -> &ConnHandler {
+        return &self.conn_handler;
+    }
+
+    pub fn entities(&self) -> &EntityTable {
+        return self.entities.table();
+    }
+
+    // Queues a line for everyone in the game, sent on the next poll. No sender means it's from the server itself.
+    pub fn broadcast_chat(&mut self, sender: Option<&str>, text: &str) {
+        println!("{}: {text}", sender.unwrap_or("Server"));
+        self.chat.push(Message::ChatFrom { sender: sender.map(str::to_string), text: text.to_string() });
+    }
+
+    // Handles everything that arrived on the transport since the last poll
+    pub fn poll(&mut self, transport: &mut dyn Transport) {
+        for event in transport.poll_events() {
+            self.handle_event(event);
+        }
+
+        for (conn, msg) in transport.poll_messages() {
+            if let Some(reply) = self.handle_message(conn, msg) {
+                transport.send_default(conn, &reply);
+            }
+        }
+
+        if !self.chat.is_empty() {
+            let spawned: Vec<ConnectionId> = self.conn_handler.spawned().map(|(conn, _)| conn).collect();
+            for msg in self.chat.drain(..) {
+                transport.broadcast(&|conn| spawned.contains(&conn), &msg);
+            }
+        }
+
+        // Lingering on close makes sure the rejection reason is delivered first
+        for (conn, reason) in self.closing.drain(..) {
+            transport.close(conn, &reason);
+        }
+    }
+
+    pub fn run_tick(&mut self, transport: &mut dyn Transport, tick_time: Duration) {
+        self.tick += 1;
+
+        self.simulate_players(tick_time);
+        self.entities.run_thinks(self.tick);
+        self.handle_game_events();
+
+        let players = self.conn_handler.spawned().filter_map(|(_, c)| c.slot).map(player_entity_id);
+        self.lag_comp.record(self.tick, players, self.entities.table());
+
+        self.send_player_states(transport);
+        self.send_snapshots(transport);
+        self.entities.table_mut().clear_events();
+    }
+
+    fn handle_event(&mut self, event: ConnectionEvent) {
+        match event {
+            ConnectionEvent::Connecting(conn) => {
+                self.conn_handler.on_connecting(conn);
+                println!("Accepted new client {conn:?}, {} connected", self.conn_handler.len());
+            }
+            ConnectionEvent::Connected(conn) => {
+                if self.conn_handler.on_connected(conn).is_err() {
+                    println!("Client {conn:?} connected without being accepted");
+                }
+            }
+            ConnectionEvent::Disconnected(conn, reason) => {
+                println!("Client {conn:?} disconnected: {reason}");
+                self.remove_client(conn, "left the game");
+            }
+        }
+    }
+
+    // Forgets everything about a connection, telling everyone else if it was in the game
+    fn remove_client(&mut self, conn: ConnectionId, why: &str) {
+        self.clients.remove(&conn);
+        self.carried.remove(&conn);
+        self.rcon.forget(conn);
+        if let Ok(connection) = self.conn_handler.on_disconnected(conn) && let Some(slot) = connection.slot {
+            self.entities.table_mut().remove(player_entity_id(slot));
+            self.broadcast_chat(None, &format!("{} {why}", connection.name));
+        }
+    }
+
+    // Runs each client's queued commands, as many as fit in the time that passed this tick
+    fn simulate_players(&mut self, tick_time: Duration) {
+        // Slot order, so the outcome doesn't depend on hash map order
+        let mut order: Vec<(u8, ConnectionId)> = self.conn_handler.spawned().filter_map(|(conn, c)| c.slot.map(|slot| (slot, conn))).collect();
+        order.sort_by_key(|(slot, _)| *slot);
+
+        for (slot, conn) in order {
+            let id = player_entity_id(slot);
+            let Some(client) = self.clients.get_mut(&conn) else { continue; };
+
+            // A client that stalls doesn't get to bank time and then run a burst of commands
+            client.cmd_budget = (client.cmd_budget + tick_time).min(tick_time * 2);
+
+            while let Some(cmd) = client.cmd_queue.front() && cmd_time(cmd) <= client.cmd_budget {
+                let cmd = client.cmd_queue.pop_front().unwrap();
+                client.cmd_budget -= cmd_time(&cmd);
+                client.player.simulate(&self.clip, &cmd);
+
+                // Snap to what the client will receive, so both sides keep simulating from identical values
+                let net_state = NetPlayerState::from_state(&client.player.state());
+                client.player.set_state(&net_state.to_state());
+
+                if let Some(entity) = self.entities.table_mut().get_mut(id) {
+                    entity.origin = net_state.origin;
+                    entity.angles = [quantize_angle(cmd.yaw), quantize_angle(cmd.pitch)];
+                }
+
+                let fired = cmd.buttons.contains(Button::Attack) && !client.last_buttons.contains(Button::Attack);
+                client.last_buttons = cmd.buttons;
+                client.last_processed = Some(cmd.sequence);
+
+                if fired && let Some(hit) = self.lag_comp.hitscan(&self.world, self.entities.table_mut(), self.tick, &cmd, id, HITSCAN_RANGE) {
+                    if let Some(target) = hit.entity {
+                        println!("Entity {id} shot entity {target}");
+                    }
+
+                    if let Some(impact_id) = self.entities.spawn("impact") {
+                        self.entities.set_networked(impact_id, EntityState { origin: quantize_pos(hit.position), model: MODEL_IMPACT, ..Default::default() });
+                        // Impacts stay up for a second
+                        self.entities.set_think(impact_id, self.tick + self.tick_rate, |_, _| Think::Remove);
+                    }
+                }
+            }
+
+            self.entities.touch_player(slot, client.player.pos);
+        }
+    }
+
+    fn send_player_states(&self, transport: &mut dyn Transport) {
+        for (conn, _) in self.conn_handler.spawned() {
+            let Some(client) = self.clients.get(&conn) else { continue; };
+            let Some(ack_sequence) = client.last_processed else { continue; };
+
+            let state = NetPlayerState::from_state(&client.player.state());
+            transport.send_default(conn, &Message::PlayerState { ack_sequence, state });
+        }
+    }
+
+    fn send_snapshots(&mut self, transport: &mut dyn Transport) {
+        for (conn, connection) in self.conn_handler.spawned() {
+            let Some(client) = self.clients.get_mut(&conn) else { continue; };
+            let own_entity = connection.slot.map(player_entity_id);
+            let view_origin = own_entity.and_then(|id| self.entities.table().get(id)).map(|e| dequantize_pos(e.origin)).unwrap_or(self.spawn_origin);
+
+            let delta = client.snapshots.build(self.tick, self.entities.table(), self.bsp, view_origin, own_entity);
+            transport.send_default(conn, &Message::Snapshot(delta));
+        }
+    }
+
+    fn handle_game_events(&mut self) {
+        for event in self.entities.take_events() {
+            match event {
+                GameEvent::ChangeLevel { map, landmark } => if self.next_map.is_none() {
+                    self.next_map = Some(map);
+                    self.transition = Some(Transition { landmark });
+                },
+                GameEvent::Print(text) => self.broadcast_chat(None, &text),
+            }
+        }
+    }
+
+    // Relays a line from a client with its name attached. Only the sender hears about it being dropped.
+    fn handle_chat(&mut self, conn: ConnectionId, text: &str) -> Option<Message> {
+        let Some(text) = chat::sanitize(text) else { return None; };
+        let client = self.clients.get_mut(&conn)?;
+
+        if let Err(muted) = client.chat_flood.check(self.tick) {
+            let seconds = muted.ticks_left.div_ceil(self.tick_rate);
+            return Some(Message::ChatFrom { sender: None, text: format!("You are sending messages too fast, wait {seconds}s") });
+        }
+
+        let name = self.conn_handler.get(conn).map(|c| c.name.clone()).unwrap_or_default();
+        self.broadcast_chat(Some(&name), &text);
+        return None;
+    }
+
+    // Returns a reply to send back to the client, if any
+    fn handle_message(&mut self, conn: ConnectionId, msg: Message) -> Option<Message> {
+        if let Message::HelloFromClient { .. } = msg {
+            return match self.conn_handler.on_hello(conn, msg) {
+                Ok(reply) => {
+                    if let Message::HelloFromServer { slot, .. } = &reply {
+                        let carried = self.carried.remove(&conn);
+                        let origin = carried.as_ref().map(|state| state.pos).unwrap_or(self.spawn_origin);
+
+                        let id = player_entity_id(*slot);
+                        self.entities.table_mut().insert(EntityState { id, origin: quantize_pos(origin), model: MODEL_PLAYER, ..Default::default() });
+
+                        let mut client = ClientState::new(origin, self.tick_rate);
+                        if let Some(state) = carried {
+                            client.player.set_state(&state);
+                        }
+                        self.clients.insert(conn, client);
+
+                        let name = self.conn_handler.get(conn).map(|c| c.name.clone()).unwrap_or_default();
+                        self.broadcast_chat(None, &format!("{name} joined the game"));
+                    }
+                    Some(reply)
+                }
+                Err(Error::Rejected(reason)) => {
+                    println!("Rejecting client {conn:?}: {reason}");
+                    let _ = self.conn_handler.on_disconnected(conn);
+                    self.closing.push((conn, "rejected".to_string()));
+                    Some(Message::Rejected(reason))
+                }
+                Err(_) => None,
+            };
+        }
+
+        // The remote console doesn't need a player, just a connection
+        match msg {
+            Message::RconChallengeRequest if self.conn_handler.get(conn).is_some() => return Some(self.rcon.challenge(conn)),
+            Message::RconCommand { response, command } if self.conn_handler.get(conn).is_some() => {
+                self.rcon.queue(conn, response, command);
+                return None;
+            }
+            _ => {}
+        }
+
+        // Everything else is only accepted from clients that finished the handshake
+        if self.conn_handler.state(conn) != Some(ConnState::Spawned) {
+            return None;
+        }
+
+        match msg {
+            Message::SnapshotAck { tick } => {
+                if let Some(client) = self.clients.get_mut(&conn) {
+                    client.snapshots.ack(tick);
+                }
+            }
+            Message::UserCmds(cmds) => {
+                if let Some(client) = self.clients.get_mut(&conn) {
+                    client.queue_cmds(cmds);
+                }
+            }
+            Message::Chat(text) => return self.handle_chat(conn, &text),
+            _ => {}
+        }
+
+        return None;
+    }
+}
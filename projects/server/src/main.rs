@@ -1,41 +1,33 @@
 mod transport;
-use transport::Transport;
-
-mod conn_handler;
-use conn_handler::ConnHandler;
-
-mod err;
+use transport::GnsTransport;
 
 use gns::GnsGlobal;
-use std::net::Ipv4Addr;
-use std::time::Instant;
-use std::time::Duration;
-
-use shared::message::Message;
-use shared::bsp::*;
+use server::config::{ServerConfig, DEFAULT_CONFIG_PATH};
+use std::io::BufRead;
+use std::sync::mpsc::channel;
 
 fn main() {
+    let config: ServerConfig = shared::config::load(std::env::args(), DEFAULT_CONFIG_PATH).unwrap_or_else(|err| {
+        eprintln!("Bad server config: {err}");
+        std::process::exit(1);
+    });
+
     // Initial the global networking state. Note that this instance must be unique per-process.
     let gns_global = GnsGlobal::get().expect("no global networking state");
-    let mut transport = Transport::new(gns_global.clone(), Ipv4Addr::LOCALHOST.into(), 27821).expect("connection failed");
-    let tick_rate = Duration::from_millis(50);
-
-    let bsp_name = "assets/box.bsp";
-    let bsp = load_bsp(bsp_name);
-
-    println!("Listening for connections...");
-
-    loop {
-        let now = Instant::now();
-        gns_global.poll_callbacks();
-        transport.poll_messages(message_handler);
-        let elapsed = Instant::now() - now;
-
-        if elapsed < tick_rate {
-            std::thread::sleep(tick_rate - elapsed);
+    let mut transport = GnsTransport::new(gns_global.clone(), config.bind, config.port).unwrap_or_else(|_| {
+        eprintln!("Could not listen on {}:{}", config.bind, config.port);
+        std::process::exit(1);
+    });
+
+    // Whatever the operator types goes to the server console
+    let (console_tx, console_rx) = channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if console_tx.send(line).is_err() {
+                break;
+            }
         }
-    }
-}
+    });
 
-fn message_handler(_msg: Message) {
+    server::run(&mut transport, &config, console_rx);
 }
@@ -0,0 +1,110 @@
+use shared::console::Console;
+use shared::message::Message;
+use shared::rcon::*;
+use shared::transport::*;
+use std::collections::HashMap;
+use crate::Server;
+
+// A wrong password locks that connection out for a second, doubling with every further miss up to five
+// minutes. The transport doesn't tell us addresses, and reconnecting gets a fresh connection, so every
+// connection is locked out along with it. That can keep an admin out while someone is guessing, but
+// guessing stays as slow as the lockout no matter how many connections are used.
+const LOCKOUT_SECONDS: u32 = 1;
+const MAX_LOCKOUT_SECONDS: u32 = 300;
+
+// Remote console requests from any connection that got past the transport, spawned or not, so a
+// command line tool can use it without joining the game
+pub struct Rcon {
+    challenges: HashMap<ConnectionId, u64>,
+    attempts: HashMap<ConnectionId, BadAttempts>,
+    server_attempts: BadAttempts, // Across every connection, and carried over to the next map
+    pending: Vec<(ConnectionId, RconResponse, String)>, // Run on the next poll, where the console is at hand
+}
+
+impl Rcon {
+    pub fn new(tick_rate: u32) -> Rcon {
+        return Rcon { challenges: HashMap::new(), attempts: HashMap::new(), server_attempts: lockout(tick_rate), pending: vec!() };
+    }
+
+    // Handed on to the next map by `Carryover` so a map change doesn't lift the lockout
+    pub fn server_attempts(&self, tick: u32) -> BadAttempts {
+        return self.server_attempts.rebased(tick);
+    }
+
+    pub fn carry_over(&mut self, server_attempts: BadAttempts) {
+        self.server_attempts = server_attempts;
+    }
+
+    // Every challenge is good for one command
+    pub fn challenge(&mut self, conn: ConnectionId) -> Message {
+        let nonce = rcon_nonce();
+        self.challenges.insert(conn, nonce);
+        return Message::RconChallenge { nonce };
+    }
+
+    pub fn queue(&mut self, conn: ConnectionId, response: RconResponse, command: String) {
+        self.pending.push((conn, response, command));
+    }
+
+    pub fn forget(&mut self, conn: ConnectionId) {
+        self.challenges.remove(&conn);
+        self.attempts.remove(&conn);
+        self.pending.retain(|(c, _, _)| *c != conn);
+    }
+}
+
+fn lockout(tick_rate: u32) -> BadAttempts {
+    return BadAttempts::new(LOCKOUT_SECONDS * tick_rate, MAX_LOCKOUT_SECONDS * tick_rate);
+}
+
+impl Server<'_> {
+    // Runs every remote command that came in since the last call, sending the output back to whoever sent it
+    pub fn run_rcon(&mut self, console: &mut Console, transport: &mut dyn Transport) {
+        for (conn, response, command) in std::mem::take(&mut self.rcon.pending) {
+            let lines = self.rcon_command(console, conn, response, &command);
+            transport.send_default(conn, &Message::RconOutput { lines });
+        }
+    }
+
+    fn rcon_command(&mut self, console: &mut Console, conn: ConnectionId, response: RconResponse, command: &str) -> Vec<String> {
+        let password = console.get_str("rcon_password").unwrap_or_default().to_string();
+        if password.is_empty() {
+            return vec!["Remote console is disabled on this server".to_string()];
+        }
+
+        let tick_rate = self.tick_rate;
+        let attempts = self.rcon.attempts.entry(conn).or_insert_with(|| lockout(tick_rate));
+        let server_attempts = &mut self.rcon.server_attempts;
+        if let Some(ticks) = attempts.locked(self.tick).max(server_attempts.locked(self.tick)) {
+            return vec![format!("Too many bad passwords, wait {}s", ticks.div_ceil(tick_rate))];
+        }
+
+        let Some(nonce) = self.rcon.challenges.remove(&conn) else {
+            return vec!["No rcon challenge was requested".to_string()];
+        };
+
+        if rcon_response(nonce, &password, command) != response {
+            attempts.fail(self.tick);
+            server_attempts.fail(self.tick);
+            println!("Bad rcon password from {conn:?}");
+            return vec!["Bad rcon password".to_string()];
+        }
+
+        attempts.succeed();
+        server_attempts.succeed();
+        println!("Rcon from {conn:?}: {command}");
+
+        // Anything printed before this doesn't belong to the remote command
+        for line in console.take_output() {
+            println!("{line}");
+        }
+
+        console.execute(command, &mut |console, cmd| self.run_command(console, cmd));
+
+        let lines = console.take_output();
+        for line in &lines {
+            println!("{line}");
+        }
+        return lines;
+    }
+}
@@ -0,0 +1,85 @@
+use raylib::prelude::*;
+use shared::bsp::Bsp;
+use shared::bsp_query::Pvs;
+use shared::message::dequantize_pos;
+use shared::snapshot::*;
+use std::collections::BTreeMap;
+
+// Networked state of every entity in the world, the source each tick's snapshots are cut from. Ids come from
+// `GameEntities`, or from the player's slot for players, so there's one id space for both.
+pub struct EntityTable {
+    entities: BTreeMap<u16, EntityState>,
+}
+
+impl EntityTable {
+    pub fn new() -> EntityTable {
+        return EntityTable { entities: BTreeMap::new() };
+    }
+
+    pub fn insert(&mut self, state: EntityState) {
+        self.entities.insert(state.id, state);
+    }
+
+    pub fn remove(&mut self, id: u16) -> Option<EntityState> {
+        return self.entities.remove(&id);
+    }
+
+    pub fn get(&self, id: u16) -> Option<&EntityState> {
+        return self.entities.get(&id);
+    }
+
+    pub fn get_mut(&mut self, id: u16) -> Option<&mut EntityState> {
+        return self.entities.get_mut(&id);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &EntityState> {
+        return self.entities.values();
+    }
+
+    // Effects like teleports only last for the snapshot they happened in
+    pub fn clear_events(&mut self) {
+        for entity in self.entities.values_mut() {
+            entity.effects.remove(EntityEffect::Teleport);
+        }
+    }
+}
+
+// Snapshots sent to a single client, kept until it acknowledges one so later ones can be delta'd against it
+pub struct ClientSnapshots {
+    sent: SnapshotHistory,
+    acked_tick: Option<u32>,
+}
+
+impl ClientSnapshots {
+    pub fn new() -> ClientSnapshots {
+        return ClientSnapshots { sent: SnapshotHistory::new(), acked_tick: None };
+    }
+
+    pub fn ack(&mut self, tick: u32) {
+        // Acks can arrive out of order, never move backwards
+        if self.acked_tick.is_some_and(|acked| acked >= tick) || self.sent.get(tick).is_none() {
+            return;
+        }
+
+        self.acked_tick = Some(tick);
+        self.sent.discard_before(tick);
+    }
+
+    // Cuts this tick's snapshot down to what the client can see, and deltas it against the newest snapshot
+    // the client is known to have. A dropped snapshot just means the next one is based on an older ack, and
+    // if that has already fallen out of the history, a full snapshot is sent instead.
+    pub fn build(&mut self, tick: u32, table: &EntityTable, bsp: &Bsp, view_origin: Vector3, own_entity: Option<u16>) -> SnapshotDelta {
+        let pvs = Pvs::for_point(bsp, view_origin);
+        let visible = table.iter()
+            .filter(|e| Some(e.id) == own_entity || pvs.contains_point(bsp, dequantize_pos(e.origin)))
+            .copied()
+            .collect();
+
+        let snapshot = Snapshot::new(tick, visible);
+        let base = self.acked_tick.and_then(|acked| self.sent.get(acked));
+        let delta = snapshot.delta_from(base);
+
+        self.sent.push(snapshot);
+        return delta;
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::{Duration, Instant};
+
+// If the server falls further behind than this it stops trying to catch up and drops the time,
+// otherwise one long stall would be followed by a burst of ticks that makes everything worse
+const MAX_CATCHUP_TICKS: u32 = 5;
+
+// How often the tick budget is reported
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Decides when the next simulation tick is due, independent of how long each tick takes
+pub struct TickClock {
+    interval: Duration,
+    next_tick: Instant,
+    dropped_ticks: u64,
+}
+
+impl TickClock {
+    pub fn new(tick_rate: u32) -> TickClock {
+        return TickClock { interval: Duration::from_secs(1) / tick_rate.max(1), next_tick: Instant::now(), dropped_ticks: 0 };
+    }
+
+    pub fn interval(&self) -> Duration {
+        return self.interval;
+    }
+
+    // How many ticks should be run right now. Usually one, more after a late tick so the
+    // simulation keeps pace with wall time.
+    pub fn ticks_due(&mut self, now: Instant) -> u32 {
+        let mut due = 0;
+        while self.next_tick <= now {
+            due += 1;
+            self.next_tick += self.interval;
+        }
+
+        if due > MAX_CATCHUP_TICKS {
+            self.dropped_ticks += (due - MAX_CATCHUP_TICKS) as u64;
+            println!("Server running {due} ticks behind, skipping {}", due - MAX_CATCHUP_TICKS);
+            self.next_tick = now + self.interval;
+            due = MAX_CATCHUP_TICKS;
+        }
+
+        return due;
+    }
+
+    pub fn time_until_next(&self, now: Instant) -> Duration {
+        return self.next_tick.saturating_duration_since(now);
+    }
+
+    pub fn dropped_ticks(&self) -> u64 {
+        return self.dropped_ticks;
+    }
+}
+
+// Keeps track of how much of each tick's time budget the simulation used
+pub struct TickBudget {
+    budget: Duration,
+    ticks: u32,
+    total: Duration,
+    worst: Duration,
+    over_budget: u32,
+    last_report: Instant,
+}
+
+impl TickBudget {
+    pub fn new(budget: Duration) -> TickBudget {
+        return TickBudget { budget, ticks: 0, total: Duration::ZERO, worst: Duration::ZERO, over_budget: 0, last_report: Instant::now() };
+    }
+
+    pub fn record(&mut self, elapsed: Duration) {
+        self.ticks += 1;
+        self.total += elapsed;
+        self.worst = self.worst.max(elapsed);
+        if elapsed > self.budget {
+            self.over_budget += 1;
+        }
+    }
+
+    // Prints a summary every so often and starts a new measuring period
+    pub fn report(&mut self, now: Instant, dropped_ticks: u64) {
+        if now - self.last_report < REPORT_INTERVAL || self.ticks == 0 {
+            return;
+        }
+
+        let average = self.total / self.ticks;
+        println!(
+            "Tick time: avg {:.2}ms, max {:.2}ms of {:.2}ms budget ({:.0}%), {} ticks over budget, {} dropped in total",
+            average.as_secs_f64() * 1000f64,
+            self.worst.as_secs_f64() * 1000f64,
+            self.budget.as_secs_f64() * 1000f64,
+            average.as_secs_f64() / self.budget.as_secs_f64() * 100f64,
+            self.over_budget,
+            dropped_ticks,
+        );
+
+        self.ticks = 0;
+        self.total = Duration::ZERO;
+        self.worst = Duration::ZERO;
+        self.over_budget = 0;
+        self.last_report = now;
+    }
+}
@@ -1,31 +1,98 @@
 use shared::message::Message;
+use shared::transport::*;
 use gns::*;
+use gns::sys::{k_nSteamNetworkingSend_Reliable, k_nSteamNetworkingSend_Unreliable, k_nSteamNetworkingSend_UnreliableNoDelay};
 use crate::transport::sys::ESteamNetworkingConnectionState;
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::sync::Arc;
-use std::collections::HashMap;
 
-pub struct Transport
+// Server side of the GameNetworkingSockets transport. GNS connection handles are mapped to our own ids
+// so nothing above the transport has to know about them.
+pub struct GnsTransport
 {
+    gns_global: Arc<GnsGlobal>,
     server: GnsSocket<IsServer>,
-    connected_clients: HashMap::<GnsConnection, String>,
-    nonce: u16,
+    ids: HashMap<GnsConnection, ConnectionId>,
+    connections: HashMap<ConnectionId, GnsConnection>,
+    next_id: u32,
 }
 
-impl Transport
+impl GnsTransport
 {
-    pub fn new(gns_global: Arc<GnsGlobal>, addr: IpAddr, port: u16) -> Result<Transport, ()> {
+    pub fn new(gns_global: Arc<GnsGlobal>, addr: IpAddr, port: u16) -> Result<GnsTransport, ()> {
         let gns_socket = GnsSocket::<IsCreated>::new(gns_global.clone());
         let server = gns_socket.listen(addr, port)?;
-        let connected_clients = HashMap::<GnsConnection, String>::new();
 
-        return Ok(Transport { server, connected_clients, nonce: 0 });
+        return Ok(GnsTransport { gns_global, server, ids: HashMap::new(), connections: HashMap::new(), next_id: 0 });
+    }
+
+    fn add_connection(&mut self, gns_conn: GnsConnection) -> ConnectionId {
+        let conn = ConnectionId(self.next_id);
+        self.next_id += 1;
+        self.ids.insert(gns_conn, conn);
+        self.connections.insert(conn, gns_conn);
+        return conn;
+    }
+
+    fn remove_connection(&mut self, conn: ConnectionId) -> Option<GnsConnection> {
+        let gns_conn = self.connections.remove(&conn)?;
+        self.ids.remove(&gns_conn);
+        return Some(gns_conn);
+    }
+}
+
+fn send_flags(reliability: Reliability) -> i32 {
+    return match reliability {
+        Reliability::Reliable => k_nSteamNetworkingSend_Reliable,
+        Reliability::Unreliable => k_nSteamNetworkingSend_Unreliable,
+        Reliability::UnreliableNoDelay => k_nSteamNetworkingSend_UnreliableNoDelay,
+    };
+}
+
+impl Transport for GnsTransport
+{
+    fn send(&mut self, conn: ConnectionId, msg: &Message, reliability: Reliability) {
+        let Some(gns_conn) = self.connections.get(&conn) else { return; };
+        let message = self.server.utils().allocate_message(*gns_conn, send_flags(reliability), &msg.to_bytes());
+        self.server.send_messages(vec![message]);
+    }
+
+    // Hands every copy to GNS in one batch
+    fn broadcast(&mut self, filter: &dyn Fn(ConnectionId) -> bool, msg: &Message) {
+        let bytes = msg.to_bytes();
+        let flags = send_flags(msg.reliability());
+        let messages: Vec<_> = self.connections.iter()
+            .filter(|(conn, _)| filter(**conn))
+            .map(|(_, gns_conn)| self.server.utils().allocate_message(*gns_conn, flags, &bytes))
+            .collect();
+
+        if !messages.is_empty() {
+            self.server.send_messages(messages);
+        }
+    }
+
+    fn connections(&self) -> Vec<ConnectionId> {
+        return self.connections.keys().copied().collect();
+    }
+
+    // Lingers so anything already queued, like a rejection reason, is delivered before the connection goes away
+    fn close(&mut self, conn: ConnectionId, reason: &str) {
+        if let Some(gns_conn) = self.remove_connection(conn) {
+            self.server.close_connection(gns_conn, 0, reason, true);
+        }
     }
 
-    pub fn poll_messages(&mut self, mut msg_callback: impl FnMut(Message)) {
+    fn poll_messages(&mut self) -> Vec<(ConnectionId, Message)> {
+        let mut messages = vec!();
+
         loop {
             let num_msg = self.server.poll_messages::<100>(|message| {
-                msg_callback(Message::from_bytes(message.payload()))
+                let Some(conn) = self.ids.get(&message.connection()) else { return; };
+                match Message::from_bytes(message.payload()) {
+                    Ok(msg) => messages.push((*conn, msg)),
+                    Err(err) => println!("Dropping malformed message of {} bytes: {err}", message.payload().len()),
+                }
             });
 
             if let Some(n) = num_msg &&
@@ -34,68 +101,70 @@ impl Transport
             }
         }
 
+        return messages;
+    }
+
+    fn poll_events(&mut self) -> Vec<ConnectionEvent> {
+        self.gns_global.poll_callbacks();
+
+        let mut events = vec!();
+        let mut gns_events = vec!();
+
         loop
         {
             let num_msg = self.server.poll_event::<100>(|event| {
-                match (event.old_state(), event.info().state()) {
-                    // A client is about to connect, accept it.
-                    (
-                        ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_None,
-                        ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_Connecting,
-                    ) => {
-                        let result = self.server.accept(event.connection());
-                        println!("GnsSocket<Server>: accepted new client: {:#?}.", result);
-                        if result.is_ok() {
-                            self.connected_clients.insert(event.connection(), self.nonce.to_string());
-                            /*
-                            broadcast_chat(
-                                self.connected_clients.keys().copied().collect(),
-                                "Server",
-                                &format!("A new user joined us, welcome {}", self.nonce),
-                            );
-                            */
-                            self.nonce += 1;
-                        }
-                        println!("GnsSocket<Server>: number of clients: {:#?}.", self.connected_clients.len());
-                    }
+                gns_events.push((event.connection(), event.old_state(), event.info().state(), event.info().end_debug().to_string()));
+            });
+
+            if num_msg < 100usize {
+                break;
+            }
+        }
 
-                    // A client is connected, we previously accepted it and don't do anything here.
-                    // In a more sophisticated scenario we could initial sending some messages.
-                    (
-                        ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_Connecting,
-                        ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_Connected,
-                    ) => {
+        for (gns_conn, old_state, state, end_debug) in gns_events {
+            match (old_state, state) {
+                // A client is about to connect, accept it.
+                (
+                    ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_None,
+                    ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_Connecting,
+                ) => {
+                    let result = self.server.accept(gns_conn);
+                    if result.is_ok() {
+                        let conn = self.add_connection(gns_conn);
+                        events.push(ConnectionEvent::Connecting(conn));
+                    } else {
+                        println!("GnsSocket<Server>: failed to accept {:#?}.", gns_conn);
                     }
+                }
 
-                    (_, ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ClosedByPeer |
-                        ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ProblemDetectedLocally) => {
-                        // Remove the client from the list and close the connection.
-                        let conn = event.connection();
-                        println!("GnsSocket<Server>: {:#?} disconnected", conn);
-                        /*
-                        let nickname = &self.connected_clients[&conn];
-                        broadcast_chat(
-                            self.connected_clients.keys().copied().collect(),
-                            "Server",
-                            &format!("[{}] lost faith.", nickname),
-                        );
-                        */
-                        self.connected_clients.remove(&conn);
-                        // Make sure we cleanup the connection, mandatory as per GNS doc.
-                        self.server.close_connection(conn, 0, "", false);
+                // The client finished connecting, it now has to introduce itself with a hello
+                (
+                    ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_Connecting,
+                    ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_Connected,
+                ) => {
+                    if let Some(conn) = self.ids.get(&gns_conn) {
+                        events.push(ConnectionEvent::Connected(*conn));
                     }
+                }
 
-                    // A client state is changing, perhaps disconnecting
-                    // If a client disconnected and it's connection get cleaned up, its state goes back to `ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_None`
-                    (previous, current) => {
-                        println!("GnsSocket<Server>: {:#?} => {:#?}.", previous, current);
+                (_, ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ClosedByPeer |
+                    ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ProblemDetectedLocally) => {
+                    if let Some(conn) = self.ids.get(&gns_conn).copied() {
+                        self.remove_connection(conn);
+                        events.push(ConnectionEvent::Disconnected(conn, end_debug));
                     }
+                    // Make sure we cleanup the connection, mandatory as per GNS doc.
+                    self.server.close_connection(gns_conn, 0, "", false);
                 }
-            });
 
-            if num_msg < 100usize {
-                break;
+                // A client state is changing, perhaps disconnecting
+                // If a client disconnected and it's connection get cleaned up, its state goes back to `ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_None`
+                (previous, current) => {
+                    println!("GnsSocket<Server>: {:#?} => {:#?}.", previous, current);
+                }
             }
         }
+
+        return events;
     }
 }
@@ -0,0 +1,100 @@
+use crate::changelevel::resolve_map;
+use crate::config::check_map;
+use crate::entity::*;
+
+// How long a trigger_multiple waits before it can fire again when the map doesn't say
+const DEFAULT_TRIGGER_WAIT: f32 = 0.2f32;
+
+pub fn register(registry: &mut Registry) {
+    // Only there to be found, by position or by name
+    registry.register("info_player_start", |_, _| Ok(()));
+    registry.register("info_landmark", |_, _| Ok(()));
+    registry.register("info_null", |_, _| Ok(()));
+
+    registry.register("trigger_multiple", spawn_trigger_multiple);
+    registry.register("trigger_once", spawn_trigger_once);
+    registry.register("trigger_relay", spawn_trigger_relay);
+    registry.register("trigger_changelevel", spawn_trigger_changelevel);
+}
+
+fn spawn_trigger_multiple(entity: &mut GameEntity, _: &SpawnContext) -> Result<(), String> {
+    if entity.bounds.is_none() {
+        return Err("no brush model".to_string());
+    }
+
+    if entity.wait == 0f32 {
+        entity.wait = DEFAULT_TRIGGER_WAIT;
+    }
+
+    entity.touch_fn = Some(|entities, id, other| {
+        if matches!(other, Activator::Player(_)) {
+            trigger(entities, id, other);
+        }
+    });
+
+    // Triggers with a name can be set off by other entities too
+    if entity.targetname.is_some() {
+        entity.use_fn = Some(trigger);
+    }
+
+    return Ok(());
+}
+
+fn spawn_trigger_once(entity: &mut GameEntity, ctx: &SpawnContext) -> Result<(), String> {
+    entity.wait = -1f32;
+    return spawn_trigger_multiple(entity, ctx);
+}
+
+// Fires the trigger's targets unless it's still waiting to rearm. One that never rearms is done after that.
+fn trigger(entities: &mut GameEntities, id: u16, activator: Activator) {
+    let tick = entities.tick();
+    let Some(entity) = entities.get(id) else { return; };
+    if tick < entity.rearm_tick {
+        return;
+    }
+
+    let wait = entity.wait;
+    entities.use_targets(id, activator);
+
+    if wait < 0f32 {
+        entities.remove(id);
+    } else {
+        let rearm_tick = tick + entities.seconds_to_ticks(wait).max(1);
+        if let Some(entity) = entities.get_mut(id) {
+            entity.rearm_tick = rearm_tick;
+        }
+    }
+}
+
+fn spawn_trigger_relay(entity: &mut GameEntity, _: &SpawnContext) -> Result<(), String> {
+    entity.use_fn = Some(|entities, id, activator| entities.use_targets(id, activator));
+    return Ok(());
+}
+
+// Takes everyone to the map in its `map` key when a player walks in. Ones without a map we have are left
+// out, rather than failing once someone walks into them.
+fn spawn_trigger_changelevel(entity: &mut GameEntity, ctx: &SpawnContext) -> Result<(), String> {
+    if entity.bounds.is_none() {
+        return Err("no brush model".to_string());
+    }
+
+    let name = entity.map.as_deref().ok_or("no map")?;
+    let map = resolve_map(ctx.map, name);
+    check_map(&map)?;
+    entity.map = Some(map);
+
+    entity.touch_fn = Some(|entities, id, other| {
+        let Activator::Player(slot) = other else { return; };
+        let Some(entity) = entities.get_mut(id) else { return; };
+
+        // The first player in takes everyone along, once
+        entity.touch_fn = None;
+        let map = entity.map.clone().unwrap_or_default();
+        let landmark = entity.landmark.clone();
+
+        println!("Player in slot {slot} touched the exit to {map}");
+        entities.push_event(GameEvent::ChangeLevel { map, landmark });
+    });
+
+    return Ok(());
+}
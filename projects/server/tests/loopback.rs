@@ -0,0 +1,297 @@
+use server::Server;
+use server::config::{ServerConfig, DEFAULT_MAX_PLAYERS, DEFAULT_TICK_RATE};
+use server::entity::*;
+use shared::bsp::*;
+use shared::console::Console;
+use shared::message::*;
+use shared::rcon::rcon_response;
+use shared::snapshot::*;
+use shared::transport::*;
+use shared::user_cmd::*;
+use std::time::Duration;
+
+const TICK_TIME: Duration = Duration::from_nanos(1_000_000_000 / DEFAULT_TICK_RATE as u64);
+
+fn load_box() -> Bsp {
+    return load_bsp(concat!(env!("CARGO_MANIFEST_DIR"), "/../client/assets/box.bsp")).unwrap();
+}
+
+fn box_config() -> ServerConfig {
+    return ServerConfig { map: "box".to_string(), ..Default::default() };
+}
+
+fn hello() -> Message {
+    return Message::HelloFromClient { protocol_version: PROTOCOL_VERSION, build_hash: BUILD_HASH, id: 1, name: "test".to_string() };
+}
+
+fn entity(keys: &[(&str, &str)]) -> Entity {
+    return Entity { line: 0, pairs: keys.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect() };
+}
+
+// Connects a client over the loopback and returns the slot it was given
+fn join(server: &mut Server, server_end: &mut LoopbackTransport, client: &mut LoopbackTransport) -> u8 {
+    server.poll(server_end);
+    assert_eq!(client.poll_events(), vec![ConnectionEvent::Connected(LOOPBACK_CONNECTION)]);
+
+    client.send(LOOPBACK_CONNECTION, &hello(), Reliability::Reliable);
+    server.poll(server_end);
+
+    let messages = client.poll_messages();
+    let Some((_, Message::HelloFromServer { slot, .. })) = messages.first() else {
+        panic!("expected a hello back, got {messages:?}");
+    };
+
+    return *slot;
+}
+
+#[test]
+fn client_joins_over_loopback() {
+    let bsp = load_box();
+    let mut server = Server::new(&bsp, &box_config());
+    let (mut server_end, mut client) = loopback_pair();
+
+    let slot = join(&mut server, &mut server_end, &mut client);
+    assert_eq!(slot, 0);
+    assert!(server.entities().get(player_entity_id(slot)).is_some());
+}
+
+#[test]
+fn commands_are_simulated_and_acknowledged() {
+    let bsp = load_box();
+    let mut server = Server::new(&bsp, &box_config());
+    let (mut server_end, mut client) = loopback_pair();
+    let slot = join(&mut server, &mut server_end, &mut client);
+    let start = server.entities().get(player_entity_id(slot)).unwrap().origin;
+
+    let cmds: Vec<UserCmd> = (0..3).map(|sequence| {
+        let mut cmd = UserCmd::new(sequence);
+        cmd.forward_move = MOVE_AXIS_MAX;
+        cmd
+    }).collect();
+    client.send(LOOPBACK_CONNECTION, &Message::UserCmds(cmds), Reliability::Unreliable);
+
+    server.poll(&mut server_end);
+    server.run_tick(&mut server_end, TICK_TIME);
+
+    let messages: Vec<Message> = client.poll_messages().into_iter().map(|(_, msg)| msg).collect();
+    assert!(messages.iter().any(|msg| matches!(msg, Message::PlayerState { ack_sequence: 2, .. })), "{messages:?}");
+
+    let snapshot = messages.iter().find_map(|msg| match msg {
+        Message::Snapshot(delta) => Some(delta.apply(None).unwrap()),
+        _ => None,
+    }).expect("no snapshot sent");
+
+    let own = snapshot.get(player_entity_id(slot)).expect("own entity missing from snapshot");
+    assert_ne!(own.origin, start);
+}
+
+#[test]
+fn command_lengths_are_clamped() {
+    let bsp = load_box();
+    let mut server = Server::new(&bsp, &box_config());
+    let (mut server_end, mut client) = loopback_pair();
+    join(&mut server, &mut server_end, &mut client);
+
+    // Longer than the budget can ever hold, followed by ones that claim to take no time at all
+    let cmds: Vec<UserCmd> = (0..MAX_CMDS_PER_MESSAGE as u32).map(|sequence| {
+        let mut cmd = UserCmd::new(sequence);
+        cmd.msec = if sequence == 0 { u8::MAX } else { 0 };
+        cmd
+    }).collect();
+    client.send(LOOPBACK_CONNECTION, &Message::UserCmds(cmds), Reliability::Unreliable);
+    server.poll(&mut server_end);
+
+    let mut run_tick = || {
+        server.run_tick(&mut server_end, TICK_TIME);
+        return client.poll_messages().into_iter().find_map(|(_, msg)| match msg {
+            Message::PlayerState { ack_sequence, .. } => Some(ack_sequence),
+            _ => None,
+        });
+    };
+
+    // The long one still runs once two ticks of time have built up, and the free ones each cost a
+    // millisecond, so they wait for the next tick's time
+    assert_eq!(run_tick(), None);
+    assert_eq!(run_tick(), Some(0));
+    assert_eq!(run_tick(), Some(MAX_CMDS_PER_MESSAGE as u32 - 1));
+}
+
+#[test]
+fn disconnecting_removes_the_player() {
+    let bsp = load_box();
+    let mut server = Server::new(&bsp, &box_config());
+    let (mut server_end, mut client) = loopback_pair();
+    let slot = join(&mut server, &mut server_end, &mut client);
+
+    client.close(LOOPBACK_CONNECTION, "bye");
+    server.poll(&mut server_end);
+
+    assert_eq!(server.conn_handler().len(), 0);
+    assert!(server.entities().get(player_entity_id(slot)).is_none());
+}
+
+#[test]
+fn chat_is_relayed_with_the_sender_name() {
+    let bsp = load_box();
+    let mut server = Server::new(&bsp, &box_config());
+    let (mut server_end, mut client) = loopback_pair();
+    join(&mut server, &mut server_end, &mut client);
+
+    client.send(LOOPBACK_CONNECTION, &Message::Chat(format!("  hi{}", "!".repeat(500))), Reliability::Reliable);
+    server.poll(&mut server_end);
+
+    let messages = client.poll_messages();
+    let Some((_, Message::ChatFrom { sender: Some(sender), text })) = messages.first() else {
+        panic!("expected the chat relayed back, got {messages:?}");
+    };
+    assert_eq!(sender, "test");
+    assert!(text.starts_with("hi!"));
+    assert_eq!(text.chars().count(), shared::chat::MAX_CHAT_LEN);
+}
+
+// Runs one command through the remote console the way a client would, returning the output
+fn rcon(server: &mut Server, console: &mut Console, server_end: &mut LoopbackTransport, client: &mut LoopbackTransport, password: &str) -> Vec<String> {
+    client.send(LOOPBACK_CONNECTION, &Message::RconChallengeRequest, Reliability::Reliable);
+    server.poll(server_end);
+    let Some((_, Message::RconChallenge { nonce })) = client.poll_messages().pop() else { panic!("no challenge") };
+
+    let command = "echo hello".to_string();
+    client.send(LOOPBACK_CONNECTION, &Message::RconCommand { response: rcon_response(nonce, password, &command), command }, Reliability::Reliable);
+    server.poll(server_end);
+    server.run_rcon(console, server_end);
+
+    let Some((_, Message::RconOutput { lines })) = client.poll_messages().pop() else { panic!("no output") };
+    return lines;
+}
+
+#[test]
+fn rcon_needs_the_password() {
+    let bsp = load_box();
+    let mut server = Server::new(&bsp, &box_config());
+    let mut console = Console::new();
+    server::commands::register(&mut console);
+    console.set("rcon_password", "secret").unwrap();
+
+    // The remote console works without joining the game
+    let (mut server_end, mut client) = loopback_pair();
+    server.poll(&mut server_end);
+    client.poll_events();
+
+    assert_eq!(rcon(&mut server, &mut console, &mut server_end, &mut client, "wrong"), vec!["Bad rcon password"]);
+
+    // Locked out for a bit, even with the right password
+    assert!(rcon(&mut server, &mut console, &mut server_end, &mut client, "secret")[0].starts_with("Too many bad passwords"));
+    for _ in 0..DEFAULT_TICK_RATE {
+        server.run_tick(&mut server_end, TICK_TIME);
+    }
+    assert_eq!(rcon(&mut server, &mut console, &mut server_end, &mut client, "secret"), vec!["hello"]);
+
+    // Reconnecting after a miss doesn't get around the lockout
+    assert_eq!(rcon(&mut server, &mut console, &mut server_end, &mut client, "wrong"), vec!["Bad rcon password"]);
+    client.close(LOOPBACK_CONNECTION, "bye");
+    server.poll(&mut server_end);
+
+    let (mut server_end, mut client) = loopback_pair();
+    server.poll(&mut server_end);
+    client.poll_events();
+    assert!(rcon(&mut server, &mut console, &mut server_end, &mut client, "secret")[0].starts_with("Too many bad passwords"));
+
+    // Nor does a map change
+    let next_bsp = load_box();
+    let mut next = Server::new(&next_bsp, &box_config());
+    next.take_carryover(server.into_carryover(), &mut server_end);
+    client.poll_messages();
+    assert!(rcon(&mut next, &mut console, &mut server_end, &mut client, "secret")[0].starts_with("Too many bad passwords"));
+}
+
+#[test]
+fn startup_script_sets_cvars() {
+    let path = std::env::temp_dir().join(format!("autoexec_test_{}.cfg", std::process::id()));
+    std::fs::write(&path, "rcon_password secret\nkick 0\n").unwrap();
+
+    let mut console = Console::new();
+    server::commands::register(&mut console);
+    server::commands::exec_startup(&mut console, path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(console.get_str("rcon_password"), Some("secret"));
+    assert_eq!(console.take_output(), vec!["kick can't be run before a map is loaded"]);
+
+    // A server without one starts with the defaults
+    server::commands::exec_startup(&mut console, "missing_autoexec.cfg");
+    assert_eq!(console.take_output(), Vec::<String>::new());
+}
+
+#[test]
+fn changing_map_keeps_the_connection() {
+    let bsp = load_box();
+    let mut server = Server::new(&bsp, &box_config());
+    let (mut server_end, mut client) = loopback_pair();
+    let slot = join(&mut server, &mut server_end, &mut client);
+
+    let next_bsp = load_box();
+    let mut next = Server::new(&next_bsp, &ServerConfig { map: "next".to_string(), ..Default::default() });
+    next.take_carryover(server.into_carryover(), &mut server_end);
+
+    // Not in the game again until the client has loaded the map and said hello
+    let messages = client.poll_messages();
+    assert!(matches!(messages.as_slice(), [(_, Message::ChangeLevel { map })] if map == "next"), "{messages:?}");
+    assert!(next.entities().get(player_entity_id(slot)).is_none());
+
+    client.send(LOOPBACK_CONNECTION, &hello(), Reliability::Reliable);
+    next.poll(&mut server_end);
+
+    let messages = client.poll_messages();
+    assert!(matches!(messages.first(), Some((_, Message::HelloFromServer { map, slot: 0, .. })) if map == "next"), "{messages:?}");
+    assert!(next.entities().get(player_entity_id(0)).is_some());
+    assert!(client.poll_events().is_empty());
+}
+
+#[test]
+fn entity_ids_are_deterministic() {
+    let bsp = load_box();
+    let registry = Registry::new();
+    let ids = |entities: &GameEntities| entities.iter().map(|e| (e.id, e.classname.clone())).collect::<Vec<_>>();
+
+    let mut entities = GameEntities::spawn_all(&bsp, "box", DEFAULT_TICK_RATE, player_entity_id(DEFAULT_MAX_PLAYERS), &registry);
+    assert_eq!(ids(&entities), ids(&GameEntities::spawn_all(&bsp, "box", DEFAULT_TICK_RATE, player_entity_id(DEFAULT_MAX_PLAYERS), &registry)));
+    assert!(entities.of_class("info_player_start").next().is_some());
+
+    // Player ids are kept clear, and freed ids are handed out again lowest first
+    let first = player_entity_id(DEFAULT_MAX_PLAYERS);
+    let count = entities.iter().count() as u16;
+    assert_eq!(entities.iter().next().map(|e| e.id), Some(first));
+    entities.remove(first);
+    assert_eq!(entities.spawn("info_null"), Some(first));
+    assert_eq!(entities.spawn("info_null"), Some(first + count));
+
+    // Networked entities share the id and go away with it
+    entities.set_networked(first, EntityState { model: MODEL_IMPACT, ..Default::default() });
+    assert_eq!(entities.table().get(first).map(|e| e.id), Some(first));
+    entities.remove(first);
+    assert!(entities.table().get(first).is_none());
+}
+
+#[test]
+fn targets_fire_through_the_chain() {
+    let mut bsp = load_box();
+    bsp.entities.push(entity(&[("classname", "trigger_relay"), ("targetname", "start"), ("target", "middle")]));
+    bsp.entities.push(entity(&[("classname", "trigger_relay"), ("targetname", "middle"), ("target", "end"), ("delay", "1")]));
+    bsp.entities.push(entity(&[("classname", "trigger_relay"), ("targetname", "end"), ("message", "fired")]));
+    bsp.entities.push(entity(&[("classname", "trigger_relay"), ("targetname", "broken"), ("delay", "soon")]));
+
+    let mut entities = GameEntities::spawn_all(&bsp, "box", DEFAULT_TICK_RATE, player_entity_id(DEFAULT_MAX_PLAYERS), &Registry::new());
+    assert!(entities.find_target("broken").is_empty());
+
+    entities.fire("start", Activator::World);
+    assert!(entities.take_events().is_empty());
+
+    // Nothing until the delay is up
+    for tick in 1..DEFAULT_TICK_RATE {
+        entities.run_thinks(tick);
+    }
+    assert!(entities.take_events().is_empty());
+
+    entities.run_thinks(DEFAULT_TICK_RATE);
+    assert_eq!(entities.take_events(), vec![GameEvent::Print("fired".to_string())]);
+}
@@ -9,9 +9,9 @@ use std::io::BufReader;
 use std::ptr::null;
 use std::str::FromStr;
 use std::collections::HashSet;
-use std::collections::HashMap;
 use strum_macros::FromRepr;
 use enumset::EnumSetType;
+use crate::ent::parse_ent;
 
 const BSP2_VER: i32 = (('B' as i32) << 0) | (('S' as i32) << 8) | (('P' as i32) << 16) | (('2' as i32) << 24);
 const BSPX_VER: i32 = (('B' as i32) << 0) | (('S' as i32) << 8) | (('P' as i32) << 16) | (('X' as i32) << 24);
@@ -115,12 +115,14 @@ enum ModelType
 	NumTypes,
 }
 
-#[derive(Debug)]
+// Keys in the order they're written, and a key given twice is kept twice
+#[derive(Clone, PartialEq, Debug, Default)]
 pub struct Entity {
-    pub map: HashMap<String, String>
+    pub line: usize, // Where its opening brace is in the entity text
+    pub pairs: Vec<(String, String)>
 }
 
-struct Model
+pub struct Model
 {
 	mins: Vector3,
 	maxs: Vector3,
@@ -131,6 +133,21 @@ struct Model
 	num_faces: i32
 }
 
+impl Model
+{
+	// Bounding box in bsp space, mins then maxs
+	pub fn bounds(&self) -> (Vector3, Vector3)
+	{
+		return (self.mins, self.maxs);
+	}
+
+	// Number of vis leafs, which for the world model sets the width of a PVS row
+	pub fn visleafs(&self) -> i32
+	{
+		return self.visleafs;
+	}
+}
+
 #[derive(Clone, Copy)]
 pub enum TextureType
 {
@@ -154,6 +171,18 @@ pub struct Plane
 	_pad1: u8
 }
 
+impl Plane
+{
+	pub fn new(normal: Vector3, dist: f32, p_type: u8) -> Plane
+	{
+		let sign = if normal.x < 0f32 { 1 << 0 } else { 0 } |
+			if normal.y < 0f32 { 1 << 1 } else { 0 } |
+			if normal.z < 0f32 { 1 << 2 } else { 0 };
+
+		return Plane { normal, dist, p_type, sign, _pad0: 0, _pad1: 0 };
+	}
+}
+
 #[derive(FromRepr, Debug, Clone, Copy, PartialEq)]
 #[repr(i32)]
 pub enum LeafContents {
@@ -320,11 +349,13 @@ pub struct LightgridSample {
 	pub style: u8,
 }
 
-pub fn load_bsp(filename: &str) -> Bsp
+// Loads a map from next to the executable. A map that can't be opened or has bad entities is an error, the
+// rest of the file is trusted to be a valid bsp.
+pub fn load_bsp(filename: &str) -> Result<Bsp, String>
 {
 	let exe_path = std::env::current_exe().expect("no exe path");
 	let path = exe_path.parent().expect("no parent!").join(filename);
-	let file = File::open(&path).unwrap_or_else(|err| panic!("{err}: couldn't open map {path:?}!"));
+	let file = File::open(&path).map_err(|err| format!("couldn't open map {path:?}: {err}"))?;
 	let mut reader = BufReader::new(file);
 	let mut buf = vec![0u8; 128];
 
@@ -377,7 +408,7 @@ pub fn load_bsp(filename: &str) -> Bsp
 	let leafs = read_leafs(&header.leaves, &mut reader, &mut buf);
 	let nodes = read_nodes(&header.nodes, &mut reader, &mut buf);
 	let clip_nodes = read_clip_nodes(&header.clip_nodes, &mut reader, &mut buf);
-	let entities = read_entities(&header.entities, &mut reader, &mut buf);
+	let entities = read_entities(&header.entities, &mut reader, &path)?;
 	let submodels = read_submodels(&header.models, &mut reader, &mut buf);
 	let (texofs, used_textures) = build_used_textures(&surfaces, &textures, &tex_infos);
 
@@ -388,7 +419,7 @@ pub fn load_bsp(filename: &str) -> Bsp
 
 	let lightgrid = if let Some(bspx_header) = bspx_header { read_lightgrids(bspx_header, &mut reader) } else { None };
 
-	return Bsp {
+	return Ok(Bsp {
 		textures,
 		planes,
 		leafs,
@@ -407,7 +438,7 @@ pub fn load_bsp(filename: &str) -> Bsp
 		texofs,
 		used_textures,
 		lightgrid,
-	};
+	});
 }
 
 fn read_verts(header: &LumpHeader, reader: &mut BufReader<File>, buf: &mut Vec<u8>) -> Vec<Vector3>
@@ -568,14 +599,10 @@ fn read_planes(header: &LumpHeader, reader: &mut BufReader<File>, buf: &mut Vec<
 	for _ in 0..count
 	{
 		let normal = read_vec3(reader);
-		let bits = if normal.x < 0f32 { 1 << 0 } else { 0 } |
-			if normal.y < 0f32 { 1 << 1 } else { 0 } |
-			if normal.z < 0f32 { 1 << 2 } else { 0 };
-
 		let dist = read_f32(reader, buf);
 		let p_type = read_i32(reader, buf) as u8;
 
-		planes.push(Plane { normal, dist, p_type, sign: bits, _pad0: 0, _pad1: 0 });
+		planes.push(Plane::new(normal, dist, p_type));
 	}
 
 	return planes;
@@ -887,76 +914,65 @@ fn read_clip_nodes(header: &LumpHeader, reader: &mut BufReader<File>, buf: &mut
 	return nodes;
 }
 
-fn read_entities(header: &LumpHeader, reader: &mut BufReader<File>, buf: &mut Vec<u8>) -> Vec<Entity>
+// A mapname.ent next to the map replaces its entity lump, so entities can be changed without recompiling.
+// One that can't be read or parsed is skipped with a warning, the lump is still there to fall back on.
+//
+// The client loads maps the same way and predicts movement against the ladders it finds, so a .ent on the
+// server has to be handed out with the map. A client without the same one disagrees with the server about
+// where the ladders are and keeps getting corrected.
+fn read_entities(header: &LumpHeader, reader: &mut BufReader<File>, path: &Path) -> Result<Vec<Entity>, String>
 {
-	reader.seek(std::io::SeekFrom::Start(header.offset as u64))
-		.unwrap_or_else(|err| panic!("Invalid entities offset {:?}: {err}", header.offset));
-
-    #[derive(PartialEq, Debug)]
-    enum ParserState {
-        LookingForEntity,
-        LookingForKey,
-        InsideKey,
-        LookingForValue,
-        InsideValue,
-    }
-
-    let mut state = ParserState::LookingForEntity;
-    let mut entity = Entity { map: HashMap::new() };
-    let mut key = vec!();
-    let mut value = vec!();
-    let mut entities = vec!();
-
-    for i in 0..(header.size as usize) {
-        let byte = read_u8(reader, buf);
-
-        if state == ParserState::LookingForEntity {
-            if byte == '{' as u8 {
-                state = ParserState::LookingForKey;
-            } else if byte == '\0' as u8 {
-                assert!(i == (header.size - 1) as usize);
-            } else if !char::is_whitespace(byte.into()) {
-                panic!("Found unexpected character {:?} while outside entity", char::from(byte));
-            }
-        } else if state == ParserState::LookingForKey {
-            if byte == '}' as u8 {
-                entities.push(entity);
-                entity = Entity { map: HashMap::new() };
-                state = ParserState::LookingForEntity;
-            } else if byte == '\"' as u8 {
-                state = ParserState::InsideKey;
-            } else if !char::is_whitespace(byte.into()) {
-                panic!("Found unexpected character {:?} while looking for key", char::from(byte));
-            }
-        } else if state == ParserState::InsideKey {
-            if byte == '\"' as u8 {
-                state = ParserState::LookingForValue;
-            } else {
-                key.push(byte);
-            }
-        } else if state == ParserState::LookingForValue {
-            if byte == '\"' as u8 {
-                state = ParserState::InsideValue;
-            } else if !char::is_whitespace(byte.into()) {
-                panic!("Found unexpected character {:?} while looking for key", char::from(byte));
-            }
-        } else if state == ParserState::InsideValue {
-            if byte == '\"' as u8 {
-                let key_str = String::from_utf8(key).unwrap();
-                let value_str = String::from_utf8(value).unwrap();
-                entity.map.insert(key_str, value_str);
-
-                key = vec!();
-                value = vec!();
-
-                state = ParserState::LookingForKey;
-            } else {
-                value.push(byte);
-            }
-        }
-    }
-
-	return entities;
+	let ent_filename = path.with_extension("ent");
+	if ent_filename.exists()
+	{
+		let source = format!("{ent_filename:?}");
+		match std::fs::read(&ent_filename).map_err(|err| format!("couldn't read {source}: {err}")).and_then(|text| parse_entities(&text, &source))
+		{
+			Ok(entities) =>
+			{
+				println!("  entities from {source}");
+				return Ok(entities);
+			}
+			Err(err) => println!("WARNING: using the entity lump, {err}"),
+		}
+	}
+
+	let text = read_lump_bytes(header, reader).map_err(|err| format!("couldn't read entity lump at {:?}: {err}", header.offset))?;
+	return parse_entities(&text, "entity lump");
+}
+
+fn parse_entities(text: &[u8], source: &str) -> Result<Vec<Entity>, String>
+{
+	let entities = parse_ent(text).map_err(|err| format!("invalid {source} at {err}"))?;
+	for entity in &entities
+	{
+		let duplicates = entity.duplicate_keys();
+		if !duplicates.is_empty()
+		{
+			println!("WARNING: entity at line {} of {source} sets {} more than once, using the last value", entity.line, duplicates.join(", "));
+		}
+	}
+
+	return Ok(entities);
+}
+
+// The entity lump as compiled into the map, ignoring any .ent file next to it. For writing one out to start from.
+pub fn read_embedded_entities(path: &Path) -> std::io::Result<Vec<u8>>
+{
+	let mut reader = BufReader::new(File::open(path)?);
+	let mut buf = vec![0u8; 128];
+
+	let _version = reader.read_i32::<LittleEndian>()?;
+	let header = read_dir_entry(&mut reader, &mut buf);
+	return read_lump_bytes(&header, &mut reader);
+}
+
+fn read_lump_bytes(header: &LumpHeader, reader: &mut BufReader<File>) -> std::io::Result<Vec<u8>>
+{
+	reader.seek(std::io::SeekFrom::Start(header.offset as u64))?;
+	let mut bytes = vec![0u8; header.size as usize];
+	reader.read_exact(&mut bytes)?;
+	return Ok(bytes);
 }
 
 fn read_submodels(header: &LumpHeader, reader: &mut BufReader<File>, buf: &mut Vec<u8>) -> Vec<Model>
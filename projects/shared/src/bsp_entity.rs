@@ -1,39 +1,371 @@
 use crate::bsp::*;
+use crate::bsp_query::LadderVolume;
 use raylib::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
 use std::str::FromStr;
 use lazy_static::lazy_static;
 
 lazy_static! {
     static ref CLASSNAME_STR: String = "classname".to_string();
+    static ref MODEL_STR: String = "model".to_string();
 }
 
+// Accepted on any entity whatever its class says, along with compiler settings starting with an underscore
+static COMMON_KEYS: [(&str, KeyType); 3] = [("classname", KeyType::Str), ("model", KeyType::Str), ("origin", KeyType::Vec3)];
+
+const LADDER_CLASSNAME: &str = "func_ladder";
+const LADDER_TEXTURE_PREFIX: &str = "ladder";
+
+// Player collision is done against the clip hull, which is expanded by the player's size,
+// so ladder volumes have to be padded by the same amount
+const LADDER_PAD_XZ: f32 = 16f32;
+const LADDER_PAD_Y: f32 = 24f32;
+
 pub fn of_type<'a>(bsp: &'a Bsp, name: &str) -> impl Iterator<Item = &'a Entity> {
     return bsp.entities.iter().filter(move |entity| {
         if let Some(value) = entity.get(&CLASSNAME_STR) && value == name { true } else { false }
     });
 }
 
+// Collects climbable volumes from `func_ladder` brush entities and from any surface using a `ladder` texture
+pub fn ladder_volumes(bsp: &Bsp) -> Vec<LadderVolume> {
+    let mut volumes = vec!();
+
+    for entity in of_type(bsp, LADDER_CLASSNAME) {
+        if let Some(model) = entity.get_model(bsp) {
+            let (mins, maxs) = model.bounds();
+            volumes.push(padded_volume(mins, maxs));
+        } else {
+            println!("WARNING: {LADDER_CLASSNAME} without a valid brush model: {:?}", entity);
+        }
+    }
+
+    for surf in &bsp.surfs {
+        let tex_info = &bsp.tex_infos[surf.tex_info as usize];
+        let Some(texture) = bsp.textures.get(tex_info.tex_num as usize) else { continue; };
+
+        if texture.name.starts_with(LADDER_TEXTURE_PREFIX) {
+            volumes.push(padded_volume(surf.mins, surf.maxs));
+        }
+    }
+
+    return volumes;
+}
+
+fn padded_volume(mins: Vector3, maxs: Vector3) -> LadderVolume {
+    let mins = to_wld(mins);
+    let maxs = to_wld(maxs);
+    let pad = Vector3::new(LADDER_PAD_XZ, LADDER_PAD_Y, LADDER_PAD_XZ);
+    return LadderVolume { mins: mins.min(maxs) - pad, maxs: mins.max(maxs) + pad };
+}
+
 impl Entity {
-    pub fn get(&self, key: &String) -> Option<&String> {
-        return self.map.get(key);
+    // The last value wins for a key given twice, the way the engine spawns it
+    pub fn get(&self, key: &str) -> Option<&String> {
+        return self.pairs.iter().rev().find(|(k, _)| k == key).map(|(_, value)| value);
+    }
+
+    // Brush entities reference their submodel with a "*N" model key
+    pub fn get_model<'a>(&self, bsp: &'a Bsp) -> Option<&'a Model> {
+        let value = self.get(&MODEL_STR)?;
+        let index = usize::from_str(value.strip_prefix('*')?).ok()?;
+        return bsp.submodels.get(index);
+    }
+
+    pub fn classname(&self) -> &str {
+        return self.get("classname").map(String::as_str).unwrap_or_default();
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<&str, KeyError> {
+        return self.get(key).map(String::as_str).ok_or_else(|| KeyError::Missing(key.to_string()));
+    }
+
+    pub fn get_f32(&self, key: &str) -> Result<f32, KeyError> {
+        return self.get_typed(key, KeyType::Float, parse_f32);
+    }
+
+    pub fn get_i32(&self, key: &str) -> Result<i32, KeyError> {
+        return self.get_typed(key, KeyType::Int, |value| value.trim().parse().ok());
     }
 
-    pub fn get_vec3(&self, key: &String) -> Vector3 {
-        let value = self.get(key).unwrap();
-        let mut split = value.split_whitespace();
+    pub fn get_bool(&self, key: &str) -> Result<bool, KeyError> {
+        return self.get_typed(key, KeyType::Bool, parse_bool);
+    }
 
-        let xs = split.next();
-        let ys = split.next();
-        let zs = split.next();
+    // Positions come back in world space, same as everything else read from the map
+    pub fn get_vec3(&self, key: &str) -> Result<Vector3, KeyError> {
+        return self.get_typed(key, KeyType::Vec3, |value| parse_floats::<3>(value).map(|[x, y, z]| to_wld(Vector3::new(x, y, z))));
+    }
 
-        if xs == None || ys == None || zs == None {
-            panic!("Invalid vec3 format for key {:?} in entity: {:?}", key, value);
+    // Pitch, yaw and roll in degrees, from "angles" or else the yaw only "angle" where -1 is up and -2 is down
+    pub fn get_angles(&self) -> Result<Vector3, KeyError> {
+        if self.get("angles").is_some() {
+            return self.get_typed("angles", KeyType::Angles, parse_angles);
         }
 
-        let x = f32::from_str(xs.unwrap()).unwrap();
-        let y = f32::from_str(ys.unwrap()).unwrap();
-        let z = f32::from_str(zs.unwrap()).unwrap();
+        return self.get_typed("angle", KeyType::Angles, parse_angles);
+    }
+
+    pub fn get_color(&self, key: &str) -> Result<Color, KeyError> {
+        return self.get_typed(key, KeyType::Color, parse_color);
+    }
+
+    // No spawnflags key means no flags set
+    pub fn get_spawnflags(&self) -> Result<u32, KeyError> {
+        return optional(self.get_typed("spawnflags", KeyType::Flags, |value| value.trim().parse().ok())).map(Option::unwrap_or_default);
+    }
+
+    fn get_typed<T>(&self, key: &str, ty: KeyType, parse: impl Fn(&str) -> Option<T>) -> Result<T, KeyError> {
+        let value = self.get_str(key)?;
+        return parse(value).ok_or_else(|| KeyError::Invalid { key: key.to_string(), value: value.to_string(), expected: ty.expected() });
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum KeyError {
+    Missing(String),
+    Invalid { key: String, value: String, expected: &'static str },
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            KeyError::Missing(key) => write!(f, "missing key `{key}`"),
+            KeyError::Invalid { key, value, expected } => write!(f, "invalid value `{value}` for `{key}`, expected {expected}"),
+        };
+    }
+}
+
+// Treats a missing key as None, keeping the error for one that's there but malformed
+pub fn optional<T>(result: Result<T, KeyError>) -> Result<Option<T>, KeyError> {
+    return match result {
+        Ok(value) => Ok(Some(value)),
+        Err(KeyError::Missing(_)) => Ok(None),
+        Err(err) => Err(err),
+    };
+}
+
+fn parse_f32(value: &str) -> Option<f32> {
+    return value.trim().parse().ok();
+}
+
+fn parse_floats<const N: usize>(value: &str) -> Option<[f32; N]> {
+    let mut floats = [0f32; N];
+    let mut split = value.split_whitespace();
+    for float in &mut floats {
+        *float = parse_f32(split.next()?)?;
+    }
+
+    return split.next().is_none().then_some(floats);
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    return match value.trim() {
+        "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    };
+}
+
+fn parse_angles(value: &str) -> Option<Vector3> {
+    if let Some([pitch, yaw, roll]) = parse_floats::<3>(value) {
+        return Some(Vector3::new(pitch, yaw, roll));
+    }
+
+    let yaw = parse_f32(value)?;
+    if yaw == -1f32 {
+        return Some(Vector3::new(-90f32, 0f32, 0f32));
+    } else if yaw == -2f32 {
+        return Some(Vector3::new(90f32, 0f32, 0f32));
+    }
+
+    return Some(Vector3::new(0f32, yaw, 0f32));
+}
+
+// Either 0-255 or 0-1 per channel, the same guess the light compilers make. A fourth number is the light's
+// brightness and doesn't belong to the color.
+fn parse_color(value: &str) -> Option<Color> {
+    let channels: Vec<f32> = value.split_whitespace().map(parse_f32).collect::<Option<_>>()?;
+    if !(3..=4).contains(&channels.len()) || channels[..3].iter().any(|c| *c < 0f32) {
+        return None;
+    }
+
+    let scale = if channels[..3].iter().all(|c| *c <= 1f32) { 255f32 } else { 1f32 };
+    let channel = |c: f32| (c * scale).round().min(255f32) as u8;
+    return Some(Color::new(channel(channels[0]), channel(channels[1]), channel(channels[2]), 255));
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum KeyType {
+    Str,
+    Int,
+    Float,
+    Bool,
+    Vec3,
+    Angles,
+    Color,
+    Flags,
+    Choices(Vec<String>),
+}
+
+impl KeyType {
+    fn expected(&self) -> &'static str {
+        return match self {
+            KeyType::Str => "text",
+            KeyType::Int | KeyType::Flags => "a whole number",
+            KeyType::Float => "a number",
+            KeyType::Bool => "0 or 1",
+            KeyType::Vec3 => "three numbers",
+            KeyType::Angles => "an angle or three of them",
+            KeyType::Color => "three numbers for red, green and blue",
+            KeyType::Choices(_) => "one of the listed choices",
+        };
+    }
+
+    fn accepts(&self, value: &str) -> bool {
+        return match self {
+            KeyType::Str => true,
+            KeyType::Int => value.trim().parse::<i32>().is_ok(),
+            KeyType::Flags => value.trim().parse::<u32>().is_ok(),
+            KeyType::Float => parse_f32(value).is_some(),
+            KeyType::Bool => parse_bool(value).is_some(),
+            KeyType::Vec3 => parse_floats::<3>(value).is_some(),
+            KeyType::Angles => parse_angles(value).is_some(),
+            KeyType::Color => parse_color(value).is_some(),
+            KeyType::Choices(choices) => choices.iter().any(|choice| choice == value.trim()),
+        };
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum SchemaIssue {
+    UnknownClass { index: usize, classname: String },
+    UnknownKey { index: usize, classname: String, key: String },
+    BadValue { index: usize, classname: String, error: KeyError },
+}
+
+impl fmt::Display for SchemaIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            SchemaIssue::UnknownClass { index, classname } => write!(f, "entity {index}: unknown class `{classname}`"),
+            SchemaIssue::UnknownKey { index, classname, key } => write!(f, "entity {index} ({classname}): unknown key `{key}`"),
+            SchemaIssue::BadValue { index, classname, error } => write!(f, "entity {index} ({classname}): {error}"),
+        };
+    }
+}
+
+// Which keys each classname takes and what they hold, for checking every entity in a map at once.
+// Can be built in code or imported from a level editor's FGD file.
+#[derive(Default)]
+pub struct EntitySchema {
+    classes: HashMap<String, HashMap<String, KeyType>>,
+}
+
+impl EntitySchema {
+    pub fn new() -> EntitySchema {
+        return EntitySchema { classes: HashMap::new() };
+    }
+
+    // Adds to whatever the class already had
+    pub fn add_class(&mut self, classname: &str, keys: impl IntoIterator<Item = (String, KeyType)>) {
+        self.classes.entry(classname.to_string()).or_default().extend(keys);
+    }
+
+    pub fn has_class(&self, classname: &str) -> bool {
+        return self.classes.contains_key(classname);
+    }
+
+    // Everything wrong with the entities, in the order they're written. A key given twice is checked both times.
+    pub fn check(&self, entities: &[Entity]) -> Vec<SchemaIssue> {
+        let mut issues = vec!();
+
+        for (index, entity) in entities.iter().enumerate() {
+            let classname = entity.classname().to_string();
+            let Some(class) = self.classes.get(&classname) else {
+                issues.push(SchemaIssue::UnknownClass { index, classname });
+                continue;
+            };
+
+            for (key, value) in &entity.pairs {
+                let ty = class.get(key).or_else(|| COMMON_KEYS.iter().find(|(common, _)| *common == key.as_str()).map(|(_, ty)| ty));
+                match ty {
+                    Some(ty) if !ty.accepts(value) => {
+                        let error = KeyError::Invalid { key: key.clone(), value: value.clone(), expected: ty.expected() };
+                        issues.push(SchemaIssue::BadValue { index, classname: classname.clone(), error });
+                    }
+                    Some(_) => {}
+                    None if key.starts_with('_') => {}
+                    None => issues.push(SchemaIssue::UnknownKey { index, classname: classname.clone(), key: key.clone() }),
+                }
+            }
+        }
+
+        return issues;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(keys: &[(&str, &str)]) -> Entity {
+        return Entity { line: 0, pairs: keys.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect() };
+    }
+
+    #[test]
+    fn typed_getters() {
+        let e = entity(&[("wait", "0.5"), ("count", "-3"), ("on", "1"), ("spawnflags", "6"), ("origin", "1 2 3"), ("_color", "1 0.5 0"), ("light_color", "255 128 0 300")]);
+        assert_eq!(e.get_f32("wait"), Ok(0.5f32));
+        assert_eq!(e.get_i32("count"), Ok(-3));
+        assert_eq!(e.get_bool("on"), Ok(true));
+        assert_eq!(e.get_spawnflags(), Ok(6));
+        assert_eq!(e.get_vec3("origin"), Ok(to_wld(Vector3::new(1f32, 2f32, 3f32))));
+        assert_eq!(e.get_color("_color"), Ok(Color::new(255, 128, 0, 255)));
+        assert_eq!(e.get_color("light_color"), Ok(Color::new(255, 128, 0, 255)));
+    }
+
+    #[test]
+    fn bad_or_missing_keys_are_errors() {
+        let e = entity(&[("wait", "soon"), ("origin", "1 2"), ("on", "yes")]);
+        assert_eq!(e.get_f32("wait"), Err(KeyError::Invalid { key: "wait".to_string(), value: "soon".to_string(), expected: "a number" }));
+        assert!(e.get_vec3("origin").is_err());
+        assert!(e.get_bool("on").is_err());
+        assert_eq!(e.get_i32("count"), Err(KeyError::Missing("count".to_string())));
+
+        assert_eq!(optional(e.get_i32("count")), Ok(None));
+        assert!(optional(e.get_f32("wait")).is_err());
+        assert_eq!(entity(&[]).get_spawnflags(), Ok(0));
+    }
+
+    #[test]
+    fn angles_from_either_key() {
+        assert_eq!(entity(&[("angle", "90")]).get_angles(), Ok(Vector3::new(0f32, 90f32, 0f32)));
+        assert_eq!(entity(&[("angle", "-1")]).get_angles(), Ok(Vector3::new(-90f32, 0f32, 0f32)));
+        assert_eq!(entity(&[("angle", "90"), ("angles", "10 20 30")]).get_angles(), Ok(Vector3::new(10f32, 20f32, 30f32)));
+        assert!(entity(&[]).get_angles().is_err());
+    }
+
+    #[test]
+    fn schema_reports_the_whole_map() {
+        let mut schema = EntitySchema::new();
+        schema.add_class("worldspawn", [("message".to_string(), KeyType::Str)]);
+        schema.add_class("light", [("light".to_string(), KeyType::Int), ("style".to_string(), KeyType::Choices(vec!["0".to_string(), "1".to_string()]))]);
+
+        let entities = [
+            entity(&[("classname", "worldspawn"), ("message", "Test"), ("_sunlight", "100")]),
+            entity(&[("classname", "light"), ("origin", "0 0 0"), ("light", "bright"), ("style", "1"), ("colour", "red")]),
+            entity(&[("classname", "light"), ("origin", "0 0"), ("style", "7")]),
+            entity(&[("classname", "monster_dog")]),
+        ];
 
-        return to_wld(Vector3::new(x, y, z));
+        let issues: Vec<String> = schema.check(&entities).iter().map(|issue| issue.to_string()).collect();
+        assert_eq!(issues, vec![
+            "entity 1 (light): invalid value `bright` for `light`, expected a whole number",
+            "entity 1 (light): unknown key `colour`",
+            "entity 2 (light): invalid value `0 0` for `origin`, expected three numbers",
+            "entity 2 (light): invalid value `7` for `style`, expected one of the listed choices",
+            "entity 3: unknown class `monster_dog`",
+        ]);
     }
 }
@@ -1,6 +1,7 @@
 use raylib::prelude::*;
 use enumset::*;
 use crate::bsp::*;
+use crate::bsp_entity;
 use lazy_static::lazy_static;
 
 lazy_static! {
@@ -27,14 +28,33 @@ struct IntersectData {
 }
 
 pub struct BspQueryNode {
-    plane_index: usize,
-    children: [i32;2]
+    pub plane_index: usize,
+    pub children: [i32;2]
+}
+
+// Axis aligned volume in world space that the player can climb while inside of
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct LadderVolume {
+	pub mins: Vector3,
+	pub maxs: Vector3
+}
+
+impl LadderVolume {
+	pub fn contains(&self, point: Vector3) -> bool {
+		return point.x >= self.mins.x && point.x <= self.maxs.x &&
+			point.y >= self.mins.y && point.y <= self.maxs.y &&
+			point.z >= self.mins.z && point.z <= self.maxs.z;
+	}
 }
 
 pub trait BspQuery<'a> {
     fn get_node(&self, idx: usize) -> BspQueryNode;
     fn get_plane(&self, idx: usize) -> &Plane;
     fn get_contents(&self, idx: i32) -> LeafContents;
+
+    fn get_ladders(&self) -> &[LadderVolume] {
+        return &[];
+    }
 }
 
 pub struct BspVisQuery<'a> {
@@ -64,12 +84,14 @@ impl<'a> BspQuery<'a> for BspVisQuery<'a> {
 }
 
 pub struct BspClipQuery<'a> {
-    bsp: &'a Bsp
+    bsp: &'a Bsp,
+    ladders: Vec<LadderVolume>
 }
 
 impl<'a> BspClipQuery<'a> {
     pub fn new(bsp: &'a Bsp) -> BspClipQuery<'a> {
-        return BspClipQuery { bsp };
+        let ladders = bsp_entity::ladder_volumes(bsp);
+        return BspClipQuery { bsp, ladders };
     }
 }
 
@@ -87,6 +109,43 @@ impl<'a> BspQuery<'a> for BspClipQuery<'a> {
     fn get_contents(&self, idx: i32) -> LeafContents {
         return LeafContents::from_repr(idx).unwrap();
     }
+
+    fn get_ladders(&self) -> &[LadderVolume] {
+        return &self.ladders;
+    }
+}
+
+// Empty space above y = 0 and solid below it, for tests that move a player without loading a map
+#[cfg(any(test, feature = "test-util"))]
+pub struct FloorQuery {
+    planes: Vec<Plane>,
+    ladders: Vec<LadderVolume>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl FloorQuery {
+    pub fn new(ladders: Vec<LadderVolume>) -> FloorQuery {
+        return FloorQuery { planes: vec![Plane::new(Vector3::new(0f32, 0f32, 1f32), 0f32, 2)], ladders };
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl<'a> BspQuery<'a> for FloorQuery {
+    fn get_node(&self, _idx: usize) -> BspQueryNode {
+        return BspQueryNode { plane_index: 0, children: [-1, -2] };
+    }
+
+    fn get_plane(&self, idx: usize) -> &Plane {
+        return &self.planes[idx];
+    }
+
+    fn get_contents(&self, idx: i32) -> LeafContents {
+        return LeafContents::from_repr(idx).unwrap();
+    }
+
+    fn get_ladders(&self) -> &[LadderVolume] {
+        return &self.ladders;
+    }
 }
 
 pub fn point_intersect<'a>(bsp: &'a impl BspQuery<'a>, point: Vector3) -> LeafContents {
@@ -254,6 +313,104 @@ fn ray_intersect_recursive<'a>(bsp: &'a impl BspQuery<'a>, point: Vector3, dir:
     }
 }
 
+// Distance along a ray to where it enters an axis aligned box, or None if it misses or the box is further than `dist`.
+// A ray starting inside the box hits it immediately.
+pub fn ray_box_intersect(point: Vector3, dir: Vector3, dist: f32, mins: Vector3, maxs: Vector3) -> Option<f32> {
+    let mut near = 0f32;
+    let mut far = dist;
+
+    for (p, d, min, max) in [(point.x, dir.x, mins.x, maxs.x), (point.y, dir.y, mins.y, maxs.y), (point.z, dir.z, mins.z, maxs.z)] {
+        if d.abs() < 0.0001f32 {
+            if p < min || p > max {
+                return None;
+            }
+            continue;
+        }
+
+        let t1 = (min - p) / d;
+        let t2 = (max - p) / d;
+        near = near.max(t1.min(t2));
+        far = far.min(t1.max(t2));
+
+        if near > far {
+            return None;
+        }
+    }
+
+    return Some(near);
+}
+
+// Index into `Bsp::leafs` of the leaf containing a world space point
+pub fn point_leaf(bsp: &Bsp, point: Vector3) -> usize {
+    let point = to_bsp(point);
+    let mut idx = 0i32;
+
+    while idx >= 0 {
+        let node = &bsp.nodes[idx as usize];
+        let plane = &bsp.planes[node.plane_index as usize];
+        let d = point.dot(plane.normal) - plane.dist;
+        idx = if d > 0f32 { node.children[0] } else { node.children[1] };
+    }
+
+    return -(idx + 1) as usize;
+}
+
+// Potentially visible set of a leaf, decompressed from the run-length encoded vis lump
+pub struct Pvs {
+    bits: Option<Vec<u8>> // None when everything is visible
+}
+
+impl Pvs {
+    pub fn for_point(bsp: &Bsp, point: Vector3) -> Pvs {
+        return Pvs::for_leaf(bsp, point_leaf(bsp, point));
+    }
+
+    pub fn for_leaf(bsp: &Bsp, leaf: usize) -> Pvs {
+        // Leaf 0 is the shared solid leaf, and maps without vis can see everything
+        let visofs = bsp.leafs[leaf].visofs;
+        if leaf == 0 || visofs < 0 || bsp.vis_data.is_empty() || bsp.submodels.is_empty() {
+            return Pvs { bits: None };
+        }
+
+        let row = (bsp.submodels[0].visleafs() as usize + 7) / 8;
+        let mut bits = Vec::with_capacity(row);
+        let mut i = visofs as usize;
+
+        // Zero bytes are followed by a count of how many zero bytes they stand for
+        while bits.len() < row && i < bsp.vis_data.len() {
+            let byte = bsp.vis_data[i];
+            i += 1;
+
+            if byte != 0 {
+                bits.push(byte);
+            } else {
+                let count = bsp.vis_data.get(i).copied().unwrap_or(0) as usize;
+                i += 1;
+                bits.resize((bits.len() + count).min(row), 0);
+            }
+        }
+
+        bits.resize(row, 0);
+        return Pvs { bits: Some(bits) };
+    }
+
+    pub fn contains_leaf(&self, leaf: usize) -> bool {
+        let Some(bits) = &self.bits else { return true; };
+
+        // Things stuck in solid have no visibility info of their own, so err on the side of sending them
+        if leaf == 0 {
+            return true;
+        }
+
+        let bit = leaf - 1;
+        return bits.get(bit >> 3).is_some_and(|byte| byte & (1 << (bit & 7)) != 0);
+    }
+
+    pub fn contains_point(&self, bsp: &Bsp, point: Vector3) -> bool {
+        return self.bits.is_none() || self.contains_leaf(point_leaf(bsp, point));
+    }
+}
+
 /*
 pub fn get_leafs_containing_sphere(bsp: &Bsp, point: Vector3, radius: f32) {
 }
@@ -0,0 +1,132 @@
+// Longest chat message anyone gets to send, in characters. Longer ones are cut short rather than dropped.
+pub const MAX_CHAT_LEN: usize = 128;
+pub const MAX_NAME_LEN: usize = 32;
+
+// Cleans up a chat line before it's relayed or shown. Control characters go, so nobody can mess with
+// other players' feeds, and messages with nothing left to say are dropped.
+pub fn sanitize(text: &str) -> Option<String> {
+    return clean(text, MAX_CHAT_LEN);
+}
+
+// Same cleanup for player names, which show up in front of every line they send
+pub fn sanitize_name(name: &str) -> String {
+    return clean(name, MAX_NAME_LEN).unwrap_or("player".to_string());
+}
+
+fn clean(text: &str, max_len: usize) -> Option<String> {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    let text = text.trim();
+
+    if text.is_empty() {
+        return None;
+    }
+
+    return Some(text.chars().take(max_len).collect::<String>().trim_end().to_string());
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FloodLimit {
+    pub burst: u32,        // Messages that can be sent back to back
+    pub refill_ticks: u32, // Ticks it takes to earn back one message
+    pub mute_ticks: u32,   // How long someone who goes over the limit is ignored for
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Muted {
+    pub ticks_left: u32,
+}
+
+// Per sender flood protection. Everyone starts with a full burst, and going over it mutes them for a while.
+// Works in ticks so the server behaves the same however long its ticks actually take.
+pub struct ChatFlood {
+    limit: FloodLimit,
+    allowance: u32,
+    refilled_at: u32,
+    muted_until: u32,
+}
+
+impl ChatFlood {
+    pub fn new(limit: FloodLimit) -> ChatFlood {
+        return ChatFlood { limit, allowance: limit.burst, refilled_at: 0, muted_until: 0 };
+    }
+
+    // Call for every message the sender tries to send
+    pub fn check(&mut self, tick: u32) -> Result<(), Muted> {
+        if tick < self.muted_until {
+            return Err(Muted { ticks_left: self.muted_until - tick });
+        }
+
+        if self.allowance >= self.limit.burst {
+            // Time spent at a full allowance doesn't count towards the next refill
+            self.refilled_at = tick;
+        } else if self.limit.refill_ticks > 0 {
+            let earned = tick.saturating_sub(self.refilled_at) / self.limit.refill_ticks;
+            self.allowance = (self.allowance + earned).min(self.limit.burst);
+            self.refilled_at += earned * self.limit.refill_ticks;
+        }
+
+        if self.allowance == 0 {
+            // Once the mute is over they start again with a clean slate
+            self.muted_until = tick + self.limit.mute_ticks;
+            self.allowance = self.limit.burst;
+            return Err(Muted { ticks_left: self.limit.mute_ticks });
+        }
+
+        self.allowance -= 1;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: FloodLimit = FloodLimit { burst: 3, refill_ticks: 10, mute_ticks: 50 };
+
+    #[test]
+    fn sanitize_strips_control_characters() {
+        assert_eq!(sanitize("  hi\x07 there\n"), Some("hi there".to_string()));
+        assert_eq!(sanitize(" \t\r\n"), None);
+        assert_eq!(sanitize(""), None);
+    }
+
+    #[test]
+    fn sanitize_truncates_long_messages() {
+        let long = "é".repeat(MAX_CHAT_LEN * 2);
+        assert_eq!(sanitize(&long).unwrap().chars().count(), MAX_CHAT_LEN);
+    }
+
+    #[test]
+    fn empty_names_get_a_default() {
+        assert_eq!(sanitize_name("\n"), "player");
+        assert_eq!(sanitize_name(&"x".repeat(100)).len(), MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn flood_allows_a_burst_then_mutes() {
+        let mut flood = ChatFlood::new(LIMIT);
+
+        for _ in 0..LIMIT.burst {
+            assert_eq!(flood.check(100), Ok(()));
+        }
+
+        assert_eq!(flood.check(100), Err(Muted { ticks_left: 50 }));
+        assert_eq!(flood.check(120), Err(Muted { ticks_left: 30 }));
+        assert_eq!(flood.check(150), Ok(()));
+    }
+
+    #[test]
+    fn flood_allowance_refills_over_time() {
+        let mut flood = ChatFlood::new(LIMIT);
+
+        for tick in 0..20 {
+            assert_eq!(flood.check(tick * LIMIT.refill_ticks), Ok(()));
+        }
+
+        // Sitting idle doesn't bank more than a burst
+        for _ in 0..LIMIT.burst {
+            assert_eq!(flood.check(10000), Ok(()));
+        }
+        assert!(flood.check(10000).is_err());
+    }
+}
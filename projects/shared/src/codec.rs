@@ -0,0 +1,433 @@
+use enumset::*;
+use raylib::prelude::*;
+use std::fmt;
+
+pub use shared_derive::{Decode, Encode};
+
+// Binary encoding used for everything sent over the network. Integers wider than a byte are
+// varints (zigzag encoded when signed), floats are little endian, and strings and vecs are
+// prefixed with their length.
+pub trait Encode {
+    fn encode(&self, writer: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+}
+
+#[derive(PartialEq, Debug)]
+pub enum DecodeError {
+    UnexpectedEof { offset: usize, needed: usize, remaining: usize },
+    VarintOverflow { offset: usize },
+    InvalidUtf8 { offset: usize },
+    InvalidDiscriminant { type_name: &'static str, value: u64 },
+    InvalidValue { type_name: &'static str, offset: usize },
+    LengthTooLarge { offset: usize, len: u64, remaining: usize },
+    TooManyItems { offset: usize, len: u64, max_len: usize },
+    TrailingBytes { offset: usize, remaining: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof { offset, needed, remaining } =>
+                write!(f, "unexpected end of message at byte {offset}: needed {needed} bytes, {remaining} left"),
+            DecodeError::VarintOverflow { offset } =>
+                write!(f, "varint at byte {offset} overflows 64 bits"),
+            DecodeError::InvalidUtf8 { offset } =>
+                write!(f, "string at byte {offset} is not valid utf-8"),
+            DecodeError::InvalidDiscriminant { type_name, value } =>
+                write!(f, "{value} is not a valid variant of {type_name}"),
+            DecodeError::InvalidValue { type_name, offset } =>
+                write!(f, "invalid {type_name} at byte {offset}"),
+            DecodeError::LengthTooLarge { offset, len, remaining } =>
+                write!(f, "length {len} at byte {offset} is longer than the {remaining} bytes left"),
+            DecodeError::TooManyItems { offset, len, max_len } =>
+                write!(f, "{len} items at byte {offset}, at most {max_len} are allowed"),
+            DecodeError::TrailingBytes { offset, remaining } =>
+                write!(f, "{remaining} unread bytes after the message ended at byte {offset}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        return Reader { bytes, offset: 0 };
+    }
+
+    pub fn offset(&self) -> usize {
+        return self.offset;
+    }
+
+    pub fn remaining(&self) -> usize {
+        return self.bytes.len() - self.offset;
+    }
+
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], DecodeError> {
+        if count > self.remaining() {
+            return Err(DecodeError::UnexpectedEof { offset: self.offset, needed: count, remaining: self.remaining() });
+        }
+
+        let bytes = &self.bytes[self.offset..self.offset + count];
+        self.offset += count;
+        return Ok(bytes);
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        return Ok(self.read_bytes(N)?.try_into().unwrap());
+    }
+
+    pub fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let start = self.offset;
+        let mut value = 0u64;
+
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_array::<1>()?[0];
+            let bits = (byte & 0x7f) as u64;
+
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::VarintOverflow { offset: start });
+            }
+
+            value |= bits << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        return Err(DecodeError::VarintOverflow { offset: start });
+    }
+
+    // Reads a length prefix, rejecting lengths that can't possibly fit in the rest of the message
+    pub fn read_len(&mut self) -> Result<usize, DecodeError> {
+        let offset = self.offset;
+        let len = self.read_varint()?;
+
+        if len > self.remaining() as u64 {
+            return Err(DecodeError::LengthTooLarge { offset, len, remaining: self.remaining() });
+        }
+
+        return Ok(len as usize);
+    }
+
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        if self.remaining() > 0 {
+            return Err(DecodeError::TrailingBytes { offset: self.offset, remaining: self.remaining() });
+        }
+
+        return Ok(());
+    }
+}
+
+pub fn write_varint(writer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        writer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+
+    writer.push(value as u8);
+}
+
+pub fn to_bytes(value: &impl Encode) -> Vec<u8> {
+    let mut writer = Vec::new();
+    value.encode(&mut writer);
+    return writer;
+}
+
+// Decodes a whole buffer, treating leftover bytes as an error
+pub fn from_bytes<T: Decode>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let mut reader = Reader::new(bytes);
+    let value = T::decode(&mut reader)?;
+    reader.finish()?;
+    return Ok(value);
+}
+
+impl Encode for u8 {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        writer.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        return Ok(reader.read_array::<1>()?[0]);
+    }
+}
+
+impl Encode for i8 {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        writer.push(*self as u8);
+    }
+}
+
+impl Decode for i8 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        return Ok(reader.read_array::<1>()?[0] as i8);
+    }
+}
+
+macro_rules! impl_unsigned_varint {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, writer: &mut Vec<u8>) {
+                write_varint(writer, *self as u64);
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+                let offset = reader.offset();
+                let value = reader.read_varint()?;
+                return <$t>::try_from(value).map_err(|_| DecodeError::InvalidValue { type_name: stringify!($t), offset });
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_signed_varint {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, writer: &mut Vec<u8>) {
+                let value = *self as i64;
+                write_varint(writer, ((value << 1) ^ (value >> 63)) as u64);
+            }
+        }
+
+        impl Decode for $t {
+            fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+                let offset = reader.offset();
+                let zigzag = reader.read_varint()?;
+                let value = ((zigzag >> 1) as i64) ^ -((zigzag & 1) as i64);
+                return <$t>::try_from(value).map_err(|_| DecodeError::InvalidValue { type_name: stringify!($t), offset });
+            }
+        }
+    )*};
+}
+
+impl_unsigned_varint!(u16, u32, u64);
+impl_signed_varint!(i16, i32, i64);
+
+impl Encode for bool {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        writer.push(*self as u8);
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let offset = reader.offset();
+        return match u8::decode(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::InvalidValue { type_name: "bool", offset }),
+        };
+    }
+}
+
+impl Encode for f32 {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        writer.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl Decode for f32 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        return Ok(f32::from_le_bytes(reader.read_array::<4>()?));
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        write_varint(writer, self.len() as u64);
+        writer.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let len = reader.read_len()?;
+        let offset = reader.offset();
+        let bytes = reader.read_bytes(len)?;
+        return String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8 { offset });
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        write_varint(writer, self.len() as u64);
+        for item in self {
+            item.encode(writer);
+        }
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        // Every element takes at least a byte, so the length can't exceed what's left
+        let len = reader.read_len()?;
+        let mut vec = Vec::with_capacity(len);
+        for _ in 0..len {
+            vec.push(T::decode(reader)?);
+        }
+        return Ok(vec);
+    }
+}
+
+// For `#[codec(max_len = N)]` fields. Only the last items are sent when there are too many, and a peer
+// sending more than that is refused before any of them are decoded.
+pub fn encode_bounded<T: Encode>(items: &[T], max_len: usize, writer: &mut Vec<u8>) {
+    let items = &items[items.len().saturating_sub(max_len)..];
+    write_varint(writer, items.len() as u64);
+    for item in items {
+        item.encode(writer);
+    }
+}
+
+pub fn decode_bounded<T: Decode>(reader: &mut Reader, max_len: usize) -> Result<Vec<T>, DecodeError> {
+    let offset = reader.offset();
+    let len = reader.read_varint()?;
+    if len > max_len as u64 {
+        return Err(DecodeError::TooManyItems { offset, len, max_len });
+    }
+    if len > reader.remaining() as u64 {
+        return Err(DecodeError::LengthTooLarge { offset, len, remaining: reader.remaining() });
+    }
+
+    let mut vec = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        vec.push(T::decode(reader)?);
+    }
+    return Ok(vec);
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                writer.push(1);
+                value.encode(writer);
+            }
+            None => writer.push(0),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        return match bool::decode(reader)? {
+            true => Ok(Some(T::decode(reader)?)),
+            false => Ok(None),
+        };
+    }
+}
+
+impl<T: Encode, const N: usize> Encode for [T; N] {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        for item in self {
+            item.encode(writer);
+        }
+    }
+}
+
+impl<T: Decode, const N: usize> Decode for [T; N] {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let mut items = Vec::with_capacity(N);
+        for _ in 0..N {
+            items.push(T::decode(reader)?);
+        }
+        return Ok(items.try_into().unwrap_or_else(|_| unreachable!()));
+    }
+}
+
+impl Encode for Vector3 {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        self.x.encode(writer);
+        self.y.encode(writer);
+        self.z.encode(writer);
+    }
+}
+
+impl Decode for Vector3 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        return Ok(Vector3::new(f32::decode(reader)?, f32::decode(reader)?, f32::decode(reader)?));
+    }
+}
+
+impl<T: EnumSetType> Encode for EnumSet<T> {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        write_varint(writer, self.as_u64());
+    }
+}
+
+impl<T: EnumSetType> Decode for EnumSet<T> {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let offset = reader.offset();
+        let bits = reader.read_varint()?;
+        return EnumSet::try_from_u64(bits).ok_or(DecodeError::InvalidValue { type_name: "EnumSet", offset });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Encode, Decode, PartialEq, Debug)]
+    struct Inner {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    #[derive(Encode, Decode, PartialEq, Debug)]
+    enum Outer {
+        Empty,
+        Tuple(i32, Option<Inner>),
+        Named { id: u64, inner: Inner, values: [i16; 3] },
+    }
+
+    fn round_trip<T: Encode + Decode + PartialEq + fmt::Debug>(value: T) {
+        let bytes = to_bytes(&value);
+        assert_eq!(from_bytes::<T>(&bytes), Ok(value));
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0u64, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            round_trip(value);
+        }
+        for value in [0i64, -1, 1, -64, 64, i64::MIN, i64::MAX] {
+            round_trip(value);
+        }
+
+        assert_eq!(to_bytes(&127u32).len(), 1);
+        assert_eq!(to_bytes(&128u32).len(), 2);
+        assert_eq!(to_bytes(&-1i32).len(), 1);
+    }
+
+    #[test]
+    fn derived_types_round_trip() {
+        let inner = Inner { name: "first".to_string(), tags: vec!["a".to_string(), "bc".to_string()] };
+        round_trip(Outer::Empty);
+        round_trip(Outer::Tuple(-5, None));
+        round_trip(Outer::Tuple(5, Some(Inner { name: "".to_string(), tags: vec!() })));
+        round_trip(Outer::Named { id: 99, inner, values: [-1, 0, 1] });
+    }
+
+    #[test]
+    fn errors_are_descriptive() {
+        assert_eq!(from_bytes::<Outer>(&[7]), Err(DecodeError::InvalidDiscriminant { type_name: "Outer", value: 7 }));
+        assert_eq!(from_bytes::<String>(&[5, b'a']), Err(DecodeError::LengthTooLarge { offset: 0, len: 5, remaining: 1 }));
+        assert_eq!(from_bytes::<String>(&[1, 0xff]), Err(DecodeError::InvalidUtf8 { offset: 1 }));
+        assert_eq!(from_bytes::<f32>(&[0, 0]), Err(DecodeError::UnexpectedEof { offset: 0, needed: 4, remaining: 2 }));
+        assert_eq!(from_bytes::<u8>(&[1, 2]), Err(DecodeError::TrailingBytes { offset: 1, remaining: 1 }));
+        assert_eq!(from_bytes::<u64>(&[0xff; 11]), Err(DecodeError::VarintOverflow { offset: 0 }));
+        assert_eq!(from_bytes::<u16>(&[0x80, 0x80, 0x04]), Err(DecodeError::InvalidValue { type_name: "u16", offset: 0 }));
+    }
+}
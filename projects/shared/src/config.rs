@@ -0,0 +1,240 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+// Startup settings for either binary, filled in from a config file and then the command line
+pub trait Config: Default {
+    // Applies one setting, returning why the value was refused
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String>;
+
+    // Checks the settings as a whole once everything has been applied
+    fn validate(&self) -> Result<(), String>;
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Setting {
+    pub key: String,
+    pub value: String,
+    pub line: Option<usize>, // Only for settings read from a file
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ConfigError {
+    pub source: String, // File the error is in, or the command line
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self.line {
+            Some(line) => write!(f, "{}:{line}: {}", self.source, self.message),
+            None => write!(f, "{}: {}", self.source, self.message),
+        };
+    }
+}
+
+const COMMAND_LINE: &str = "command line";
+
+// Reads a flat config file, one setting per line. Both TOML style `key = "value"` and .cfg style `key value`
+// lines are accepted, with `#` or `//` comments. There are no sections, each binary has a file of its own.
+pub fn parse_file(text: &str, source: &str) -> Result<Vec<Setting>, ConfigError> {
+    let mut settings = vec!();
+
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let error = |message: String| ConfigError { source: source.to_string(), line: Some(line_no), message };
+
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            return Err(error("sections aren't supported".to_string()));
+        }
+
+        // The key runs up to the first `=` or space, whichever style the line is in
+        let (key, rest) = line.split_at(line.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(line.len()));
+        let rest = rest.trim_start();
+        let value = rest.strip_prefix('=').unwrap_or(rest).trim();
+
+        if key.is_empty() {
+            return Err(error(format!("expected `key = value`, got `{line}`")));
+        }
+
+        let value = unquote(value).map_err(error)?;
+        settings.push(Setting { key: key.to_string(), value, line: Some(line_no) });
+    }
+
+    return Ok(settings);
+}
+
+// Comment markers inside quoted values don't count
+fn strip_comment(line: &str) -> &str {
+    let mut in_quotes = false;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '#' if !in_quotes => return &line[..i],
+            '/' if !in_quotes && line[i..].starts_with("//") => return &line[..i],
+            _ => {}
+        }
+    }
+
+    return line;
+}
+
+fn unquote(value: &str) -> Result<String, String> {
+    let Some(inner) = value.strip_prefix('"') else {
+        return Ok(value.to_string());
+    };
+
+    return match inner.strip_suffix('"') {
+        Some(inner) if !inner.contains('"') => Ok(inner.to_string()),
+        _ => Err(format!("badly quoted value {value}")),
+    };
+}
+
+// Splits `--key value` and `--key=value` arguments, skipping the program name. A key with no value is a
+// switch and gets "true". `--config <path>` is pulled out separately since it says where the rest comes from.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<(Option<String>, Vec<Setting>), ConfigError> {
+    let mut args = args.into_iter().skip(1).peekable();
+    let mut config_path = None;
+    let mut settings = vec!();
+
+    while let Some(arg) = args.next() {
+        let Some(arg) = arg.strip_prefix("--") else {
+            return Err(ConfigError { source: COMMAND_LINE.to_string(), line: None, message: format!("unexpected argument `{arg}`") });
+        };
+
+        let (key, value) = match arg.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => match args.next_if(|next| !next.starts_with("--")) {
+                Some(value) => (arg.to_string(), value),
+                None => (arg.to_string(), "true".to_string()),
+            },
+        };
+
+        if key == "config" {
+            config_path = Some(value);
+        } else {
+            settings.push(Setting { key, value, line: None });
+        }
+    }
+
+    return Ok((config_path, settings));
+}
+
+// Builds a config from the defaults, then the config file, then the command line, each overriding the last.
+// The default file is optional, one named with `--config` has to exist.
+pub fn load<C: Config>(args: impl IntoIterator<Item = String>, default_path: &str) -> Result<C, ConfigError> {
+    let (config_path, args) = parse_args(args)?;
+    let mut config = C::default();
+
+    let path = config_path.clone().unwrap_or(default_path.to_string());
+    if config_path.is_some() || Path::new(&path).is_file() {
+        let text = std::fs::read_to_string(&path)
+            .map_err(|err| ConfigError { source: path.clone(), line: None, message: err.to_string() })?;
+        apply(&mut config, &parse_file(&text, &path)?, &path)?;
+    }
+
+    apply(&mut config, &args, COMMAND_LINE)?;
+
+    config.validate().map_err(|message| ConfigError { source: "config".to_string(), line: None, message })?;
+    return Ok(config);
+}
+
+pub fn apply<C: Config>(config: &mut C, settings: &[Setting], source: &str) -> Result<(), ConfigError> {
+    for setting in settings {
+        config.set(&setting.key, &setting.value)
+            .map_err(|message| ConfigError { source: source.to_string(), line: setting.line, message })?;
+    }
+
+    return Ok(());
+}
+
+// Parses a value for `Config::set`, with an error that says which setting it was for
+pub fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    return value.parse().map_err(|_| format!("invalid value `{value}` for {key}"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default, Debug)]
+    struct TestConfig {
+        port: u16,
+        name: String,
+        fullscreen: bool,
+    }
+
+    impl Config for TestConfig {
+        fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+            match key {
+                "port" => self.port = parse_value(key, value)?,
+                "name" => self.name = value.to_string(),
+                "fullscreen" => self.fullscreen = parse_value(key, value)?,
+                _ => return Err(format!("unknown setting `{key}`")),
+            }
+            return Ok(());
+        }
+
+        fn validate(&self) -> Result<(), String> {
+            return if self.port == 0 { Err("port can't be 0".to_string()) } else { Ok(()) };
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        return std::iter::once("game").chain(args.iter().copied()).map(str::to_string).collect();
+    }
+
+    #[test]
+    fn parses_toml_and_cfg_lines() {
+        let text = "# comment\nport = 27000\nname \"two words\" // trailing\n\n  fullscreen=true\nname = \"a#b\"";
+        let keys: Vec<(String, String, Option<usize>)> = parse_file(text, "test.cfg").unwrap().into_iter().map(|s| (s.key, s.value, s.line)).collect();
+
+        assert_eq!(keys, vec![
+            ("port".to_string(), "27000".to_string(), Some(2)),
+            ("name".to_string(), "two words".to_string(), Some(3)),
+            ("fullscreen".to_string(), "true".to_string(), Some(5)),
+            ("name".to_string(), "a#b".to_string(), Some(6)),
+        ]);
+    }
+
+    #[test]
+    fn file_errors_have_line_numbers() {
+        let err = parse_file("port = 1\n[server]\n", "test.toml").unwrap_err();
+        assert_eq!(err.to_string(), "test.toml:2: sections aren't supported");
+
+        let err = parse_file("name = \"open\n", "test.toml").unwrap_err();
+        assert_eq!(err.line, Some(1));
+
+        let mut config = TestConfig::default();
+        let err = apply(&mut config, &parse_file("\nport = many", "test.toml").unwrap(), "test.toml").unwrap_err();
+        assert_eq!(err.to_string(), "test.toml:2: invalid value `many` for port");
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let (path, settings) = parse_args(args(&["--port", "5", "--fullscreen", "--name=x", "--config", "a.cfg"])).unwrap();
+        assert_eq!(path, Some("a.cfg".to_string()));
+
+        let keys: Vec<(&str, &str)> = settings.iter().map(|s| (s.key.as_str(), s.value.as_str())).collect();
+        assert_eq!(keys, vec![("port", "5"), ("fullscreen", "true"), ("name", "x")]);
+
+        assert!(parse_args(args(&["port"])).is_err());
+    }
+
+    #[test]
+    fn command_line_overrides_defaults_and_is_validated() {
+        let config: TestConfig = load(args(&["--port", "9", "--name", "srv"]), "does-not-exist.cfg").unwrap();
+        assert_eq!((config.port, config.name.as_str(), config.fullscreen), (9, "srv", false));
+
+        assert!(load::<TestConfig>(args(&["--name", "srv"]), "does-not-exist.cfg").is_err());
+        assert!(load::<TestConfig>(args(&["--port", "9", "--config", "does-not-exist.cfg"]), "").is_err());
+        assert!(load::<TestConfig>(args(&["--port", "9", "--colour", "red"]), "does-not-exist.cfg").is_err());
+    }
+}
@@ -0,0 +1,470 @@
+use enumset::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+// Scripts that exec each other this deep are almost certainly exec'ing themselves
+const MAX_EXEC_DEPTH: usize = 8;
+
+#[derive(EnumSetType, Debug)]
+pub enum CvarFlag {
+    Archive,    // Written out by `writeconfig`, so it survives a restart
+    ServerInfo, // Part of the info the server reports about itself
+    Cheat,      // Can only be changed while sv_cheats is on
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum CvarValue {
+    Bool(bool),
+    Int(i64),
+    Float(f32),
+    Str(String),
+}
+
+impl CvarValue {
+    // Parses the text into the same type as this value
+    fn parse_as(&self, text: &str) -> Option<CvarValue> {
+        return match self {
+            CvarValue::Bool(_) => match text {
+                "1" | "true" | "on" => Some(CvarValue::Bool(true)),
+                "0" | "false" | "off" => Some(CvarValue::Bool(false)),
+                _ => None,
+            },
+            CvarValue::Int(_) => text.parse().ok().map(CvarValue::Int),
+            CvarValue::Float(_) => text.parse().ok().filter(|v: &f32| v.is_finite()).map(CvarValue::Float),
+            CvarValue::Str(_) => Some(CvarValue::Str(text.to_string())),
+        };
+    }
+
+    fn type_name(&self) -> &'static str {
+        return match self {
+            CvarValue::Bool(_) => "a bool",
+            CvarValue::Int(_) => "an integer",
+            CvarValue::Float(_) => "a number",
+            CvarValue::Str(_) => "a string",
+        };
+    }
+}
+
+impl fmt::Display for CvarValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            CvarValue::Bool(v) => write!(f, "{}", *v as u8),
+            CvarValue::Int(v) => write!(f, "{v}"),
+            CvarValue::Float(v) => write!(f, "{v}"),
+            CvarValue::Str(v) => write!(f, "{v}"),
+        };
+    }
+}
+
+pub struct Cvar {
+    pub value: CvarValue,
+    pub default: CvarValue,
+    pub flags: EnumSet<CvarFlag>,
+    pub description: &'static str,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum CvarError {
+    Unknown(String),
+    Cheat(String),
+    Invalid { name: String, expected: &'static str },
+}
+
+impl fmt::Display for CvarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            CvarError::Unknown(name) => write!(f, "Unknown cvar {name}"),
+            CvarError::Cheat(name) => write!(f, "{name} is cheat protected, set sv_cheats 1 first"),
+            CvarError::Invalid { name, expected } => write!(f, "{name} must be {expected}"),
+        };
+    }
+}
+
+struct CommandInfo {
+    usage: &'static str,
+    description: &'static str,
+    min_args: usize,
+}
+
+// A command line split into its name and arguments
+#[derive(Clone, PartialEq, Debug)]
+pub struct Command {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+impl Command {
+    pub fn arg(&self, i: usize) -> Option<&str> {
+        return self.args.get(i).map(String::as_str);
+    }
+
+    pub fn parse_arg<T: FromStr>(&self, i: usize) -> Result<T, String> {
+        let arg = self.arg(i).ok_or(format!("{} is missing argument {}", self.name, i + 1))?;
+        return arg.parse().map_err(|_| format!("{}: invalid argument `{arg}`", self.name));
+    }
+
+    // Everything from the given argument on, for commands like `say` that take free text
+    pub fn rest(&self, from: usize) -> String {
+        return self.args.get(from..).unwrap_or_default().join(" ");
+    }
+}
+
+// Splits console text into commands. Commands end at a newline or `;`, arguments are separated by spaces
+// unless quoted, and `//` starts a comment.
+pub fn tokenize(text: &str) -> Vec<Command> {
+    let mut commands = vec!();
+    let mut tokens: Vec<String> = vec!();
+    let mut chars = text.chars().peekable();
+
+    let mut end_command = |tokens: &mut Vec<String>| {
+        if !tokens.is_empty() {
+            let name = tokens.remove(0).to_lowercase();
+            commands.push(Command { name, args: std::mem::take(tokens) });
+        }
+    };
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | ';' => end_command(&mut tokens),
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+            }
+            '"' => {
+                let mut token = String::new();
+                while let Some(c) = chars.next_if(|c| *c != '"' && *c != '\n') {
+                    token.push(c);
+                }
+                chars.next_if_eq(&'"');
+                tokens.push(token);
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != ';' && *c != '"') {
+                    token.push(c);
+                }
+                tokens.push(token);
+            }
+        }
+    }
+
+    end_command(&mut tokens);
+    return commands;
+}
+
+// Quake style console. Cvars and a few built in commands are handled here, every other registered command
+// is handed to whoever runs the console, which is what lets it drive the server or client that owns it.
+// Output is collected rather than printed, so it can be shown wherever the command came from.
+pub struct Console {
+    cvars: BTreeMap<String, Cvar>,
+    commands: BTreeMap<String, CommandInfo>,
+    output: Vec<String>,
+    exec_depth: usize,
+}
+
+const BUILTINS: [(&str, &str, &str); 9] = [
+    ("help", "help", "List every command"),
+    ("cvarlist", "cvarlist", "List every cvar and its value"),
+    ("set", "set <cvar> <value>", "Change a cvar"),
+    ("reset", "reset <cvar>", "Put a cvar back to its default"),
+    ("toggle", "toggle <cvar>", "Flip a bool cvar"),
+    ("echo", "echo <text>", "Print some text"),
+    ("exec", "exec <file>", "Run every command in a config file"),
+    ("writeconfig", "writeconfig <file>", "Save archived cvars to a config file"),
+    ("serverinfo", "serverinfo", "List the cvars the server reports about itself"),
+];
+
+impl Console {
+    pub fn new() -> Console {
+        let mut console = Console { cvars: BTreeMap::new(), commands: BTreeMap::new(), output: vec!(), exec_depth: 0 };
+        console.register_cvar("sv_cheats", CvarValue::Bool(false), CvarFlag::ServerInfo.into(), "Allow changing cheat protected cvars");
+        return console;
+    }
+
+    pub fn register_cvar(&mut self, name: &str, default: CvarValue, flags: EnumSet<CvarFlag>, description: &'static str) {
+        self.cvars.insert(name.to_lowercase(), Cvar { value: default.clone(), default, flags, description });
+    }
+
+    // Registers a command for the owner of the console to run. Commands with fewer arguments than
+    // `min_args` never reach it, the usage is printed instead.
+    pub fn register_command(&mut self, name: &str, usage: &'static str, description: &'static str, min_args: usize) {
+        self.commands.insert(name.to_lowercase(), CommandInfo { usage, description, min_args });
+    }
+
+    pub fn print(&mut self, line: impl Into<String>) {
+        self.output.push(line.into());
+    }
+
+    // Everything printed since the last call
+    pub fn take_output(&mut self) -> Vec<String> {
+        return std::mem::take(&mut self.output);
+    }
+
+    pub fn cvar(&self, name: &str) -> Option<&Cvar> {
+        return self.cvars.get(&name.to_lowercase());
+    }
+
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        return match self.cvar(name)?.value { CvarValue::Bool(v) => Some(v), _ => None };
+    }
+
+    pub fn get_int(&self, name: &str) -> Option<i64> {
+        return match self.cvar(name)?.value { CvarValue::Int(v) => Some(v), _ => None };
+    }
+
+    pub fn get_float(&self, name: &str) -> Option<f32> {
+        return match self.cvar(name)?.value { CvarValue::Float(v) => Some(v), _ => None };
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        return match &self.cvar(name)?.value { CvarValue::Str(v) => Some(v), _ => None };
+    }
+
+    // Sets a cvar from text, parsed as whatever type it was registered with
+    pub fn set(&mut self, name: &str, text: &str) -> Result<(), CvarError> {
+        let cheats = self.get_bool("sv_cheats").unwrap_or(false);
+        let name = name.to_lowercase();
+        let cvar = self.cvars.get_mut(&name).ok_or(CvarError::Unknown(name.clone()))?;
+
+        if cvar.flags.contains(CvarFlag::Cheat) && !cheats {
+            return Err(CvarError::Cheat(name));
+        }
+
+        cvar.value = cvar.default.parse_as(text).ok_or(CvarError::Invalid { name, expected: cvar.default.type_name() })?;
+        return Ok(());
+    }
+
+    // Every name a line could start with, for completion
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        return BUILTINS.iter().map(|(name, _, _)| *name)
+            .chain(self.commands.keys().map(String::as_str))
+            .chain(self.cvars.keys().map(String::as_str));
+    }
+
+    pub fn is_command(&self, name: &str) -> bool {
+        return self.commands.contains_key(&name.to_lowercase());
+    }
+
+    // `set` lines for every archived cvar that isn't at its default
+    pub fn archive(&self) -> String {
+        let mut text = String::new();
+        for (name, cvar) in self.cvars.iter().filter(|(_, c)| c.flags.contains(CvarFlag::Archive) && c.value != c.default) {
+            text += &format!("set {name} \"{}\"\n", cvar.value);
+        }
+        return text;
+    }
+
+    pub fn serverinfo(&self) -> Vec<(&str, String)> {
+        return self.cvars.iter().filter(|(_, c)| c.flags.contains(CvarFlag::ServerInfo)).map(|(name, c)| (name.as_str(), c.value.to_string())).collect();
+    }
+
+    // Runs console text, handing any registered command that isn't built in to `handler`
+    pub fn execute(&mut self, text: &str, handler: &mut dyn FnMut(&mut Console, &Command)) {
+        for cmd in tokenize(text) {
+            self.run(&cmd, handler);
+        }
+    }
+
+    fn run(&mut self, cmd: &Command, handler: &mut dyn FnMut(&mut Console, &Command)) {
+        if let Some(info) = self.commands.get(&cmd.name) {
+            if cmd.args.len() < info.min_args {
+                let usage = info.usage;
+                self.print(format!("Usage: {usage}"));
+            } else {
+                handler(self, cmd);
+            }
+            return;
+        }
+
+        if self.cvars.contains_key(&cmd.name) {
+            // `name` prints the cvar, `name value` sets it
+            match cmd.arg(0) {
+                None => self.print_cvar(&cmd.name),
+                Some(_) => self.set_and_report(&cmd.name, &cmd.rest(0)),
+            }
+            return;
+        }
+
+        let Some((_, usage, _)) = BUILTINS.iter().find(|(name, _, _)| *name == cmd.name) else {
+            self.print(format!("Unknown command {}", cmd.name));
+            return;
+        };
+
+        let needs_arg = usage.contains('<');
+        if needs_arg && cmd.args.is_empty() {
+            self.print(format!("Usage: {usage}"));
+            return;
+        }
+
+        match cmd.name.as_str() {
+            "help" => {
+                let lines: Vec<String> = BUILTINS.iter().map(|(_, usage, description)| (*usage, *description))
+                    .chain(self.commands.values().map(|c| (c.usage, c.description)))
+                    .map(|(usage, description)| format!("{usage:<24} {description}"))
+                    .collect();
+                self.output.extend(lines);
+            }
+            "cvarlist" => {
+                let names: Vec<String> = self.cvars.keys().cloned().collect();
+                for name in names {
+                    self.print_cvar(&name);
+                }
+            }
+            "set" => self.set_and_report(&cmd.args[0], &cmd.rest(1)),
+            "reset" => match self.cvars.get(&cmd.args[0].to_lowercase()) {
+                Some(cvar) => {
+                    let default = cvar.default.to_string();
+                    self.set_and_report(&cmd.args[0], &default);
+                }
+                None => self.print(CvarError::Unknown(cmd.args[0].clone()).to_string()),
+            },
+            "toggle" => match self.get_bool(&cmd.args[0]) {
+                Some(value) => self.set_and_report(&cmd.args[0], if value { "0" } else { "1" }),
+                None => self.print(format!("{} isn't a bool cvar", cmd.args[0])),
+            },
+            "echo" => self.print(cmd.rest(0)),
+            "exec" => self.exec(&cmd.args[0], handler),
+            "writeconfig" => {
+                if let Err(err) = std::fs::write(&cmd.args[0], self.archive()) {
+                    self.print(format!("Couldn't write {}: {err}", cmd.args[0]));
+                }
+            }
+            "serverinfo" => {
+                let lines: Vec<String> = self.serverinfo().into_iter().map(|(name, value)| format!("{name:<24} {value}")).collect();
+                self.output.extend(lines);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Runs a config file as if every line was typed in
+    pub fn exec(&mut self, path: &str, handler: &mut dyn FnMut(&mut Console, &Command)) {
+        if self.exec_depth >= MAX_EXEC_DEPTH {
+            self.print(format!("Not running {path}, execs are nested too deep"));
+            return;
+        }
+
+        let text = match std::fs::read(path) {
+            // Config files are often saved by old editors, so don't insist on UTF-8
+            Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            Err(err) => {
+                self.print(format!("Couldn't exec {path}: {err}"));
+                return;
+            }
+        };
+
+        self.exec_depth += 1;
+        self.execute(&text, handler);
+        self.exec_depth -= 1;
+    }
+
+    fn print_cvar(&mut self, name: &str) {
+        if let Some(cvar) = self.cvars.get(name) {
+            let line = format!("{name} is \"{}\", default \"{}\" - {}", cvar.value, cvar.default, cvar.description);
+            self.print(line);
+        }
+    }
+
+    fn set_and_report(&mut self, name: &str, text: &str) {
+        if let Err(err) = self.set(name, text) {
+            self.print(err.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmd(name: &str, args: &[&str]) -> Command {
+        return Command { name: name.to_string(), args: args.iter().map(|a| a.to_string()).collect() };
+    }
+
+    // Runs text through the console, returning every command handed to the owner
+    fn run(console: &mut Console, text: &str) -> Vec<Command> {
+        let mut handled = vec!();
+        console.execute(text, &mut |_, cmd| handled.push(cmd.clone()));
+        return handled;
+    }
+
+    #[test]
+    fn tokenizes_quotes_separators_and_comments() {
+        assert_eq!(tokenize("say \"hello; there\" you // not this\nKick 3;status"), vec![
+            cmd("say", &["hello; there", "you"]),
+            cmd("kick", &["3"]),
+            cmd("status", &[]),
+        ]);
+        assert_eq!(tokenize("  ;\n// nothing\n"), vec!());
+        assert_eq!(tokenize("map\"box\""), vec![cmd("map", &["box"])]);
+    }
+
+    #[test]
+    fn cvars_are_typed() {
+        let mut console = Console::new();
+        console.register_cvar("rate", CvarValue::Int(20), EnumSet::empty(), "");
+        console.register_cvar("sensitivity", CvarValue::Float(0.5), EnumSet::empty(), "");
+        console.register_cvar("hostname", CvarValue::Str("box".to_string()), EnumSet::empty(), "");
+
+        run(&mut console, "rate 30; sensitivity 1.5; hostname My Server; sv_cheats on");
+        assert_eq!(console.get_int("rate"), Some(30));
+        assert_eq!(console.get_float("sensitivity"), Some(1.5));
+        assert_eq!(console.get_str("hostname"), Some("My Server"));
+        assert_eq!(console.get_bool("sv_cheats"), Some(true));
+
+        console.take_output();
+        run(&mut console, "rate fast");
+        assert_eq!(console.get_int("rate"), Some(30));
+        assert_eq!(console.take_output(), vec!["rate must be an integer"]);
+
+        run(&mut console, "reset rate; toggle sv_cheats");
+        assert_eq!(console.get_int("rate"), Some(20));
+        assert_eq!(console.get_bool("sv_cheats"), Some(false));
+    }
+
+    #[test]
+    fn cheat_cvars_need_sv_cheats() {
+        let mut console = Console::new();
+        console.register_cvar("god", CvarValue::Bool(false), CvarFlag::Cheat.into(), "");
+
+        assert_eq!(console.set("god", "1"), Err(CvarError::Cheat("god".to_string())));
+        console.set("sv_cheats", "1").unwrap();
+        assert_eq!(console.set("god", "1"), Ok(()));
+    }
+
+    #[test]
+    fn registered_commands_reach_the_handler() {
+        let mut console = Console::new();
+        console.register_command("kick", "kick <player>", "", 1);
+
+        assert_eq!(run(&mut console, "kick; KICK bob; nonsense"), vec![cmd("kick", &["bob"])]);
+        assert_eq!(console.take_output(), vec!["Usage: kick <player>", "Unknown command nonsense"]);
+    }
+
+    #[test]
+    fn exec_runs_files_and_stops_recursion() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("console_test_{}.cfg", std::process::id()));
+        let path_str = path.to_str().unwrap().to_string();
+        std::fs::write(&path, format!("echo again\nexec \"{path_str}\"\n")).unwrap();
+
+        let mut console = Console::new();
+        run(&mut console, &format!("exec \"{path_str}\""));
+        std::fs::remove_file(&path).unwrap();
+
+        let output = console.take_output();
+        assert_eq!(output.iter().filter(|line| *line == "again").count(), MAX_EXEC_DEPTH);
+        assert!(output.last().unwrap().contains("nested too deep"));
+    }
+
+    #[test]
+    fn archive_only_writes_changed_archived_cvars() {
+        let mut console = Console::new();
+        console.register_cvar("name", CvarValue::Str("player".to_string()), CvarFlag::Archive.into(), "");
+        console.register_cvar("fov", CvarValue::Int(90), CvarFlag::Archive.into(), "");
+        console.register_cvar("rate", CvarValue::Int(20), EnumSet::empty(), "");
+
+        run(&mut console, "name \"big bob\"; rate 30");
+        assert_eq!(console.archive(), "set name \"big bob\"\n");
+    }
+}
@@ -0,0 +1,227 @@
+use crate::bsp::Entity;
+use std::collections::HashSet;
+use std::fmt;
+
+// Reads the Quake entity text format, the same whether it comes from a map's entity lump or a standalone
+// .ent file. Entities are `{ "key" "value" ... }` blocks. The text is taken as Latin-1, so any byte is a
+// character, and `//` starts a comment outside of quotes. Inside quotes `\"` is a quote and `\n` a new line,
+// which is how the Quake engines that support them read them. A NUL ends the text, as it does in the lump.
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct EntError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for EntError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "line {}, column {}: {}", self.line, self.column, self.message);
+    }
+}
+
+impl Entity {
+    pub fn duplicate_keys(&self) -> Vec<&str> {
+        let mut seen = HashSet::new();
+        let mut duplicates = vec!();
+        for (key, _) in &self.pairs {
+            if !seen.insert(key.as_str()) && !duplicates.contains(&key.as_str()) {
+                duplicates.push(key.as_str());
+            }
+        }
+
+        return duplicates;
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Open,
+    Close,
+    Str(String),
+}
+
+struct Tokenizer<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn peek_byte(&self) -> Option<u8> {
+        return self.bytes.get(self.pos).copied().filter(|byte| *byte != 0);
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        let byte = self.peek_byte()?;
+        self.pos += 1;
+        if byte == b'\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
+        return Some(byte);
+    }
+
+    fn error(&self, line: usize, column: usize, message: &str) -> EntError {
+        return EntError { line, column, message: message.to_string() };
+    }
+
+    // The next token with the line and column it starts at
+    fn next(&mut self) -> Result<Option<(Token, usize, usize)>, EntError> {
+        loop {
+            let (line, column) = (self.line, self.column);
+            let Some(byte) = self.next_byte() else { return Ok(None); };
+
+            let token = match byte {
+                b'{' => Token::Open,
+                b'}' => Token::Close,
+                b'"' => Token::Str(self.string(line, column)?),
+                b'/' if self.peek_byte() == Some(b'/') => {
+                    while self.peek_byte().is_some_and(|byte| byte != b'\n') {
+                        self.next_byte();
+                    }
+                    continue;
+                }
+                byte if byte.is_ascii_whitespace() => continue,
+                byte => return Err(self.error(line, column, &format!("unexpected {:?}", byte as char))),
+            };
+
+            return Ok(Some((token, line, column)));
+        }
+    }
+
+    fn string(&mut self, line: usize, column: usize) -> Result<String, EntError> {
+        let mut text = String::new();
+
+        loop {
+            match self.next_byte() {
+                None => return Err(self.error(line, column, "string never ends")),
+                Some(b'"') => return Ok(text),
+                Some(b'\\') if self.peek_byte() == Some(b'"') => { self.next_byte(); text.push('"'); }
+                Some(b'\\') if self.peek_byte() == Some(b'n') => { self.next_byte(); text.push('\n'); }
+                Some(b'\\') if self.peek_byte() == Some(b'\\') => { self.next_byte(); text.push('\\'); }
+                // Latin-1 maps each byte to the code point with the same number
+                Some(byte) => text.push(byte as char),
+            }
+        }
+    }
+}
+
+pub fn parse_ent(bytes: &[u8]) -> Result<Vec<Entity>, EntError> {
+    let mut tokens = Tokenizer { bytes, pos: 0, line: 1, column: 1 };
+    let mut entities = vec!();
+
+    while let Some((token, line, column)) = tokens.next()? {
+        if token != Token::Open {
+            return Err(tokens.error(line, column, "expected `{` to start an entity"));
+        }
+
+        let mut entity = Entity { line, pairs: vec!() };
+        loop {
+            let key = match tokens.next()? {
+                Some((Token::Close, _, _)) => break,
+                Some((Token::Str(key), _, _)) => key,
+                Some((Token::Open, line, column)) => return Err(tokens.error(line, column, "`{` inside an entity, is a `}` missing?")),
+                None => return Err(tokens.error(entity.line, 1, "entity never ends")),
+            };
+
+            match tokens.next()? {
+                Some((Token::Str(value), _, _)) => entity.pairs.push((key, value)),
+                Some((_, line, column)) => return Err(tokens.error(line, column, &format!("expected a value for `{key}`"))),
+                None => return Err(tokens.error(entity.line, 1, "entity never ends")),
+            }
+        }
+
+        entities.push(entity);
+    }
+
+    return Ok(entities);
+}
+
+// Writes entities back out the way compilers lay out the lump, one key per line. Parsing the result gives
+// back the same entities.
+pub fn write_ent(entities: &[Entity]) -> Vec<u8> {
+    let mut text = String::new();
+    for entity in entities {
+        text.push_str("{\n");
+        for (key, value) in &entity.pairs {
+            text.push_str(&format!("\"{}\" \"{}\"\n", escape(key), escape(value)));
+        }
+        text.push_str("}\n");
+    }
+
+    // Back to Latin-1, anything it can't hold becomes a question mark
+    return text.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect();
+}
+
+// Backslashes are left alone unless they'd be read as an escape, so Windows style paths stay readable
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\\' if matches!(chars.peek(), None | Some('"' | 'n' | '\\' | '\n')) => escaped.push_str("\\\\"),
+            c => escaped.push(c),
+        }
+    }
+
+    return escaped;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(entity: &Entity) -> Vec<(&str, &str)> {
+        return entity.pairs.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+    }
+
+    #[test]
+    fn keeps_order_and_duplicates() {
+        let text = b"{\n\"classname\" \"worldspawn\"\n\"wad\" \"\\quake\\id1\\gfx.wad\"\n}\n// A comment\n{ \"target\" \"a\" \"origin\" \"0 0 0\" \"target\" \"b\" } // Another\n\0garbage";
+        let entities = parse_ent(text).unwrap();
+
+        assert_eq!(entities.len(), 2);
+        assert_eq!(pairs(&entities[0]), vec![("classname", "worldspawn"), ("wad", "\\quake\\id1\\gfx.wad")]);
+        assert_eq!(entities[1].line, 6);
+        assert_eq!(pairs(&entities[1]), vec![("target", "a"), ("origin", "0 0 0"), ("target", "b")]);
+        assert_eq!(entities[1].duplicate_keys(), vec!["target"]);
+        assert_eq!(entities[1].get_str("target"), Ok("b"));
+    }
+
+    #[test]
+    fn escapes_and_latin1() {
+        let entities = parse_ent(b"{ \"message\" \"Say \\\"hi\\\"\\nthen go\" \"netname\" \"caf\xe9\" }").unwrap();
+        assert_eq!(pairs(&entities[0]), vec![("message", "Say \"hi\"\nthen go"), ("netname", "caf\u{e9}")]);
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let entities = vec![
+            Entity { line: 1, pairs: vec![("classname".to_string(), "worldspawn".to_string()), ("wad".to_string(), "\\quake\\id1\\".to_string())] },
+            Entity { line: 5, pairs: vec![("message".to_string(), "\"Hi\"\nthere \\n caf\u{e9}".to_string()), ("message".to_string(), "again".to_string())] },
+        ];
+
+        let text = write_ent(&entities);
+        assert_eq!(String::from_utf8_lossy(&text[..41]), "{\n\"classname\" \"worldspawn\"\n\"wad\" \"\\quake");
+        assert_eq!(parse_ent(&text), Ok(entities));
+    }
+
+    #[test]
+    fn errors_have_positions() {
+        let err = |text: &[u8]| parse_ent(text).unwrap_err();
+
+        assert_eq!(err(b"{\n  \"a\" \"1\"\n  x\n}"), EntError { line: 3, column: 3, message: "unexpected 'x'".to_string() });
+        assert_eq!(err(b"\"a\" \"1\""), EntError { line: 1, column: 1, message: "expected `{` to start an entity".to_string() });
+        assert_eq!(err(b"{ \"a\" }"), EntError { line: 1, column: 7, message: "expected a value for `a`".to_string() });
+        assert_eq!(err(b"\n{ \"a\" \"1\"\n"), EntError { line: 2, column: 1, message: "entity never ends".to_string() });
+        assert_eq!(err(b"{ \"a\" \"1 }").message, "string never ends");
+        assert_eq!(err(b"{ \"a\" \"1\" { }").column, 11);
+    }
+}
@@ -0,0 +1,432 @@
+use crate::bsp_entity::{EntitySchema, KeyType};
+use std::collections::HashMap;
+use std::fmt;
+
+// Reads the entity classes out of a level editor's FGD file. Only what a schema needs is kept: each
+// class's keys and their types, with base classes folded in. Everything about how the editor shows
+// things is skipped over.
+
+// Includes nested deeper than this are including themselves
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct FgdError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for FgdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "line {}: {}", self.line, self.message);
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Directive(String), // @PointClass, @include and so on
+    Ident(String),
+    Str(String),
+    Num(String),
+    Punct(char),
+}
+
+// `include` gives back the text of a file named by `@include`, which is parsed into the same schema so
+// classes can be built on base classes from it
+pub fn parse_fgd(text: &str, include: &mut dyn FnMut(&str) -> Result<String, String>) -> Result<EntitySchema, FgdError> {
+    let mut base_classes = HashMap::new();
+    let mut schema = EntitySchema::new();
+    parse_file(text, &mut base_classes, &mut schema, include, 0)?;
+    return Ok(schema);
+}
+
+fn parse_file(text: &str, base_classes: &mut HashMap<String, HashMap<String, KeyType>>, schema: &mut EntitySchema, include: &mut dyn FnMut(&str) -> Result<String, String>, depth: usize) -> Result<(), FgdError> {
+    let mut parser = Parser { tokens: tokenize(text)?, pos: 0, base_classes, schema };
+
+    while let Some(token) = parser.next() {
+        match token {
+            Token::Directive(kind) if kind.eq_ignore_ascii_case("include") => parser.include(include, depth)?,
+            Token::Directive(kind) if kind.to_lowercase().ends_with("class") => parser.class(kind.eq_ignore_ascii_case("BaseClass"))?,
+            // @mapsize and the rest don't say anything about keys
+            Token::Directive(_) => parser.skip_directive(),
+            _ => return Err(parser.error("expected a class definition")),
+        }
+    }
+
+    return Ok(());
+}
+
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, FgdError> {
+    let mut tokens = vec!();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '\n' => { line += 1; continue; }
+            c if c.is_whitespace() => continue,
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+                continue;
+            }
+            '"' => {
+                let start = line;
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' { line += 1; }
+                            text.push(c);
+                        }
+                        None => return Err(FgdError { line: start, message: "string never ends".to_string() }),
+                    }
+                }
+                Token::Str(text)
+            }
+            '@' => Token::Directive(take_while(&mut chars, String::new(), |c| c.is_alphanumeric() || c == '_')),
+            c if c.is_ascii_digit() || c == '-' => Token::Num(take_while(&mut chars, c.to_string(), |c| c.is_ascii_digit() || c == '.')),
+            c if c.is_alphabetic() || c == '_' => Token::Ident(take_while(&mut chars, c.to_string(), |c| c.is_alphanumeric() || c == '_')),
+            // Newer editors put JSON-like expressions in `model({ ... })` and the like, which are skipped over
+            // anyway, so anything else is punctuation to the parser rather than an error here
+            c => Token::Punct(c),
+        };
+
+        tokens.push((token, line));
+    }
+
+    return Ok(tokens);
+}
+
+fn take_while(chars: &mut std::iter::Peekable<std::str::Chars>, mut text: String, keep: impl Fn(char) -> bool) -> String {
+    while let Some(c) = chars.next_if(|c| keep(*c)) {
+        text.push(c);
+    }
+
+    return text;
+}
+
+struct Parser<'a> {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    base_classes: &'a mut HashMap<String, HashMap<String, KeyType>>,
+    schema: &'a mut EntitySchema,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        return self.tokens.get(self.pos).map(|(token, _)| token);
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        return self.tokens.get(self.pos + offset).map(|(token, _)| token);
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(token, _)| token.clone());
+        self.pos += 1;
+        return token;
+    }
+
+    fn error(&self, message: &str) -> FgdError {
+        let line = self.tokens.get(self.pos.saturating_sub(1)).or(self.tokens.last()).map_or(1, |(_, line)| *line);
+        return FgdError { line, message: message.to_string() };
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), FgdError> {
+        return match self.next() {
+            Some(Token::Punct(c)) if c == punct => Ok(()),
+            _ => Err(self.error(&format!("expected `{punct}`"))),
+        };
+    }
+
+    fn ident(&mut self) -> Result<String, FgdError> {
+        return match self.next() {
+            Some(Token::Ident(name)) => Ok(name),
+            _ => Err(self.error("expected a name")),
+        };
+    }
+
+    // Skips to the matching close, having just read the open
+    fn skip_group(&mut self, open: char, close: char) {
+        let mut depth = 1;
+        while depth > 0 && let Some(token) = self.next() {
+            match token {
+                Token::Punct(c) if c == open => depth += 1,
+                Token::Punct(c) if c == close => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+
+    fn skip_directive(&mut self) {
+        let mut depth = 0;
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Directive(_) if depth == 0 => return,
+                Token::Punct('(' | '[') => depth += 1,
+                Token::Punct(')' | ']') => depth -= 1,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+    }
+
+    // `@include "file.fgd"`
+    fn include(&mut self, include: &mut dyn FnMut(&str) -> Result<String, String>, depth: usize) -> Result<(), FgdError> {
+        let Some(Token::Str(name)) = self.next() else {
+            return Err(self.error("expected a file to include"));
+        };
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(self.error(&format!("not including {name}, includes are nested too deep")));
+        }
+
+        let text = include(&name).map_err(|err| self.error(&format!("can't include {name}: {err}")))?;
+        let result = parse_file(&text, self.base_classes, self.schema, include, depth + 1);
+        return result.map_err(|err| self.error(&format!("in {name}, line {}: {}", err.line, err.message)));
+    }
+
+    // `@PointClass base(A, B) size(...) = classname : "description" [ keys ]`
+    fn class(&mut self, is_base: bool) -> Result<(), FgdError> {
+        let mut keys: HashMap<String, KeyType> = HashMap::new();
+
+        loop {
+            match self.next() {
+                Some(Token::Punct('=')) => break,
+                Some(Token::Ident(name)) if self.peek() == Some(&Token::Punct('(')) => {
+                    self.pos += 1;
+                    if name != "base" {
+                        self.skip_group('(', ')');
+                        continue;
+                    }
+
+                    while let Some(token) = self.next() {
+                        match token {
+                            Token::Ident(base) => {
+                                let base_keys = self.base_classes.get(&base).ok_or_else(|| self.error(&format!("unknown base class {base}")))?;
+                                keys.extend(base_keys.iter().map(|(key, ty)| (key.clone(), ty.clone())));
+                            }
+                            Token::Punct(',') => {}
+                            Token::Punct(')') => break,
+                            _ => return Err(self.error("expected base class names")),
+                        }
+                    }
+                }
+                Some(_) => {}
+                None => return Err(self.error("class never gets a name")),
+            }
+        }
+
+        let classname = self.ident()?;
+        if self.peek() == Some(&Token::Punct(':')) {
+            self.pos += 1;
+            self.description()?;
+        }
+
+        self.expect('[')?;
+        while self.peek() != Some(&Token::Punct(']')) {
+            let (key, ty) = self.key()?;
+            keys.insert(key, ty);
+        }
+        self.pos += 1;
+
+        if is_base {
+            self.base_classes.insert(classname, keys);
+        } else {
+            self.schema.add_class(&classname, keys);
+        }
+
+        return Ok(());
+    }
+
+    // Strings can be joined with `+` to spread them over several lines
+    fn description(&mut self) -> Result<(), FgdError> {
+        loop {
+            match self.next() {
+                Some(Token::Str(_)) => {}
+                _ => return Err(self.error("expected a description")),
+            }
+
+            if self.peek() != Some(&Token::Punct('+')) {
+                return Ok(());
+            }
+            self.pos += 1;
+        }
+    }
+
+    // `name(type) : "Display name" : default : "description"`, with `= [ ... ]` listing choices or flags
+    fn key(&mut self) -> Result<(String, KeyType), FgdError> {
+        let key = self.ident()?;
+        self.expect('(')?;
+        let kind = self.ident()?;
+        self.expect(')')?;
+
+        let mut choices = vec!();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("class never ends")),
+                Some(Token::Punct(']')) => break,
+                // The next key
+                Some(Token::Ident(_)) if self.peek_at(1) == Some(&Token::Punct('(')) => break,
+                Some(Token::Punct('[')) => {
+                    self.pos += 1;
+                    choices = self.choices()?;
+                }
+                Some(_) => self.pos += 1,
+            }
+        }
+
+        let ty = match kind.to_lowercase().as_str() {
+            "integer" => KeyType::Int,
+            "float" => KeyType::Float,
+            "boolean" => KeyType::Bool,
+            "choices" => KeyType::Choices(choices),
+            "flags" => KeyType::Flags,
+            "color255" | "color1" => KeyType::Color,
+            "angle" => KeyType::Angles,
+            "origin" | "vector" => KeyType::Vec3,
+            _ => KeyType::Str,
+        };
+
+        return Ok((key, ty));
+    }
+
+    // `value : "Display name"`, flags add `: default` after it
+    fn choices(&mut self) -> Result<Vec<String>, FgdError> {
+        let mut values = vec!();
+
+        loop {
+            match self.next() {
+                Some(Token::Punct(']')) => return Ok(values),
+                Some(Token::Num(value) | Token::Str(value)) => values.push(value),
+                _ => return Err(self.error("expected a choice")),
+            }
+
+            self.expect(':')?;
+            self.description()?;
+
+            if self.peek() == Some(&Token::Punct(':')) {
+                self.pos += 1;
+                self.next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bsp::Entity;
+
+    const FGD: &str = r#"
+// Base classes fold into the ones built on them
+@BaseClass = Targetname [ targetname(target_source) : "Name" ]
+@BaseClass base(Targetname) = Trigger
+[
+    target(target_destination) : "Target"
+    wait(float) : "Wait" : "0.2"
+]
+
+@mapsize(-4096, 4096)
+
+@PointClass base(Targetname) size(-16 -16 -24, 16 16 32) color(0 255 0) = info_player_start : "Player " +
+    "start" []
+
+@PointClass size(-8 -8 -8, 8 8 8) = light : "Light"
+[
+    light(integer) : "Brightness" : 300
+    _color(color1) : "Color" : "1 1 1"
+    style(choices) : "Style" : 0 =
+    [
+        0 : "Normal"
+        1 : "Flicker"
+    ]
+    spawnflags(flags) =
+    [
+        1 : "Start off" : 0
+    ]
+]
+
+@SolidClass base(Trigger) = trigger_changelevel : "Level exit"
+[
+    map(string) : "Next map"
+    landmark(target_destination) readonly : "Landmark"
+]
+"#;
+
+    fn no_includes(name: &str) -> Result<String, String> {
+        return Err(format!("no {name}"));
+    }
+
+    fn entity(keys: &[(&str, &str)]) -> Entity {
+        return Entity { line: 0, pairs: keys.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect() };
+    }
+
+    #[test]
+    fn classes_keep_their_keys() {
+        let schema = parse_fgd(FGD, &mut no_includes).unwrap();
+        assert!(schema.has_class("info_player_start"));
+        assert!(!schema.has_class("Trigger"));
+
+        let entities = [
+            entity(&[("classname", "info_player_start"), ("targetname", "start"), ("origin", "0 0 0")]),
+            entity(&[("classname", "light"), ("light", "200"), ("_color", "1 0.5 0"), ("style", "1"), ("spawnflags", "1")]),
+            entity(&[("classname", "trigger_changelevel"), ("targetname", "exit"), ("target", "t1"), ("wait", "1"), ("map", "e1m2"), ("landmark", "lm")]),
+        ];
+        assert_eq!(schema.check(&entities), vec!());
+
+        let entities = [
+            entity(&[("classname", "light"), ("light", "bright"), ("style", "2")]),
+            entity(&[("classname", "trigger_changelevel"), ("wait", "later"), ("delay", "1")]),
+        ];
+        let issues: Vec<String> = schema.check(&entities).iter().map(|issue| issue.to_string()).collect();
+        assert_eq!(issues, vec![
+            "entity 0 (light): invalid value `bright` for `light`, expected a whole number",
+            "entity 0 (light): invalid value `2` for `style`, expected one of the listed choices",
+            "entity 1 (trigger_changelevel): invalid value `later` for `wait`, expected a number",
+            "entity 1 (trigger_changelevel): unknown key `delay`",
+        ]);
+    }
+
+    #[test]
+    fn errors_have_lines() {
+        assert_eq!(parse_fgd("@PointClass = a [\n  b(integer) : \"B\n", &mut no_includes).unwrap_err().line, 2);
+        assert_eq!(parse_fgd("\n\n@PointClass base(Missing) = a []", &mut no_includes).unwrap_err(), FgdError { line: 3, message: "unknown base class Missing".to_string() });
+        assert_eq!(parse_fgd("@PointClass = a [ b(integer) ", &mut no_includes).unwrap_err().message, "class never ends");
+        assert_eq!(parse_fgd("\n@include \"base.fgd\"", &mut no_includes).unwrap_err(), FgdError { line: 2, message: "can't include base.fgd: no base.fgd".to_string() });
+    }
+
+    #[test]
+    fn trenchbroom_models_and_includes() {
+        let base = r#"
+@BaseClass = Appearflags [ spawnflags(Flags) = [ 256 : "Not in Easy" : 0 ] ]
+"#;
+        let fgd = r#"
+@include "base.fgd"
+
+@PointClass base(Appearflags) size(-16 -16 -24, 16 16 40) model({"path": ":progs/player.mdl", "skin": 0, "frame": 0}) = info_player_start : "Player start" []
+
+@PointClass base(Appearflags) model({{ spawnflags & 1 -> ":progs/g_shot.mdl", ":progs/g_nails.mdl" }}) = weapon_pickup : "Weapon"
+[
+    count(integer) : "Ammo" : 10
+]
+"#;
+        let mut files = |name: &str| if name == "base.fgd" { Ok(base.to_string()) } else { Err(format!("no {name}")) };
+        let schema = parse_fgd(fgd, &mut files).unwrap();
+        assert!(schema.has_class("info_player_start"));
+
+        let entities = [
+            entity(&[("classname", "info_player_start"), ("spawnflags", "256"), ("origin", "0 0 0")]),
+            entity(&[("classname", "weapon_pickup"), ("spawnflags", "257"), ("count", "20")]),
+        ];
+        assert_eq!(schema.check(&entities), vec!());
+
+        // Lines in an included file are its own
+        let err = parse_fgd("@include \"base.fgd\"", &mut |_: &str| Ok("\n@PointClass = a [ b(integer) ".to_string())).unwrap_err();
+        assert_eq!(err, FgdError { line: 1, message: "in base.fgd, line 2: class never ends".to_string() });
+
+        let err = parse_fgd("\n@include \"self.fgd\"", &mut |_: &str| Ok("@include \"self.fgd\"".to_string())).unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.message.ends_with("includes are nested too deep"), "{err}");
+    }
+}
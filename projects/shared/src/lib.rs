@@ -1,8 +1,22 @@
+// Lets the derive macros refer to `::shared` from inside this crate too
+extern crate self as shared;
+
+pub mod chat;
+pub mod codec;
+pub mod config;
+pub mod console;
+pub mod ent;
+pub mod fgd;
 pub mod message;
+pub mod net_sim;
 pub mod bsp;
 pub mod bsp_entity;
 pub mod bsp_query;
 pub mod player;
+pub mod rcon;
+pub mod snapshot;
+pub mod transport;
+pub mod user_cmd;
 
 #[cfg(test)]
 mod tests {
@@ -1,72 +1,298 @@
-use byteorder::LittleEndian;
-use byteorder::ReadBytesExt;
-use std::io::BufReader;
-use std::io::Read;
-use strum_macros::FromRepr;
-
-#[repr(u8)]
-#[derive(FromRepr)]
+use enumset::EnumSet;
+use raylib::prelude::*;
+use std::f32::consts::PI;
+use crate::codec::{self, Decode, DecodeError, Encode};
+use crate::player::PlayerState;
+use crate::rcon::RconResponse;
+use crate::snapshot::SnapshotDelta;
+use crate::transport::Reliability;
+use crate::user_cmd::UserCmd;
+use std::fmt;
+
+// Bump whenever the layout of any message changes. Clients with a different version are turned away.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+// Identifies the exact build, so mismatched but protocol compatible builds can at least be logged.
+// CI sets BUILD_ID to the commit hash, local builds fall back to the package version alone.
+pub const BUILD_HASH: u64 = fnv1a(concat!(env!("CARGO_PKG_VERSION"), "+").as_bytes(), fnv1a_str(option_env!("BUILD_ID")));
+
+const fn fnv1a_str(s: Option<&str>) -> u64 {
+    return match s {
+        Some(s) => fnv1a(s.as_bytes(), 0xcbf29ce484222325),
+        None => 0xcbf29ce484222325,
+    };
+}
+
+const fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    return hash;
+}
+
+// Clients resend their most recent commands in every packet so a single drop doesn't lose input
+pub const MAX_CMDS_PER_MESSAGE: usize = 8;
+
+// Positions and velocities are sent in 1/8th units, angles in 1/65536th of a turn
+const POS_SCALE: f32 = 8f32;
+const ANGLE_SCALE: f32 = 65536f32 / (2f32 * PI);
+
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
 pub enum Message {
-    HelloFromClient(u64, String) = 0,
-    HelloFromServer(String),
+    HelloFromClient { protocol_version: u32, build_hash: u64, id: u64, name: String },
+    HelloFromServer { map: String, tick_rate: u32, slot: u8 },
+    Rejected(RejectReason),
     Chat(String),
-    Move { x: u8, y: u8 }
+    UserCmds(#[codec(max_len = MAX_CMDS_PER_MESSAGE)] Vec<UserCmd>),
+    PlayerState { ack_sequence: u32, state: NetPlayerState },
+    Snapshot(SnapshotDelta),
+    SnapshotAck { tick: u32 },
+    ChatFrom { sender: Option<String>, text: String }, // Relayed `Chat`, no sender means it came from the server itself
+    RconChallengeRequest,
+    RconChallenge { nonce: u64 },
+    RconCommand { response: RconResponse, command: String },
+    RconOutput { lines: Vec<String> },
+    ChangeLevel { map: String }, // Load this map and say hello again, the connection stays up
 }
 
-impl Message {
-    pub fn from_bytes(bytes: &[u8]) -> Option<Message> {
-        let mut reader = BufReader::new(bytes);
-        let msg_type = reader.read_u8().ok()?;
-        let mut msg = Message::from_repr(msg_type)?;
-
-        match &mut msg {
-            Message::HelloFromClient(id, s) => {
-                *id = reader.read_u64::<LittleEndian>().ok()?;
-                reader.read_to_string(s).ok()?;
-            }
-            Message::HelloFromServer(s) => {
-                reader.read_to_string(s).ok()?;
-            }
-            Message::Chat(s) => {
-                reader.read_to_string(s).ok()?;
-            }
-            Message::Move { x, y } => {
-                *x = bytes[1];
-                *y = bytes[2];
-            }
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub enum RejectReason {
+    VersionMismatch { server_protocol: u32, client_protocol: u32 },
+    ServerFull { max_players: u8 },
+    Banned { reason: String },
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::VersionMismatch { server_protocol, client_protocol } =>
+                write!(f, "Protocol version mismatch: server is {server_protocol}, client is {client_protocol}"),
+            RejectReason::ServerFull { max_players } =>
+                write!(f, "Server is full ({max_players} players)"),
+            RejectReason::Banned { reason } =>
+                write!(f, "Banned from this server: {reason}"),
         }
+    }
+}
 
-        return Some(msg);
+// Quantized `PlayerState` as sent from the server. The server snaps its own players to the
+// quantized values after every tick so clients replay from exactly the same state.
+#[derive(Encode, Decode, Default, Clone, Copy, PartialEq, Debug)]
+pub struct NetPlayerState {
+    pub origin: [i32; 3],
+    pub velocity: [i16; 3],
+    pub yaw: u16,
+    pub pitch: i16,
+    pub ladder_release_msec: u16,
+    pub flags: u8,
+}
+
+pub fn quantize_pos(pos: Vector3) -> [i32; 3] {
+    return [(pos.x * POS_SCALE).round() as i32, (pos.y * POS_SCALE).round() as i32, (pos.z * POS_SCALE).round() as i32];
+}
+
+pub fn dequantize_pos(pos: [i32; 3]) -> Vector3 {
+    return Vector3::new(pos[0] as f32, pos[1] as f32, pos[2] as f32) / POS_SCALE;
+}
+
+// Wraps to a single turn
+pub fn quantize_angle(angle: f32) -> u16 {
+    return ((angle * ANGLE_SCALE).round() as i64).rem_euclid(65536) as u16;
+}
+
+pub fn dequantize_angle(angle: u16) -> f32 {
+    return angle as f32 / ANGLE_SCALE;
+}
+
+impl NetPlayerState {
+    pub fn from_state(state: &PlayerState) -> NetPlayerState {
+        let quantize_vel = |v: f32| (v * POS_SCALE).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+
+        return NetPlayerState {
+            origin: quantize_pos(state.pos),
+            velocity: [quantize_vel(state.velocity.x), quantize_vel(state.velocity.y), quantize_vel(state.velocity.z)],
+            yaw: quantize_angle(state.yaw),
+            pitch: (state.pitch * ANGLE_SCALE).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16,
+            ladder_release_msec: (state.ladder_release.max(0f32) * 1000f32).round().min(u16::MAX as f32) as u16,
+            flags: state.flags.as_u8(),
+        };
+    }
+
+    pub fn to_state(&self) -> PlayerState {
+        return PlayerState {
+            pos: dequantize_pos(self.origin),
+            velocity: Vector3::new(self.velocity[0] as f32, self.velocity[1] as f32, self.velocity[2] as f32) / POS_SCALE,
+            yaw: dequantize_angle(self.yaw),
+            pitch: self.pitch as f32 / ANGLE_SCALE,
+            ladder_release: self.ladder_release_msec as f32 / 1000f32,
+            flags: EnumSet::from_u8_truncated(self.flags),
+        };
+    }
+}
+
+impl Message {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Message, DecodeError> {
+        return codec::from_bytes(bytes);
+    }
+
+    // Channel each kind of message goes out on unless the sender asks for something else. Anything that has to
+    // arrive for the session to make sense is reliable, per-tick state is unreliable since a newer copy is
+    // always on the way, and input skips any send queue since it's stale by the time it would go out.
+    pub fn reliability(&self) -> Reliability {
+        return match self {
+            Message::HelloFromClient { .. } | Message::HelloFromServer { .. } | Message::Rejected(_) | Message::Chat(_) | Message::ChatFrom { .. } | Message::ChangeLevel { .. } => Reliability::Reliable,
+            Message::RconChallengeRequest | Message::RconChallenge { .. } | Message::RconCommand { .. } | Message::RconOutput { .. } => Reliability::Reliable,
+            Message::UserCmds(_) => Reliability::UnreliableNoDelay,
+            Message::PlayerState { .. } | Message::Snapshot(_) | Message::SnapshotAck { .. } => Reliability::Unreliable,
+        };
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut vec : Vec<u8> = Vec::new();
-        vec.push(self.discriminant());
+        return codec::to_bytes(self);
+    }
+}
 
-        match self {
-            Message::HelloFromClient(id, s) => {
-                vec.extend_from_slice(&id.to_le_bytes());
-                vec.extend_from_slice(s.as_bytes());
-            }
-            Message::HelloFromServer(s) => {
-                vec.extend_from_slice(s.as_bytes());
-            }
-            Message::Chat(s) => {
-                vec.extend_from_slice(s.as_bytes());
-            }
-            Message::Move { x, y } => {
-                vec.push(*x);
-                vec.push(*y);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_cmd::Button;
+
+    fn round_trip(msg: Message) {
+        let bytes = msg.to_bytes();
+        assert_eq!(Message::from_bytes(&bytes), Ok(msg));
+    }
+
+    fn test_cmd(sequence: u32) -> UserCmd {
+        let mut cmd = UserCmd::new(sequence);
+        cmd.yaw = 1.25f32;
+        cmd.pitch = -0.5f32;
+        cmd.forward_move = 127;
+        cmd.side_move = -127;
+        cmd.up_move = 3;
+        cmd.buttons = Button::Jump | Button::Attack;
+        return cmd;
+    }
+
+    #[test]
+    fn round_trip_hello_from_client() {
+        round_trip(Message::HelloFromClient { protocol_version: PROTOCOL_VERSION, build_hash: BUILD_HASH, id: 0xdeadbeef, name: "player".to_string() });
+    }
+
+    #[test]
+    fn round_trip_hello_from_server() {
+        round_trip(Message::HelloFromServer { map: "assets/box.bsp".to_string(), tick_rate: 20, slot: 3 });
+    }
+
+    #[test]
+    fn round_trip_rejected() {
+        round_trip(Message::Rejected(RejectReason::VersionMismatch { server_protocol: 2, client_protocol: 1 }));
+        round_trip(Message::Rejected(RejectReason::ServerFull { max_players: 16 }));
+        round_trip(Message::Rejected(RejectReason::Banned { reason: "griefing".to_string() }));
+    }
+
+    #[test]
+    fn round_trip_chat() {
+        round_trip(Message::Chat("hello there".to_string()));
+        round_trip(Message::ChatFrom { sender: Some("player".to_string()), text: "hello there".to_string() });
+        round_trip(Message::ChatFrom { sender: None, text: "player joined the game".to_string() });
+    }
+
+    #[test]
+    fn round_trip_rcon() {
+        round_trip(Message::RconChallengeRequest);
+        round_trip(Message::RconChallenge { nonce: u64::MAX });
+        round_trip(Message::RconCommand { response: [7; 32], command: "kick 3".to_string() });
+        round_trip(Message::RconOutput { lines: vec!["one".to_string(), String::new()] });
+    }
+
+    #[test]
+    fn round_trip_change_level() {
+        round_trip(Message::ChangeLevel { map: "assets/qbj3_chaosed0.bsp".to_string() });
+    }
+
+    #[test]
+    fn round_trip_user_cmds() {
+        round_trip(Message::UserCmds(vec!()));
+        round_trip(Message::UserCmds((0..MAX_CMDS_PER_MESSAGE as u32).map(test_cmd).collect()));
+    }
+
+    #[test]
+    fn round_trip_player_state() {
+        let state = NetPlayerState { origin: [-80, 1024, 7], velocity: [-3, 2400, 0], yaw: 65535, pitch: -16000, ladder_release_msec: 300, flags: 0b101 };
+        round_trip(Message::PlayerState { ack_sequence: 12345, state });
+    }
+
+    #[test]
+    fn round_trip_snapshot() {
+        use crate::snapshot::EntityDelta;
+
+        let mut moved = EntityDelta::new(3);
+        moved.origin = Some([1, -2, 3]);
+        moved.frame = Some(4);
+
+        round_trip(Message::Snapshot(SnapshotDelta { tick: 10, base_tick: None, removed: vec!(), changed: vec!() }));
+        round_trip(Message::Snapshot(SnapshotDelta { tick: 11, base_tick: Some(10), removed: vec![1, 2], changed: vec![moved, EntityDelta::new(9)] }));
+        round_trip(Message::SnapshotAck { tick: 11 });
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        let messages = [
+            Message::HelloFromClient { protocol_version: 1, build_hash: 2, id: 3, name: "a".to_string() },
+            Message::Rejected(RejectReason::Banned { reason: "b".to_string() }),
+            Message::Chat("two words".to_string()),
+            Message::UserCmds(vec![test_cmd(1), test_cmd(2)]),
+            Message::PlayerState { ack_sequence: 1, state: NetPlayerState::default() },
+        ];
+
+        for msg in messages {
+            let bytes = msg.to_bytes();
+
+            for len in 0..bytes.len() {
+                assert!(Message::from_bytes(&bytes[..len]).is_err());
             }
         }
+    }
+
+    #[test]
+    fn too_many_cmds_are_rejected() {
+        let mut bytes = Message::UserCmds(vec!()).to_bytes();
+        bytes[1] = MAX_CMDS_PER_MESSAGE as u8 + 1;
+        assert_eq!(Message::from_bytes(&bytes), Err(DecodeError::TooManyItems { offset: 1, len: MAX_CMDS_PER_MESSAGE as u64 + 1, max_len: MAX_CMDS_PER_MESSAGE }));
+
+        // Only the newest ones are sent
+        let cmds: Vec<UserCmd> = (0..MAX_CMDS_PER_MESSAGE as u32 + 3).map(test_cmd).collect();
+        let sent = Message::from_bytes(&Message::UserCmds(cmds.clone()).to_bytes());
+        assert_eq!(sent, Ok(Message::UserCmds(cmds[3..].to_vec())));
+    }
+
+    #[test]
+    fn unknown_message_type_is_rejected() {
+        assert_eq!(Message::from_bytes(&[100]), Err(DecodeError::InvalidDiscriminant { type_name: "Message", value: 100 }));
+    }
 
-        return vec;
+    #[test]
+    fn session_messages_are_reliable() {
+        assert_eq!(Message::Chat("hi".to_string()).reliability(), Reliability::Reliable);
+        assert_eq!(Message::Rejected(RejectReason::ServerFull { max_players: 2 }).reliability(), Reliability::Reliable);
+        assert_eq!(Message::SnapshotAck { tick: 1 }.reliability(), Reliability::Unreliable);
+        assert_eq!(Message::UserCmds(vec!()).reliability(), Reliability::UnreliableNoDelay);
     }
 
-    fn discriminant(&self) -> u8 {
-        // SAFETY: Because `Self` is marked `repr(u8)`, its layout is a `repr(C)` `union`
-        // between `repr(C)` structs, each of which has the `u8` discriminant as its first
-        // field, so we can read the discriminant without offsetting the pointer.
-        unsafe { *<*const _>::from(self).cast::<u8>() }
+    #[test]
+    fn player_state_quantizes_within_tolerance() {
+        let mut state = PlayerState { pos: Vector3::new(12.3f32, -45.6f32, 789.01f32), velocity: Vector3::new(0f32, -300.3f32, 20f32), yaw: -7f32, pitch: 0.4f32, ladder_release: 0.25f32, flags: EnumSet::empty() };
+        let restored = NetPlayerState::from_state(&state).to_state();
+
+        assert!((restored.pos - state.pos).length() < 1f32 / POS_SCALE);
+        assert!((restored.velocity - state.velocity).length() < 1f32 / POS_SCALE);
+        assert!((restored.pitch - state.pitch).abs() < 1f32 / ANGLE_SCALE);
+
+        // Yaw wraps to a single turn
+        state.yaw = state.yaw.rem_euclid(2f32 * PI);
+        assert!((restored.yaw - state.yaw).abs() < 1f32 / ANGLE_SCALE);
     }
 }
@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use crate::message::Message;
+use crate::transport::*;
+
+// Network conditions for one direction of traffic. Reliable messages are only ever delayed, since the real
+// transport would retransmit and reorder them for us, everything else is also subject to loss and the rest.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NetConditions {
+    pub latency: Duration,
+    pub jitter: Duration,       // Each message gets up to this much extra delay on top of the latency
+    pub loss: f32,              // Chance of a message being dropped, 0 to 1
+    pub duplicate: f32,         // Chance of a message being delivered twice
+    pub reorder: f32,           // Chance of a message being held back so later ones overtake it
+    pub reorder_delay: Duration,
+}
+
+impl NetConditions {
+    // A perfect network, the starting point for setting up anything else
+    pub const NONE: NetConditions = NetConditions {
+        latency: Duration::ZERO,
+        jitter: Duration::ZERO,
+        loss: 0f32,
+        duplicate: 0f32,
+        reorder: 0f32,
+        reorder_delay: Duration::ZERO,
+    };
+}
+
+// Small deterministic generator so a seed always reproduces the same run, whatever platform CI is on
+struct SimRng(u64);
+
+impl SimRng {
+    // splitmix64
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        return z ^ (z >> 31);
+    }
+
+    // Uniform in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        return (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32;
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        return probability > 0f32 && self.next_f32() < probability;
+    }
+}
+
+enum SimClock {
+    Real(Instant),
+    Manual(Duration),
+}
+
+struct Delayed {
+    conn: ConnectionId,
+    msg: Message,
+    reliability: Reliability,
+}
+
+// Messages waiting to be let through, ordered by release time and then by the order they were queued in
+struct DelayQueue {
+    pending: BTreeMap<(Duration, u64), Delayed>,
+    next_seq: u64,
+    last_reliable: Duration,
+}
+
+impl DelayQueue {
+    fn new() -> DelayQueue {
+        return DelayQueue { pending: BTreeMap::new(), next_seq: 0, last_reliable: Duration::ZERO };
+    }
+
+    fn push(&mut self, now: Duration, conditions: &NetConditions, rng: &mut SimRng, delayed: Delayed) {
+        let jitter = conditions.jitter.mul_f32(rng.next_f32());
+        let mut release = now + conditions.latency + jitter;
+
+        if delayed.reliability == Reliability::Reliable {
+            // Reliable messages keep their order no matter how the jitter falls
+            release = release.max(self.last_reliable);
+            self.last_reliable = release;
+            self.insert(release, delayed);
+            return;
+        }
+
+        if rng.chance(conditions.loss) {
+            return;
+        }
+
+        if rng.chance(conditions.reorder) {
+            release += conditions.reorder_delay;
+        }
+
+        if rng.chance(conditions.duplicate) {
+            let copy = Delayed { conn: delayed.conn, msg: delayed.msg.clone(), reliability: delayed.reliability };
+            self.insert(release + conditions.jitter.mul_f32(rng.next_f32()), copy);
+        }
+
+        self.insert(release, delayed);
+    }
+
+    fn insert(&mut self, release: Duration, delayed: Delayed) {
+        self.pending.insert((release, self.next_seq), delayed);
+        self.next_seq += 1;
+    }
+
+    fn pop_due(&mut self, now: Duration) -> Vec<Delayed> {
+        let later = self.pending.split_off(&(now, u64::MAX));
+        let due = std::mem::replace(&mut self.pending, later);
+        return due.into_values().collect();
+    }
+}
+
+// Wraps any transport and makes it behave like a bad network, for reproducing prediction and interpolation
+// problems on a local connection. With a manual clock and a fixed seed every run is identical.
+pub struct NetSimTransport<T: Transport> {
+    inner: T,
+    outgoing_conditions: NetConditions,
+    incoming_conditions: NetConditions,
+    outgoing: DelayQueue,
+    incoming: DelayQueue,
+    rng: SimRng,
+    clock: SimClock,
+}
+
+impl<T: Transport> NetSimTransport<T> {
+    pub fn new(inner: T, outgoing: NetConditions, incoming: NetConditions, seed: u64) -> NetSimTransport<T> {
+        return NetSimTransport {
+            inner,
+            outgoing_conditions: outgoing,
+            incoming_conditions: incoming,
+            outgoing: DelayQueue::new(),
+            incoming: DelayQueue::new(),
+            rng: SimRng(seed),
+            clock: SimClock::Real(Instant::now()),
+        };
+    }
+
+    // Time only moves when `advance` is called, instead of following the wall clock
+    pub fn with_manual_clock(mut self) -> NetSimTransport<T> {
+        self.clock = SimClock::Manual(Duration::ZERO);
+        return self;
+    }
+
+    pub fn advance(&mut self, dt: Duration) {
+        if let SimClock::Manual(now) = &mut self.clock {
+            *now += dt;
+        }
+    }
+
+    pub fn set_conditions(&mut self, outgoing: NetConditions, incoming: NetConditions) {
+        self.outgoing_conditions = outgoing;
+        self.incoming_conditions = incoming;
+    }
+
+    pub fn inner(&self) -> &T {
+        return &self.inner;
+    }
+
+    fn now(&self) -> Duration {
+        return match self.clock {
+            SimClock::Real(start) => start.elapsed(),
+            SimClock::Manual(now) => now,
+        };
+    }
+
+    fn flush_outgoing(&mut self) {
+        for delayed in self.outgoing.pop_due(self.now()) {
+            self.inner.send(delayed.conn, &delayed.msg, delayed.reliability);
+        }
+    }
+}
+
+impl<T: Transport> Transport for NetSimTransport<T> {
+    fn send(&mut self, conn: ConnectionId, msg: &Message, reliability: Reliability) {
+        let now = self.now();
+        self.outgoing.push(now, &self.outgoing_conditions, &mut self.rng, Delayed { conn, msg: msg.clone(), reliability });
+        self.flush_outgoing();
+    }
+
+    fn connections(&self) -> Vec<ConnectionId> {
+        return self.inner.connections();
+    }
+
+    fn close(&mut self, conn: ConnectionId, reason: &str) {
+        // Whatever is still in flight goes out first, like a lingering close would
+        let pending = std::mem::replace(&mut self.outgoing, DelayQueue::new());
+        for delayed in pending.pending.into_values() {
+            self.inner.send(delayed.conn, &delayed.msg, delayed.reliability);
+        }
+        self.inner.close(conn, reason);
+    }
+
+    fn poll_messages(&mut self) -> Vec<(ConnectionId, Message)> {
+        self.flush_outgoing();
+
+        let now = self.now();
+        for (conn, msg) in self.inner.poll_messages() {
+            // The wrapped transport doesn't say how a message was sent, so assume its default channel
+            let reliability = msg.reliability();
+            self.incoming.push(now, &self.incoming_conditions, &mut self.rng, Delayed { conn, msg, reliability });
+        }
+
+        return self.incoming.pop_due(now).into_iter().map(|d| (d.conn, d.msg)).collect();
+    }
+
+    fn poll_events(&mut self) -> Vec<ConnectionEvent> {
+        self.flush_outgoing();
+        return self.inner.poll_events();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn ack(tick: u32) -> Message {
+        return Message::SnapshotAck { tick };
+    }
+
+    // A client end wrapped in the simulator and a plain server end, both already connected
+    fn sim_pair(outgoing: NetConditions, seed: u64) -> (LoopbackTransport, NetSimTransport<LoopbackTransport>) {
+        let (mut server, client) = loopback_pair();
+        let mut client = NetSimTransport::new(client, outgoing, NetConditions::NONE, seed).with_manual_clock();
+        server.poll_events();
+        client.poll_events();
+        return (server, client);
+    }
+
+    fn received_ticks(server: &mut LoopbackTransport) -> Vec<u32> {
+        return server.poll_messages().into_iter().filter_map(|(_, msg)| match msg {
+            Message::SnapshotAck { tick } => Some(tick),
+            _ => None,
+        }).collect();
+    }
+
+    #[test]
+    fn latency_holds_messages_back() {
+        let (mut server, mut client) = sim_pair(NetConditions { latency: 100 * MS, ..NetConditions::NONE }, 1);
+
+        client.send(LOOPBACK_CONNECTION, &ack(1), Reliability::Unreliable);
+        assert!(received_ticks(&mut server).is_empty());
+
+        client.advance(99 * MS);
+        client.poll_events();
+        assert!(received_ticks(&mut server).is_empty());
+
+        client.advance(MS);
+        client.poll_events();
+        assert_eq!(received_ticks(&mut server), vec![1]);
+    }
+
+    #[test]
+    fn reliable_messages_are_never_lost() {
+        let (mut server, mut client) = sim_pair(NetConditions { loss: 1f32, ..NetConditions::NONE }, 1);
+
+        client.send(LOOPBACK_CONNECTION, &ack(1), Reliability::Unreliable);
+        client.send(LOOPBACK_CONNECTION, &Message::Chat("hi".to_string()), Reliability::Reliable);
+
+        assert_eq!(server.poll_messages(), vec![(LOOPBACK_CONNECTION, Message::Chat("hi".to_string()))]);
+    }
+
+    #[test]
+    fn same_seed_gives_same_run() {
+        let conditions = NetConditions {
+            latency: 50 * MS,
+            jitter: 30 * MS,
+            loss: 0.2f32,
+            duplicate: 0.1f32,
+            reorder: 0.1f32,
+            reorder_delay: 40 * MS,
+        };
+
+        let run = |seed: u64| {
+            let (mut server, mut client) = sim_pair(conditions, seed);
+            let mut received = vec!();
+            for tick in 0..200 {
+                client.send(LOOPBACK_CONNECTION, &ack(tick), Reliability::Unreliable);
+                client.advance(10 * MS);
+                client.poll_events();
+                received.extend(received_ticks(&mut server));
+            }
+            received
+        };
+
+        let first = run(7);
+        assert_eq!(first, run(7));
+        assert_ne!(first, run(8));
+
+        // Some of everything should have happened over that many messages
+        assert!(first.len() < 200 * 11 / 10);
+        assert!(first.windows(2).any(|w| w[0] > w[1]));
+
+        let mut sorted = first.clone();
+        sorted.sort();
+        assert!(sorted.windows(2).any(|w| w[0] == w[1]));
+    }
+}
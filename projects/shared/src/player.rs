@@ -1,14 +1,44 @@
 use std::f32::consts::PI;
 
-use enumset::EnumSet;
+use enumset::*;
 use raylib::prelude::*;
-use crate::{bsp::LeafContentsSet, bsp_query::*};
+use crate::{bsp::LeafContentsSet, bsp_query::*, user_cmd::*};
 
 const MOVE_SPEED: f32 = 256f32;
 const JUMP_SPEED: f32 = 300f32;
 const GRAVITY: f32 = 550f32;
 const DEPEN: f32 = 0.01f32;
 const MAX_STEP: f32 = 20f32;
+const LADDER_SPEED: f32 = 200f32;
+const LADDER_PITCH: f32 = 0.26f32; // Looking further up or down than this while moving forward climbs
+const LADDER_JUMP_SPEED: f32 = 200f32;
+const LADDER_PUSH_SPEED: f32 = 250f32;
+const LADDER_RELEASE: f32 = 0.3f32; // Seconds after pushing off before the ladder can be grabbed again
+
+// The player is traced as a point against the clip hull, which already has this box baked into it
+pub const HITBOX_MINS: Vector3 = Vector3 { x: -16f32, y: -24f32, z: -16f32 };
+pub const HITBOX_MAXS: Vector3 = Vector3 { x: 16f32, y: 32f32, z: 16f32 };
+pub const EYE_HEIGHT: f32 = 16f32;
+
+#[derive(EnumSetType, Debug)]
+pub enum PlayerFlag {
+    Grounded,
+    OnLadder,
+    FreeMove,
+    JumpHeld,
+}
+
+// Everything `simulate` reads from one command to the next. Restoring a player to a state
+// and replaying the same commands reproduces the same movement.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PlayerState {
+    pub pos: Vector3,
+    pub velocity: Vector3,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub ladder_release: f32,
+    pub flags: EnumSet<PlayerFlag>,
+}
 
 pub struct Player {
     pub movement: Vector3,
@@ -20,6 +50,10 @@ pub struct Player {
 
     is_grounded: bool,
     y_speed: f32,
+    jump_held: bool,
+    on_ladder: bool,
+    ladder_release: f32,
+    push_velocity: Vector3,
 
     pub pos: Vector3,
 }
@@ -35,16 +69,70 @@ impl Player {
             free_move: false,
             is_grounded: true,
             y_speed: 0f32,
+            jump_held: false,
+            on_ladder: false,
+            ladder_release: 0f32,
+            push_velocity: Vector3::ZERO,
             pos: pos
         };
     }
 
     pub fn forward(&mut self) -> Vector3 {
-        let right = Vector3::X.rotate_axis(Vector3::Y, self.yaw);
-        return Vector3::Z.rotate_axis(Vector3::Y, self.yaw).rotate_axis(right, self.pitch);
+        return view_dir(self.yaw, self.pitch);
+    }
+
+    pub fn state(&self) -> PlayerState {
+        let mut flags = EnumSet::empty();
+        if self.is_grounded { flags |= PlayerFlag::Grounded; }
+        if self.on_ladder { flags |= PlayerFlag::OnLadder; }
+        if self.free_move { flags |= PlayerFlag::FreeMove; }
+        if self.jump_held { flags |= PlayerFlag::JumpHeld; }
+
+        return PlayerState {
+            pos: self.pos,
+            velocity: Vector3::new(self.push_velocity.x, self.y_speed, self.push_velocity.z),
+            yaw: self.yaw,
+            pitch: self.pitch,
+            ladder_release: self.ladder_release,
+            flags,
+        };
+    }
+
+    pub fn set_state(&mut self, state: &PlayerState) {
+        self.pos = state.pos;
+        self.push_velocity = Vector3::new(state.velocity.x, 0f32, state.velocity.z);
+        self.y_speed = state.velocity.y;
+        self.yaw = state.yaw;
+        self.pitch = state.pitch;
+        self.ladder_release = state.ladder_release;
+        self.is_grounded = state.flags.contains(PlayerFlag::Grounded);
+        self.on_ladder = state.flags.contains(PlayerFlag::OnLadder);
+        self.free_move = state.flags.contains(PlayerFlag::FreeMove);
+        self.jump_held = state.flags.contains(PlayerFlag::JumpHeld);
+    }
+
+    pub fn is_on_ladder(&self) -> bool {
+        return self.on_ladder;
+    }
+
+    // Runs a single input command. This is the only way the player moves, so it must not
+    // depend on anything other than the current state, the map and the command.
+    pub fn simulate<'a>(&mut self, bsp: &'a impl BspQuery<'a>, cmd: &UserCmd) {
+        self.yaw = cmd.yaw;
+        self.pitch = cmd.pitch;
+        self.movement = cmd.movement();
+        self.sprint = cmd.buttons.contains(Button::Sprint);
+        self.free_move = cmd.buttons.contains(Button::FreeMove);
+
+        // Jumps only trigger on the command where the button goes down
+        let jump_down = cmd.buttons.contains(Button::Jump);
+        self.jump = jump_down && !self.jump_held;
+        self.jump_held = jump_down;
+
+        self.update(bsp, cmd.dt());
     }
 
-    pub fn update<'a>(&mut self, bsp: &'a impl BspQuery<'a>, dt: f32) {
+    fn update<'a>(&mut self, bsp: &'a impl BspQuery<'a>, dt: f32) {
         let movement = self.movement.try_normalize().unwrap_or(Vector3::ZERO);
 
         if self.free_move {
@@ -52,6 +140,17 @@ impl Player {
             return;
         }
 
+        if self.ladder_release > 0f32 {
+            self.ladder_release -= dt;
+        }
+
+        self.on_ladder = self.ladder_release <= 0f32 && bsp.get_ladders().iter().any(|ladder| ladder.contains(self.pos));
+
+        if self.on_ladder {
+            self.ladder_update(bsp, dt);
+            return;
+        }
+
         // Planar movement
         if movement.x.abs() > 0.01f32 || movement.z.abs() > 0.01f32 {
             let forward = Vector3::Z.rotate_axis(Vector3::Y, self.yaw);
@@ -108,6 +207,11 @@ impl Player {
             self.pos = new_pos;
         }
 
+        // Momentum from pushing off a ladder, kept until we land
+        if !self.is_grounded && self.push_velocity.length_squared() > 0.01f32 {
+            self.pos = clip_move(bsp, self.pos, self.push_velocity, self.push_velocity.length() * dt);
+        }
+
         // Grounded check
         if self.is_grounded || self.y_speed <= 0f32 {
             let intersect = ray_intersect(bsp, self.pos, -Vector3::Y, 0.1f32, *DPASS);
@@ -119,6 +223,7 @@ impl Player {
 					//println!("BECAME GROUNDED");
 					self.is_grounded = true;
 					self.y_speed = 0f32;
+					self.push_velocity = Vector3::ZERO;
 					self.pos = intersect.position + intersect.normal * DEPEN;
 				}
             } else {
@@ -161,11 +266,43 @@ impl Player {
             if self.is_grounded {
                 self.is_grounded = false;
                 self.y_speed = JUMP_SPEED;
-                println!("Begin Jump {:?}", self.y_speed);
             }
         }
     }
 
+    fn ladder_update<'a>(&mut self, bsp: &'a impl BspQuery<'a>, dt: f32) {
+        let movement = self.movement.try_normalize().unwrap_or(Vector3::ZERO);
+        let forward = Vector3::Z.rotate_axis(Vector3::Y, self.yaw);
+        let right = Vector3::X.rotate_axis(Vector3::Y, self.yaw);
+
+        // Gravity is suspended while climbing
+        self.is_grounded = false;
+        self.y_speed = 0f32;
+        self.push_velocity = Vector3::ZERO;
+
+        // Positive pitch looks down, so moving forward while looking up climbs and vice versa
+        let climb = if self.pitch < -LADDER_PITCH {
+            1f32
+        } else if self.pitch > LADDER_PITCH {
+            -1f32
+        } else {
+            0f32
+        };
+
+        let delta = LADDER_SPEED * dt;
+        self.pos = clip_move(bsp, self.pos, Vector3::Y * climb * movement.z, delta);
+        self.pos = clip_move(bsp, self.pos, forward * movement.z + right * movement.x, delta);
+
+        // Jumping pushes off the ladder, away from the direction we're facing
+        if self.jump {
+            self.jump = false;
+            self.on_ladder = false;
+            self.ladder_release = LADDER_RELEASE;
+            self.y_speed = LADDER_JUMP_SPEED;
+            self.push_velocity = -forward * LADDER_PUSH_SPEED;
+        }
+    }
+
     fn free_move_update(&mut self, dt: f32) {
         let movement = self.movement.try_normalize().unwrap_or(Vector3::ZERO);
 
@@ -179,3 +316,99 @@ impl Player {
         self.pos += move_dir * delta;
     }
 }
+
+// Direction a player is looking in with the given view angles
+pub fn view_dir(yaw: f32, pitch: f32) -> Vector3 {
+    let right = Vector3::X.rotate_axis(Vector3::Y, yaw);
+    return Vector3::Z.rotate_axis(Vector3::Y, yaw).rotate_axis(right, pitch);
+}
+
+fn clip_move<'a>(bsp: &'a impl BspQuery<'a>, pos: Vector3, dir: Vector3, dist: f32) -> Vector3 {
+    if dir.length_squared() < 0.0001f32 || dist < 0.0001f32 {
+        return pos;
+    }
+
+    let dir = dir.normalize();
+    return match ray_intersect(bsp, pos, dir, dist, *DPASS) {
+        Some(intersect) => intersect.position + intersect.normal * DEPEN,
+        None => pos + dir * dist
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record_cmds(count: u32) -> Vec<UserCmd> {
+        return (0..count).map(|i| {
+            let mut cmd = UserCmd::new(i);
+            cmd.yaw = i as f32 * 0.013f32;
+            cmd.pitch = ((i % 40) as f32 - 20f32) * 0.02f32;
+            cmd.forward_move = if i % 90 < 60 { MOVE_AXIS_MAX } else { -MOVE_AXIS_MAX };
+            cmd.side_move = if i % 50 < 25 { 64 } else { -32 };
+
+            if i % 70 < 3 {
+                cmd.buttons |= Button::Jump;
+            }
+
+            return cmd;
+        }).collect();
+    }
+
+    fn replay(cmds: &Vec<UserCmd>) -> Player {
+        let bsp = FloorQuery::new(vec!());
+        let mut player = Player::new(Vector3::new(0f32, 24f32, 0f32));
+
+        for cmd in cmds {
+            player.simulate(&bsp, cmd);
+        }
+
+        return player;
+    }
+
+    fn state_bits(player: &Player) -> [u32; 8] {
+        return [
+            player.pos.x.to_bits(),
+            player.pos.y.to_bits(),
+            player.pos.z.to_bits(),
+            player.y_speed.to_bits(),
+            player.yaw.to_bits(),
+            player.pitch.to_bits(),
+            player.is_grounded as u32,
+            player.jump_held as u32,
+        ];
+    }
+
+    #[test]
+    fn replay_is_deterministic() {
+        let cmds = record_cmds(600);
+
+        let first = replay(&cmds);
+        let second = replay(&cmds);
+
+        assert_ne!(first.pos, Vector3::new(0f32, 24f32, 0f32));
+        assert_eq!(state_bits(&first), state_bits(&second));
+    }
+
+    #[test]
+    fn forward_climbs_only_inside_a_ladder() {
+        let bsp = FloorQuery::new(vec![LadderVolume { mins: Vector3::new(-32f32, 0f32, -32f32), maxs: Vector3::new(32f32, 256f32, 32f32) }]);
+        let start_y = 24f32;
+        let mut inside = Player::new(Vector3::new(0f32, start_y, 0f32));
+        let mut outside = Player::new(Vector3::new(128f32, start_y, 0f32));
+
+        // Looking up and pressing forward
+        for i in 0..5 {
+            let mut cmd = UserCmd::new(i);
+            cmd.pitch = -0.5f32;
+            cmd.forward_move = MOVE_AXIS_MAX;
+            inside.simulate(&bsp, &cmd);
+            outside.simulate(&bsp, &cmd);
+        }
+
+        assert!(inside.is_on_ladder());
+        assert!(inside.pos.y > start_y, "{:?}", inside.pos);
+        assert!(!outside.is_on_ladder());
+        assert!(outside.pos.y <= start_y, "{:?}", outside.pos);
+    }
+}
@@ -0,0 +1,104 @@
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+pub type RconResponse = [u8; 32];
+
+// Proves the sender knows the password without sending it. The server hands out a fresh nonce for every
+// command, and the command itself is hashed in so a captured response can't be reused for anything else.
+pub fn rcon_response(nonce: u64, password: &str, command: &str) -> RconResponse {
+    let mut hasher = Sha256::new();
+    hasher.update(nonce.to_le_bytes());
+    hasher.update((password.len() as u32).to_le_bytes());
+    hasher.update(password.as_bytes());
+    hasher.update(command.as_bytes());
+    return hasher.finalize().into();
+}
+
+// Unpredictable nonce, std's hasher keys are randomly seeded per process and per `RandomState`
+pub fn rcon_nonce() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+    return hasher.finish();
+}
+
+// Every wrong password locks the sender out for twice as long as the last one, up to a limit.
+// Works in ticks like the chat flood protection.
+pub struct BadAttempts {
+    failures: u32,
+    locked_until: u32,
+    base_ticks: u32,
+    max_ticks: u32,
+}
+
+impl BadAttempts {
+    pub fn new(base_ticks: u32, max_ticks: u32) -> BadAttempts {
+        return BadAttempts { failures: 0, locked_until: 0, base_ticks, max_ticks };
+    }
+
+    // How many more ticks the sender has to wait before trying again, if any
+    pub fn locked(&self, tick: u32) -> Option<u32> {
+        return (tick < self.locked_until).then(|| self.locked_until - tick);
+    }
+
+    pub fn fail(&mut self, tick: u32) {
+        let lockout = self.base_ticks.saturating_mul(1 << self.failures.min(16)).min(self.max_ticks);
+        self.failures += 1;
+        self.locked_until = tick + lockout;
+    }
+
+    pub fn succeed(&mut self) {
+        self.failures = 0;
+    }
+
+    // The same lockout for a server whose ticks start over from 0, with `tick` being where the old one got to
+    pub fn rebased(&self, tick: u32) -> BadAttempts {
+        return BadAttempts { locked_until: self.locked_until.saturating_sub(tick), ..*self };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_depends_on_everything() {
+        let response = rcon_response(1, "secret", "status");
+        assert_eq!(response, rcon_response(1, "secret", "status"));
+        assert_ne!(response, rcon_response(2, "secret", "status"));
+        assert_ne!(response, rcon_response(1, "secreT", "status"));
+        assert_ne!(response, rcon_response(1, "secret", "kick 0"));
+
+        // The password length is hashed in so it can't bleed into the command
+        assert_ne!(rcon_response(1, "ab", "c"), rcon_response(1, "a", "bc"));
+    }
+
+    #[test]
+    fn nonces_differ() {
+        assert_ne!(rcon_nonce(), rcon_nonce());
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_the_limit() {
+        let mut attempts = BadAttempts::new(10, 50);
+        assert_eq!(attempts.locked(0), None);
+
+        attempts.fail(0);
+        assert_eq!(attempts.locked(5), Some(5));
+        assert_eq!(attempts.locked(10), None);
+
+        attempts.fail(10);
+        assert_eq!(attempts.locked(10), Some(20));
+
+        attempts.fail(30);
+        attempts.fail(70);
+        assert_eq!(attempts.locked(70), Some(50));
+
+        attempts.succeed();
+        attempts.fail(200);
+        assert_eq!(attempts.locked(200), Some(10));
+
+        assert_eq!(attempts.rebased(205).locked(0), Some(5));
+        assert_eq!(attempts.rebased(300).locked(0), None);
+    }
+}
@@ -0,0 +1,296 @@
+use enumset::*;
+use std::collections::VecDeque;
+use crate::codec::*;
+
+// How many snapshots are kept around to delta against, a bit over a second at the default tick rate
+pub const SNAPSHOT_BACKUP: usize = 32;
+
+// Player entities take the ids right after the world, one per slot
+pub fn player_entity_id(slot: u8) -> u16 {
+    return slot as u16 + 1;
+}
+
+// Model indices for entities that aren't drawn with a brush model
+pub const MODEL_NONE: u16 = 0;
+pub const MODEL_PLAYER: u16 = 1;
+pub const MODEL_IMPACT: u16 = 2;
+
+#[derive(EnumSetType, Debug)]
+pub enum EntityEffect {
+    Teleport, // Origin jumped, don't interpolate from the previous position
+    Hidden,
+}
+
+// Networked state of a single entity, already quantized for sending
+#[derive(Encode, Decode, Default, Clone, Copy, PartialEq, Debug)]
+pub struct EntityState {
+    pub id: u16,
+    pub origin: [i32; 3],
+    pub angles: [u16; 2],
+    pub model: u16,
+    pub frame: u8,
+    pub effects: EnumSet<EntityEffect>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Snapshot {
+    pub tick: u32,
+    pub entities: Vec<EntityState>, // Sorted by id
+}
+
+// Only the fields of an entity that differ from the base it's applied to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct EntityDelta {
+    pub id: u16,
+    pub origin: Option<[i32; 3]>,
+    pub angles: Option<[u16; 2]>,
+    pub model: Option<u16>,
+    pub frame: Option<u8>,
+    pub effects: Option<EnumSet<EntityEffect>>,
+}
+
+// Snapshot encoded against an older one the client acknowledged, or against nothing when `base_tick` is None
+#[derive(Encode, Decode, Clone, PartialEq, Debug)]
+pub struct SnapshotDelta {
+    pub tick: u32,
+    pub base_tick: Option<u32>,
+    pub removed: Vec<u16>,
+    pub changed: Vec<EntityDelta>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum SnapshotError {
+    MissingBase { tick: u32, base_tick: u32 },
+}
+
+const DELTA_ORIGIN: u8 = 1 << 0;
+const DELTA_ANGLES: u8 = 1 << 1;
+const DELTA_MODEL: u8 = 1 << 2;
+const DELTA_FRAME: u8 = 1 << 3;
+const DELTA_EFFECTS: u8 = 1 << 4;
+const DELTA_ALL: u8 = DELTA_ORIGIN | DELTA_ANGLES | DELTA_MODEL | DELTA_FRAME | DELTA_EFFECTS;
+
+impl EntityDelta {
+    pub fn new(id: u16) -> EntityDelta {
+        return EntityDelta { id, origin: None, angles: None, model: None, frame: None, effects: None };
+    }
+
+    // Returns None when nothing changed
+    pub fn between(base: &EntityState, current: &EntityState) -> Option<EntityDelta> {
+        fn changed<T: PartialEq>(a: T, b: T) -> Option<T> {
+            return if a != b { Some(b) } else { None };
+        }
+
+        let delta = EntityDelta {
+            id: current.id,
+            origin: changed(base.origin, current.origin),
+            angles: changed(base.angles, current.angles),
+            model: changed(base.model, current.model),
+            frame: changed(base.frame, current.frame),
+            effects: changed(base.effects, current.effects),
+        };
+
+        return if delta.bits() == 0 { None } else { Some(delta) };
+    }
+
+    pub fn apply(&self, state: &mut EntityState) {
+        if let Some(origin) = self.origin { state.origin = origin; }
+        if let Some(angles) = self.angles { state.angles = angles; }
+        if let Some(model) = self.model { state.model = model; }
+        if let Some(frame) = self.frame { state.frame = frame; }
+        if let Some(effects) = self.effects { state.effects = effects; }
+    }
+
+    fn bits(&self) -> u8 {
+        let mut bits = 0;
+        if self.origin.is_some() { bits |= DELTA_ORIGIN; }
+        if self.angles.is_some() { bits |= DELTA_ANGLES; }
+        if self.model.is_some() { bits |= DELTA_MODEL; }
+        if self.frame.is_some() { bits |= DELTA_FRAME; }
+        if self.effects.is_some() { bits |= DELTA_EFFECTS; }
+        return bits;
+    }
+}
+
+// Written as the id and a byte of flags for which fields follow, rather than a byte per `Option`
+impl Encode for EntityDelta {
+    fn encode(&self, writer: &mut Vec<u8>) {
+        self.id.encode(writer);
+        self.bits().encode(writer);
+
+        if let Some(origin) = &self.origin { origin.encode(writer); }
+        if let Some(angles) = &self.angles { angles.encode(writer); }
+        if let Some(model) = &self.model { model.encode(writer); }
+        if let Some(frame) = &self.frame { frame.encode(writer); }
+        if let Some(effects) = &self.effects { effects.encode(writer); }
+    }
+}
+
+impl Decode for EntityDelta {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let id = u16::decode(reader)?;
+        let offset = reader.offset();
+        let bits = u8::decode(reader)?;
+
+        if bits & !DELTA_ALL != 0 {
+            return Err(DecodeError::InvalidValue { type_name: "EntityDelta", offset });
+        }
+
+        let mut delta = EntityDelta::new(id);
+        if bits & DELTA_ORIGIN != 0 { delta.origin = Some(Decode::decode(reader)?); }
+        if bits & DELTA_ANGLES != 0 { delta.angles = Some(Decode::decode(reader)?); }
+        if bits & DELTA_MODEL != 0 { delta.model = Some(Decode::decode(reader)?); }
+        if bits & DELTA_FRAME != 0 { delta.frame = Some(Decode::decode(reader)?); }
+        if bits & DELTA_EFFECTS != 0 { delta.effects = Some(Decode::decode(reader)?); }
+        return Ok(delta);
+    }
+}
+
+impl Snapshot {
+    pub fn new(tick: u32, mut entities: Vec<EntityState>) -> Snapshot {
+        entities.sort_by_key(|e| e.id);
+        return Snapshot { tick, entities };
+    }
+
+    pub fn get(&self, id: u16) -> Option<&EntityState> {
+        return self.entities.binary_search_by_key(&id, |e| e.id).ok().map(|i| &self.entities[i]);
+    }
+
+    // Entities that aren't in the base are sent against a default state, so only their non-default fields go out
+    pub fn delta_from(&self, base: Option<&Snapshot>) -> SnapshotDelta {
+        let mut changed = vec!();
+
+        for entity in &self.entities {
+            match base.and_then(|b| b.get(entity.id)) {
+                Some(base_entity) => changed.extend(EntityDelta::between(base_entity, entity)),
+                None => {
+                    // New entities are always sent, even if every field is default
+                    let default = EntityState { id: entity.id, ..Default::default() };
+                    changed.push(EntityDelta::between(&default, entity).unwrap_or(EntityDelta::new(entity.id)));
+                }
+            }
+        }
+
+        let removed = match base {
+            Some(base) => base.entities.iter().filter(|e| self.get(e.id).is_none()).map(|e| e.id).collect(),
+            None => vec!(),
+        };
+
+        return SnapshotDelta { tick: self.tick, base_tick: base.map(|b| b.tick), removed, changed };
+    }
+}
+
+impl SnapshotDelta {
+    pub fn apply(&self, base: Option<&Snapshot>) -> Result<Snapshot, SnapshotError> {
+        let mut entities = match (self.base_tick, base) {
+            (None, _) => vec!(),
+            (Some(base_tick), Some(base)) if base.tick == base_tick => base.entities.clone(),
+            (Some(base_tick), _) => return Err(SnapshotError::MissingBase { tick: self.tick, base_tick }),
+        };
+
+        entities.retain(|e| !self.removed.contains(&e.id));
+
+        for delta in &self.changed {
+            match entities.binary_search_by_key(&delta.id, |e| e.id) {
+                Ok(i) => delta.apply(&mut entities[i]),
+                Err(i) => {
+                    let mut state = EntityState { id: delta.id, ..Default::default() };
+                    delta.apply(&mut state);
+                    entities.insert(i, state);
+                }
+            }
+        }
+
+        return Ok(Snapshot { tick: self.tick, entities });
+    }
+}
+
+// Recent snapshots by tick, oldest first
+pub struct SnapshotHistory {
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotHistory {
+    pub fn new() -> SnapshotHistory {
+        return SnapshotHistory { snapshots: VecDeque::with_capacity(SNAPSHOT_BACKUP) };
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.snapshots.len() >= SNAPSHOT_BACKUP {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back(snapshot);
+    }
+
+    pub fn get(&self, tick: u32) -> Option<&Snapshot> {
+        return self.snapshots.iter().find(|s| s.tick == tick);
+    }
+
+    pub fn latest(&self) -> Option<&Snapshot> {
+        return self.snapshots.back();
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Snapshot> {
+        return self.snapshots.iter();
+    }
+
+    pub fn discard_before(&mut self, tick: u32) {
+        self.snapshots.retain(|s| s.tick >= tick);
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(id: u16, x: i32) -> EntityState {
+        return EntityState { id, origin: [x, 0, 0], model: 1, ..Default::default() };
+    }
+
+    #[test]
+    fn full_snapshot_round_trips() {
+        let snapshot = Snapshot::new(5, vec![entity(2, 10), entity(1, 0), EntityState { id: 7, ..Default::default() }]);
+        let delta = snapshot.delta_from(None);
+
+        assert_eq!(delta.changed.len(), 3);
+        assert_eq!(delta.apply(None), Ok(snapshot));
+    }
+
+    #[test]
+    fn only_changed_fields_are_sent() {
+        let base = Snapshot::new(1, vec![entity(1, 0), entity(2, 0), entity(3, 0)]);
+        let mut moved = entity(2, 16);
+        moved.frame = 1;
+        let current = Snapshot::new(2, vec![entity(1, 0), moved, entity(4, 8)]);
+
+        let delta = current.delta_from(Some(&base));
+
+        assert_eq!(delta.base_tick, Some(1));
+        assert_eq!(delta.removed, vec![3]);
+        assert_eq!(delta.changed.len(), 2);
+        assert_eq!(delta.changed[0], EntityDelta { id: 2, origin: Some([16, 0, 0]), frame: Some(1), ..EntityDelta::new(2) });
+        assert_eq!(delta.apply(Some(&base)), Ok(current));
+    }
+
+    #[test]
+    fn delta_needs_matching_base() {
+        let base = Snapshot::new(1, vec![entity(1, 0)]);
+        let current = Snapshot::new(3, vec![entity(1, 8)]);
+        let delta = current.delta_from(Some(&base));
+
+        assert_eq!(delta.apply(None), Err(SnapshotError::MissingBase { tick: 3, base_tick: 1 }));
+        assert_eq!(delta.apply(Some(&Snapshot::new(2, vec!()))), Err(SnapshotError::MissingBase { tick: 3, base_tick: 1 }));
+    }
+
+    #[test]
+    fn delta_encoding_round_trips() {
+        let delta = EntityDelta { id: 300, angles: Some([1, 65535]), effects: Some(EntityEffect::Teleport.into()), ..EntityDelta::new(300) };
+        assert_eq!(from_bytes::<EntityDelta>(&to_bytes(&delta)), Ok(delta));
+        assert_eq!(to_bytes(&EntityDelta::new(1)).len(), 2);
+    }
+}
@@ -0,0 +1,223 @@
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use crate::message::Message;
+
+// Identifies one connection on a transport. Ids are only meaningful to the transport that handed them out.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct ConnectionId(pub u32);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reliability {
+    Reliable,          // Retransmitted until acknowledged, delivered in order
+    Unreliable,        // Sent once, may be lost, duplicated or arrive out of order
+    UnreliableNoDelay, // Like unreliable, but dropped rather than queued if it can't go out right away
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum ConnectionEvent {
+    Connecting(ConnectionId), // Only seen by servers, a client was accepted but can't be sent to yet
+    Connected(ConnectionId),
+    Disconnected(ConnectionId, String),
+}
+
+// Moves messages between a client and a server, independent of what actually carries them
+pub trait Transport {
+    fn send(&mut self, conn: ConnectionId, msg: &Message, reliability: Reliability);
+
+    // Sends on the message's default channel
+    fn send_default(&mut self, conn: ConnectionId, msg: &Message) {
+        self.send(conn, msg, msg.reliability());
+    }
+
+    // Sends to every open connection the filter accepts, on the message's default channel
+    fn broadcast(&mut self, filter: &dyn Fn(ConnectionId) -> bool, msg: &Message) {
+        for conn in self.connections() {
+            if filter(conn) {
+                self.send(conn, msg, msg.reliability());
+            }
+        }
+    }
+
+    // Every connection that can currently be sent to
+    fn connections(&self) -> Vec<ConnectionId>;
+
+    fn close(&mut self, conn: ConnectionId, reason: &str);
+
+    // Every message received since the last poll, in arrival order. Malformed messages are dropped.
+    fn poll_messages(&mut self) -> Vec<(ConnectionId, Message)>;
+
+    // Connection state changes since the last poll
+    fn poll_events(&mut self) -> Vec<ConnectionEvent>;
+}
+
+// The loopback only ever has the one connection, to the other end of the pair
+pub const LOOPBACK_CONNECTION: ConnectionId = ConnectionId(0);
+
+enum LoopbackPacket {
+    Connect,
+    Data(Vec<u8>),
+    Close(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum LoopbackState {
+    Connecting,
+    Connected,
+    Closed,
+}
+
+// One end of an in-memory connection. Messages still go through the codec so the loopback behaves like a
+// real transport, just one that never loses anything. Each end can live on its own thread.
+pub struct LoopbackTransport {
+    tx: Sender<LoopbackPacket>,
+    rx: Receiver<LoopbackPacket>,
+    state: LoopbackState,
+    is_server: bool,
+    messages: Vec<(ConnectionId, Message)>,
+    events: Vec<ConnectionEvent>,
+}
+
+// Creates a connected pair, the server end first. The client end starts connecting straight away.
+pub fn loopback_pair() -> (LoopbackTransport, LoopbackTransport) {
+    let (server_tx, client_rx) = channel();
+    let (client_tx, server_rx) = channel();
+
+    let server = LoopbackTransport::new(server_tx, server_rx, true);
+    let client = LoopbackTransport::new(client_tx, client_rx, false);
+    let _ = client.tx.send(LoopbackPacket::Connect);
+
+    return (server, client);
+}
+
+impl LoopbackTransport {
+    fn new(tx: Sender<LoopbackPacket>, rx: Receiver<LoopbackPacket>, is_server: bool) -> LoopbackTransport {
+        return LoopbackTransport { tx, rx, state: LoopbackState::Connecting, is_server, messages: vec!(), events: vec!() };
+    }
+
+    // Sorts everything the other end sent into messages and events
+    fn pump(&mut self) {
+        loop {
+            let packet = match self.rx.try_recv() {
+                Ok(packet) => packet,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.set_closed("loopback peer went away");
+                    break;
+                }
+            };
+
+            match packet {
+                LoopbackPacket::Connect if self.state == LoopbackState::Connecting => {
+                    if self.is_server {
+                        self.events.push(ConnectionEvent::Connecting(LOOPBACK_CONNECTION));
+                        let _ = self.tx.send(LoopbackPacket::Connect);
+                    }
+
+                    self.state = LoopbackState::Connected;
+                    self.events.push(ConnectionEvent::Connected(LOOPBACK_CONNECTION));
+                }
+                LoopbackPacket::Connect => {}
+                LoopbackPacket::Data(_) if self.state == LoopbackState::Closed => {}
+                LoopbackPacket::Data(bytes) => match Message::from_bytes(&bytes) {
+                    Ok(msg) => self.messages.push((LOOPBACK_CONNECTION, msg)),
+                    Err(err) => println!("Dropping malformed message of {} bytes: {err}", bytes.len()),
+                },
+                LoopbackPacket::Close(reason) => self.set_closed(&reason),
+            }
+        }
+    }
+
+    fn set_closed(&mut self, reason: &str) {
+        if self.state != LoopbackState::Closed {
+            self.state = LoopbackState::Closed;
+            self.events.push(ConnectionEvent::Disconnected(LOOPBACK_CONNECTION, reason.to_string()));
+        }
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, conn: ConnectionId, msg: &Message, _reliability: Reliability) {
+        if conn == LOOPBACK_CONNECTION && self.state != LoopbackState::Closed {
+            let _ = self.tx.send(LoopbackPacket::Data(msg.to_bytes()));
+        }
+    }
+
+    fn connections(&self) -> Vec<ConnectionId> {
+        return if self.state == LoopbackState::Connected { vec![LOOPBACK_CONNECTION] } else { vec!() };
+    }
+
+    fn close(&mut self, conn: ConnectionId, reason: &str) {
+        if conn == LOOPBACK_CONNECTION && self.state != LoopbackState::Closed {
+            let _ = self.tx.send(LoopbackPacket::Close(reason.to_string()));
+            self.state = LoopbackState::Closed;
+        }
+    }
+
+    fn poll_messages(&mut self) -> Vec<(ConnectionId, Message)> {
+        self.pump();
+        return std::mem::take(&mut self.messages);
+    }
+
+    fn poll_events(&mut self) -> Vec<ConnectionEvent> {
+        self.pump();
+        return std::mem::take(&mut self.events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_connects_both_ends() {
+        let (mut server, mut client) = loopback_pair();
+
+        assert_eq!(server.poll_events(), vec![ConnectionEvent::Connecting(LOOPBACK_CONNECTION), ConnectionEvent::Connected(LOOPBACK_CONNECTION)]);
+        assert_eq!(client.poll_events(), vec![ConnectionEvent::Connected(LOOPBACK_CONNECTION)]);
+    }
+
+    #[test]
+    fn loopback_delivers_in_order() {
+        let (mut server, mut client) = loopback_pair();
+        server.poll_events();
+        client.poll_events();
+
+        client.send(LOOPBACK_CONNECTION, &Message::Chat("one".to_string()), Reliability::Reliable);
+        client.send(LOOPBACK_CONNECTION, &Message::Chat("two".to_string()), Reliability::Unreliable);
+        server.send(LOOPBACK_CONNECTION, &Message::SnapshotAck { tick: 3 }, Reliability::Unreliable);
+
+        assert_eq!(server.poll_messages(), vec![
+            (LOOPBACK_CONNECTION, Message::Chat("one".to_string())),
+            (LOOPBACK_CONNECTION, Message::Chat("two".to_string())),
+        ]);
+        assert_eq!(client.poll_messages(), vec![(LOOPBACK_CONNECTION, Message::SnapshotAck { tick: 3 })]);
+        assert!(server.poll_messages().is_empty());
+    }
+
+    #[test]
+    fn broadcast_respects_the_filter() {
+        let (mut server, mut client) = loopback_pair();
+        server.poll_events();
+        client.poll_events();
+
+        server.broadcast(&|conn| conn != LOOPBACK_CONNECTION, &Message::Chat("nobody".to_string()));
+        server.broadcast(&|_| true, &Message::Chat("everyone".to_string()));
+
+        assert_eq!(client.poll_messages(), vec![(LOOPBACK_CONNECTION, Message::Chat("everyone".to_string()))]);
+    }
+
+    #[test]
+    fn closing_disconnects_the_peer() {
+        let (mut server, mut client) = loopback_pair();
+        server.poll_events();
+        client.poll_events();
+
+        server.close(LOOPBACK_CONNECTION, "kicked");
+        server.send(LOOPBACK_CONNECTION, &Message::Chat("too late".to_string()), Reliability::Reliable);
+
+        assert_eq!(client.poll_events(), vec![ConnectionEvent::Disconnected(LOOPBACK_CONNECTION, "kicked".to_string())]);
+        assert!(client.poll_messages().is_empty());
+
+        drop(client);
+        assert!(server.poll_events().is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+use enumset::*;
+use raylib::prelude::*;
+use crate::codec::{Decode, Encode};
+
+// Clients generate one command per fixed step, so the simulation never sees a variable frame time
+pub const CMD_MSEC: u8 = 16;
+pub const MOVE_AXIS_MAX: i8 = 127;
+
+#[derive(EnumSetType, Debug)]
+pub enum Button {
+    Jump,
+    Sprint,
+    FreeMove,
+    Attack,
+}
+
+// A single step of player input. Simulating the same stream of commands from the same
+// starting state gives the same result on the client and the server.
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Debug)]
+pub struct UserCmd {
+    pub sequence: u32,
+    pub msec: u8,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub forward_move: i8,
+    pub side_move: i8,
+    pub up_move: i8,
+    pub buttons: EnumSet<Button>,
+    // Server tick other entities were being drawn at, plus how far towards the next one out of 255,
+    // so the server can rewind them to what the player actually saw when shooting
+    pub view_tick: u32,
+    pub view_lerp: u8,
+}
+
+impl UserCmd {
+    pub fn new(sequence: u32) -> UserCmd {
+        return UserCmd {
+            sequence,
+            msec: CMD_MSEC,
+            yaw: 0f32,
+            pitch: 0f32,
+            forward_move: 0,
+            side_move: 0,
+            up_move: 0,
+            buttons: EnumSet::empty(),
+            view_tick: 0,
+            view_lerp: 0,
+        };
+    }
+
+    // Movement axes in the layout `Player` expects: x is right, y is up and z is forward
+    pub fn movement(&self) -> Vector3 {
+        let scale = MOVE_AXIS_MAX as f32;
+        return Vector3::new(self.side_move as f32 / scale, self.up_move as f32 / scale, self.forward_move as f32 / scale);
+    }
+
+    pub fn dt(&self) -> f32 {
+        return self.msec as f32 / 1000f32;
+    }
+}
@@ -0,0 +1,163 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Field, Fields};
+
+// Derives `shared::codec::Encode`. Struct fields are written in declaration order, and enums
+// write the variant's index as a varint followed by its fields. A Vec field marked
+// `#[codec(max_len = N)]` keeps only its last N items, and decoding rejects any more than that.
+#[proc_macro_derive(Encode, attributes(codec))]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, encodes) = encode_fields(&data.fields);
+            quote! {
+                let Self #pattern = self;
+                #(#encodes)*
+            }
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().enumerate().map(|(index, variant)| {
+                let ident = &variant.ident;
+                let index = index as u64;
+                let (pattern, encodes) = encode_fields(&variant.fields);
+                quote! {
+                    Self::#ident #pattern => {
+                        ::shared::codec::write_varint(writer, #index);
+                        #(#encodes)*
+                    }
+                }
+            });
+
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return syn::Error::new_spanned(name, "Encode can't be derived for unions").to_compile_error().into();
+        }
+    };
+
+    return quote! {
+        impl #impl_generics ::shared::codec::Encode for #name #ty_generics #where_clause {
+            fn encode(&self, writer: &mut ::std::vec::Vec<u8>) {
+                let _ = &writer;
+                #body
+            }
+        }
+    }.into();
+}
+
+// Derives `shared::codec::Decode`, reading back the layout written by `Encode`
+#[proc_macro_derive(Decode, attributes(codec))]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let construct = decode_fields(&data.fields);
+            quote! { return Ok(Self #construct); }
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().enumerate().map(|(index, variant)| {
+                let ident = &variant.ident;
+                let index = index as u64;
+                let construct = decode_fields(&variant.fields);
+                quote! { #index => Ok(Self::#ident #construct), }
+            });
+
+            quote! {
+                return match reader.read_varint()? {
+                    #(#arms)*
+                    value => Err(::shared::codec::DecodeError::InvalidDiscriminant { type_name: stringify!(#name), value }),
+                };
+            }
+        }
+        Data::Union(_) => {
+            return syn::Error::new_spanned(name, "Decode can't be derived for unions").to_compile_error().into();
+        }
+    };
+
+    return quote! {
+        impl #impl_generics ::shared::codec::Decode for #name #ty_generics #where_clause {
+            fn decode(reader: &mut ::shared::codec::Reader) -> ::std::result::Result<Self, ::shared::codec::DecodeError> {
+                let _ = &reader;
+                #body
+            }
+        }
+    }.into();
+}
+
+// The `N` in a field's `#[codec(max_len = N)]`, if it has one
+fn max_len(field: &Field) -> Option<Expr> {
+    let mut max_len = None;
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("codec")) {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("max_len") {
+                return Err(meta.error("expected `max_len`"));
+            }
+            max_len = Some(meta.value()?.parse::<Expr>()?);
+            return Ok(());
+        }).unwrap_or_else(|err| panic!("{err}"));
+    }
+
+    return max_len;
+}
+
+fn encode_field(ident: &syn::Ident, field: &Field) -> TokenStream2 {
+    return match max_len(field) {
+        Some(max_len) => quote! { ::shared::codec::encode_bounded(#ident, #max_len, writer); },
+        None => quote! { ::shared::codec::Encode::encode(#ident, writer); },
+    };
+}
+
+fn decode_field(field: &Field) -> TokenStream2 {
+    return match max_len(field) {
+        Some(max_len) => quote! { ::shared::codec::decode_bounded(reader, #max_len)? },
+        None => quote! { ::shared::codec::Decode::decode(reader)? },
+    };
+}
+
+// Returns a pattern binding every field by reference, and an encode call for each binding
+fn encode_fields(fields: &Fields) -> (TokenStream2, Vec<TokenStream2>) {
+    match fields {
+        Fields::Named(named) => {
+            let idents = named.named.iter().map(|f| f.ident.clone().unwrap()).collect::<Vec<_>>();
+            let encodes = idents.iter().zip(&named.named).map(|(ident, field)| encode_field(ident, field)).collect();
+            return (quote! { { #(#idents),* } }, encodes);
+        }
+        Fields::Unnamed(unnamed) => {
+            let idents = (0..unnamed.unnamed.len()).map(|i| format_ident!("field{}", i)).collect::<Vec<_>>();
+            let encodes = idents.iter().zip(&unnamed.unnamed).map(|(ident, field)| encode_field(ident, field)).collect();
+            return (quote! { ( #(#idents),* ) }, encodes);
+        }
+        Fields::Unit => {
+            return (quote! {}, vec!());
+        }
+    }
+}
+
+fn decode_fields(fields: &Fields) -> TokenStream2 {
+    match fields {
+        Fields::Named(named) => {
+            let idents = named.named.iter().map(|f| f.ident.clone().unwrap());
+            let decodes = named.named.iter().map(decode_field);
+            return quote! { { #(#idents: #decodes),* } };
+        }
+        Fields::Unnamed(unnamed) => {
+            let decodes = unnamed.unnamed.iter().map(decode_field);
+            return quote! { ( #(#decodes),* ) };
+        }
+        Fields::Unit => {
+            return quote! {};
+        }
+    }
+}